@@ -38,6 +38,14 @@ pub struct SendCommand {
     /// Delay in milliseconds between sending to multiple destinations (IPv4 + IPv6)
     #[arg(short = 'd', long, default_value = "50")]
     pub send_delay_ms: u64,
+    /// Parameter for the command, as declared in the server's commands.toml (repeatable). Sends
+    /// the packet in protocol version 2, which needs a server that supports it.
+    #[arg(long = "param", value_name = "NAME=VALUE")]
+    pub params: Vec<String>,
+    /// Protocol version to send, 1 or 2. Defaults to the lowest one that can carry the request
+    /// (2 with --param, 1 otherwise). Set it when the server only accepts a version for this key.
+    #[arg(long, value_name = "VERSION", value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub protocol_version: Option<u8>,
 }
 
 #[derive(Parser, Debug)]
@@ -70,6 +78,8 @@ impl Default for SendCommand {
             ipv4: false,
             ipv6: false,
             send_delay_ms: 50,
+            params: vec![],
+            protocol_version: None,
         }
     }
 }
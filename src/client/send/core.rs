@@ -3,7 +3,8 @@ use crate::client::counter::Counter;
 use crate::common::client_data::ClientData;
 use crate::common::data_parser::DataParser;
 use crate::common::logging::error;
-use crate::common::protocol::PLAINTEXT_SIZE;
+use crate::common::protocol::params::parse_params;
+use crate::common::protocol::{PROTOCOL_VERSION, PROTOCOL_VERSION_V2};
 use crate::common::{info, now_nanos, resolve_path};
 use anyhow::{bail, Context};
 use openssl::version::version;
@@ -16,6 +17,7 @@ use zeroize::Zeroizing;
 pub struct Sender {
     pub(super) cmd: SendCommand,
     pub(super) src_ip: Option<IpAddr>,
+    pub(super) params: Vec<(String, String)>,
     pub(super) data_parser: DataParser,
     pub(super) counter: Counter,
 }
@@ -28,6 +30,10 @@ impl Sender {
             .clone()
             .map(|ip| ip.parse().with_context(|| format!("Invalid --ip value {ip:?}")))
             .transpose()?;
+        let params = parse_params(&cmd.params)?;
+        if cmd.protocol_version == Some(PROTOCOL_VERSION) && !params.is_empty() {
+            bail!("--param needs protocol version {PROTOCOL_VERSION_V2}, not {PROTOCOL_VERSION}");
+        }
         let key: Zeroizing<String> = std::fs::read_to_string(&cmd.key_file)
             .with_context(|| format!("Could not read key file {:?}", cmd.key_file))?
             .into();
@@ -37,6 +43,7 @@ impl Sender {
             data_parser: DataParser::create(key.trim())?,
             cmd,
             src_ip,
+            params,
             counter: Counter::create_and_init(counter_path, now_nanos()?)?,
         })
     }
@@ -101,18 +108,21 @@ impl Sender {
         Ok(())
     }
 
-    pub(super) fn get_data_to_encrypt(
-        &self,
-        destination_ip: IpAddr,
-    ) -> anyhow::Result<[u8; PLAINTEXT_SIZE]> {
-        ClientData::create(
+    /// A v1 plaintext, or a v2 one when the request carries `--param`s or `--protocol-version 2`
+    /// was given.
+    pub(super) fn get_data_to_encrypt(&self, destination_ip: IpAddr) -> anyhow::Result<Vec<u8>> {
+        let data = ClientData::create(
             &self.cmd.command,
             !self.cmd.permissive,
             self.src_ip,
             destination_ip,
             self.counter.count(),
-        )?
-        .serialize()
+            self.params.clone(),
+        )?;
+        match self.cmd.protocol_version {
+            Some(version) => data.with_version(version)?.serialize(),
+            None => data.serialize(),
+        }
     }
 }
 
@@ -121,9 +131,10 @@ mod tests {
     use clap::error::ErrorKind::DisplayHelp;
     use clap::Parser;
 
-    use crate::client::config::{CliClient, SendCommand};
+    use crate::client::config::{CliClient, CommandsClient, SendCommand};
     use crate::client::gen::Generator;
     use crate::client::send::Sender;
+    use crate::common::protocol::PLAINTEXT_SIZE_V2;
     use std::net::SocketAddr;
     use std::path::{Path, PathBuf};
     use tempfile::TempDir;
@@ -187,6 +198,100 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("Invalid --ip value"));
     }
 
+    #[test]
+    fn test_send_params() {
+        let conf_dir = set_test_conf_dir();
+        let key_file = write_key_file(conf_dir.path());
+        let cli = CliClient::try_parse_from([
+            "ruroco",
+            "send",
+            "-a",
+            IP,
+            "-k",
+            "test.key",
+            "--param",
+            "hours=8",
+            "--param",
+            "proto=tcp",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            CommandsClient::Send(cmd) if cmd.params == ["hours=8", "proto=tcp"]
+        ));
+
+        let sender = Sender::create(SendCommand {
+            key_file,
+            params: vec!["hours=8".to_string(), "proto=tcp".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let plaintext = sender.get_data_to_encrypt(IP.parse().unwrap()).unwrap();
+        assert_eq!(plaintext.len(), PLAINTEXT_SIZE_V2);
+    }
+
+    #[test]
+    fn test_send_invalid_param() {
+        let conf_dir = set_test_conf_dir();
+        let key_file = write_key_file(conf_dir.path());
+
+        let result = Sender::create(SendCommand {
+            key_file,
+            params: vec!["hours".to_string()],
+            ..Default::default()
+        });
+
+        assert!(result.unwrap_err().to_string().contains("expected name=value"));
+    }
+
+    #[test]
+    fn test_send_protocol_version() {
+        let conf_dir = set_test_conf_dir();
+        let key_file = write_key_file(conf_dir.path());
+        let cli = CliClient::try_parse_from([
+            "ruroco",
+            "send",
+            "-a",
+            IP,
+            "-k",
+            "k",
+            "--protocol-version",
+            "2",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, CommandsClient::Send(cmd) if cmd.protocol_version == Some(2))
+        );
+        assert!(CliClient::try_parse_from([
+            "ruroco",
+            "send",
+            "-a",
+            IP,
+            "-k",
+            "k",
+            "--protocol-version",
+            "3"
+        ])
+        .is_err());
+
+        let sender = Sender::create(SendCommand {
+            key_file: key_file.clone(),
+            protocol_version: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let plaintext = sender.get_data_to_encrypt(IP.parse().unwrap()).unwrap();
+        assert_eq!((plaintext.len(), plaintext[0]), (PLAINTEXT_SIZE_V2, 2));
+
+        let result = Sender::create(SendCommand {
+            key_file,
+            params: vec!["hours=8".to_string()],
+            protocol_version: Some(1),
+            ..Default::default()
+        });
+        assert!(result.unwrap_err().to_string().contains("--param needs protocol version 2"));
+    }
+
     #[test]
     fn test_send_invalid_key() {
         let conf_dir = set_test_conf_dir();
@@ -0,0 +1,290 @@
+//! Tamper-evident audit log of executed commands. With `audit_log` set, every run (command, hook,
+//! alert or undo) appends one line to that file:
+//!
+//! | Field | Value |
+//! | --- | --- |
+//! | seq | 1 for the first record, then counting up |
+//! | time | when the run finished, RFC 3339, UTC, milliseconds |
+//! | name | as in the output file names, see `output`, escaped with `str::escape_default` |
+//! | key_id | of the key that authenticated the request, upper-case hex |
+//! | ip | client IP |
+//! | result | `exit status: N`, `signal: N (...)`, timed out, or `not run`; escaped like `name` |
+//! | duration_ms | wall time of the run |
+//! | prev | `hash` of the previous line, `GENESIS` for the first |
+//! | hash | BLAKE2b-256 of everything before it on the line (tab included), lower-case hex |
+//!
+//! Fields are separated by tabs. Editing, inserting, reordering or deleting a record breaks the
+//! chain from that record on, which `ruroco-commander audit verify` reports. Cutting records off
+//! the end does not, and neither does rewriting the whole file with freshly computed hashes: keep
+//! copies of the last `seq`/`hash` elsewhere (e.g. shipped with the journal) to catch that.
+
+use crate::commander::ConfigCommander;
+use crate::common::logging::error;
+use anyhow::{anyhow, bail, Context};
+use blake2::digest::{Update, VariableOutput};
+use blake2::Blake2bVar;
+use chrono::{SecondsFormat, Utc};
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+const GENESIS: &str = "GENESIS";
+const HASH_SIZE: usize = 32;
+const FIELDS: usize = 9;
+
+/// One run, as recorded.
+pub(crate) struct AuditRecord<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) key_id: &'a str,
+    pub(crate) ip: IpAddr,
+    pub(crate) result: &'a str,
+    pub(crate) duration_ms: u64,
+}
+
+#[derive(Debug)]
+struct Tail {
+    file: File,
+    seq: u64,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    /// Appends are serialised: each record needs the hash of the one before.
+    tail: Mutex<Tail>,
+}
+
+/// The file behind it is what matters, not the open handle.
+impl PartialEq for AuditLog {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl AuditLog {
+    /// Open `path` for appending (created `0600` if missing) and continue the chain from its last
+    /// record. Only that record is checked here; a malformed one refuses the start, as appending
+    /// to it would hide the damage. The full chain is checked by `verify`.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<AuditLog> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Could not open audit log {path:?}"))?;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read audit log {path:?}"))?;
+        let (seq, hash) = match content.lines().enumerate().last() {
+            None => (0, GENESIS.to_string()),
+            Some((index, line)) => {
+                let record = parse(line).with_context(|| {
+                    format!("Audit log {path:?} has a malformed last record (line {})", index + 1)
+                })?;
+                (record.seq, record.hash.to_string())
+            }
+        };
+        if !content.is_empty() && !content.ends_with('\n') {
+            bail!("Audit log {path:?} ends in an incomplete record");
+        }
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            tail: Mutex::new(Tail { file, seq, hash }),
+        })
+    }
+
+    /// Append `record`. Errors are logged, not returned: the command has run already.
+    pub(crate) fn append(&self, record: &AuditRecord) {
+        if let Err(e) = self.try_append(record) {
+            error(format!("Could not write audit record for {}: {e:#}", record.name))
+        }
+    }
+
+    fn try_append(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut tail = self.tail.lock().unwrap_or_else(PoisonError::into_inner);
+        let seq = tail.seq + 1;
+        let body = format!(
+            "{seq}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            record.name.escape_default(),
+            record.key_id,
+            record.ip,
+            record.result.escape_default(),
+            record.duration_ms,
+            tail.hash,
+        );
+        let hash = hash_hex(&body)?;
+        // One write per record, synced, so a crash can at worst leave the last one incomplete.
+        tail.file
+            .write_all(format!("{body}{hash}\n").as_bytes())
+            .and_then(|()| tail.file.sync_data())
+            .with_context(|| format!("Could not append to {:?}", self.path))?;
+        tail.seq = seq;
+        tail.hash = hash;
+        Ok(())
+    }
+}
+
+struct Parsed<'a> {
+    seq: u64,
+    prev: &'a str,
+    hash: &'a str,
+    /// Everything the hash covers.
+    body: &'a str,
+}
+
+fn parse(line: &str) -> anyhow::Result<Parsed<'_>> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != FIELDS {
+        bail!("expected {FIELDS} tab-separated fields, found {}", fields.len());
+    }
+    let seq = fields[0].parse().with_context(|| format!("invalid seq {:?}", fields[0]))?;
+    let hash = fields[FIELDS - 1];
+    Ok(Parsed {
+        seq,
+        prev: fields[FIELDS - 2],
+        hash,
+        body: &line[..line.len() - hash.len()],
+    })
+}
+
+fn hash_hex(body: &str) -> anyhow::Result<String> {
+    let mut hasher = Blake2bVar::new(HASH_SIZE).with_context(|| "Could not create hasher")?;
+    hasher.update(body.as_bytes());
+    let mut out = [0u8; HASH_SIZE];
+    hasher.finalize_variable(&mut out).with_context(|| "Could not finalize hash")?;
+    Ok(out.iter().fold(String::with_capacity(2 * HASH_SIZE), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    }))
+}
+
+/// Check every record of the audit log at `path`: its hash, its link to the one before and its
+/// `seq`. Returns the number of records and the last hash, or the first broken record.
+pub(crate) fn verify(path: &Path) -> anyhow::Result<(u64, String)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Could not read audit log {path:?}"))?;
+    let mut seq = 0;
+    let mut prev = GENESIS.to_string();
+    for (index, line) in content.lines().enumerate() {
+        let line_no = index + 1;
+        let record = parse(line).with_context(|| format!("Line {line_no} is malformed"))?;
+        if record.seq != seq + 1 {
+            bail!("Line {line_no}: seq is {}, expected {}", record.seq, seq + 1);
+        }
+        if record.prev != prev {
+            bail!("Line {line_no}: does not follow the previous record (deleted or reordered?)");
+        }
+        if hash_hex(record.body)? != record.hash {
+            bail!("Line {line_no}: hash mismatch (record edited?)");
+        }
+        seq = record.seq;
+        prev = record.hash.to_string();
+    }
+    if !content.is_empty() && !content.ends_with('\n') {
+        bail!("The last record is incomplete");
+    }
+    Ok((seq, prev))
+}
+
+/// `ruroco-commander audit verify`: check `file`, or the `audit_log` configured in `config_path`.
+pub(super) fn run_verify(config_path: &Path, file: Option<PathBuf>) -> anyhow::Result<()> {
+    let path = match file {
+        Some(file) => file,
+        None => ConfigCommander::create_from_path(config_path)?
+            .audit_log
+            .ok_or_else(|| anyhow!("No audit_log configured in {config_path:?}, pass the file"))?,
+    };
+    let (count, last) = verify(&path).with_context(|| format!("Audit log {path:?} is broken"))?;
+    println!("{path:?}: {count} record(s), chain intact, last hash {last}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, AuditLog, AuditRecord};
+    use std::fs;
+
+    fn record(name: &str) -> AuditRecord<'_> {
+        AuditRecord {
+            name,
+            key_id: "1A2B3C4D5E6F7081",
+            ip: "1.2.3.4".parse().unwrap(),
+            result: "exit status: 0",
+            duration_ms: 12,
+        }
+    }
+
+    fn write_log(path: &std::path::Path) -> Vec<String> {
+        let log = AuditLog::open(path).unwrap();
+        log.append(&record("open"));
+        log.append(&record("open.on_success"));
+        // Reopening continues the chain.
+        AuditLog::open(path).unwrap().append(&AuditRecord {
+            result: "odd\tresult",
+            ..record("weird\tname\n")
+        });
+        fs::read_to_string(path).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_append_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let lines = write_log(&path);
+        assert_eq!(lines.len(), 3);
+        let fields: Vec<&str> = lines[2].split('\t').collect();
+        assert_eq!(fields[0], "3");
+        assert_eq!(
+            fields[2..7],
+            [
+                "weird\\tname\\n",
+                "1A2B3C4D5E6F7081",
+                "1.2.3.4",
+                "odd\\tresult",
+                "12"
+            ]
+        );
+        let (count, last) = verify(&path).unwrap();
+        assert_eq!((count, last.as_str()), (3, fields[8]));
+        assert_eq!(verify(&dir.path().join("empty")).ok(), None);
+    }
+
+    #[test]
+    fn test_verify_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let lines = write_log(&path);
+        for (tampered, expected) in [
+            (
+                vec![
+                    lines[0].replace("1.2.3.4", "5.6.7.8"),
+                    lines[1].clone(),
+                    lines[2].clone(),
+                ],
+                "Line 1: hash mismatch",
+            ),
+            (vec![lines[0].clone(), lines[2].clone()], "Line 2: seq is 3"),
+            (vec![lines[1].clone(), lines[0].clone(), lines[2].clone()], "Line 1: seq is 2"),
+            (vec![lines[0].clone(), "garbage".to_string()], "Line 2 is malformed"),
+        ] {
+            fs::write(&path, tampered.join("\n") + "\n").unwrap();
+            let err = verify(&path).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{expected}: {err:#}");
+        }
+
+        // Renumbering after a deletion still breaks the link.
+        let renumbered = lines[2].replacen("3\t", "2\t", 1);
+        fs::write(&path, format!("{}\n{renumbered}\n", lines[0])).unwrap();
+        let err = verify(&path).unwrap_err();
+        assert!(err.to_string().contains("Line 2: does not follow"), "{err}");
+
+        // A malformed last record keeps the commander from appending to it.
+        fs::write(&path, format!("{}\npartial", lines[0])).unwrap();
+        assert!(AuditLog::open(&path).is_err());
+    }
+}
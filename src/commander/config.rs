@@ -8,11 +8,17 @@
 //!   server process never loads the command set; installed `root`-owned `0600` and relocatable via
 //!   `--commands` independently of `config.toml`.
 
+use crate::commander::ip_filter::{Cidr, IpFilter};
+use crate::commander::output;
 use crate::common::blake2b_u64;
-use anyhow::{anyhow, Context};
-use clap::Parser;
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::common::protocol::params::{check_param_name, check_param_value, decode_params};
+use crate::common::protocol::EXTENSIONS_SIZE;
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
+use nix::unistd::{Group, User};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
@@ -20,6 +26,19 @@ use std::time::Duration;
 /// Default execution timeout for a command that doesn't specify `timeout_sec`.
 pub(crate) const DEFAULT_TIMEOUT_SECS: u64 = 30;
 
+/// The placeholders an `argv` may use besides `{param_<name>}`: the `RUROCO_*` variables set by
+/// `exec::request_env`, lower-cased and without the prefix.
+const PLACEHOLDERS: [&str; 8] = [
+    "ip",
+    "ip_family",
+    "dst_ip",
+    "key_id",
+    "key_label",
+    "command_name",
+    "counter",
+    "received_at",
+];
+
 /// The commander reads two files: the shared `config.toml` and its own `commands.toml`. Both paths
 /// are configurable so the command set can be relocated independently of the server config.
 #[derive(Parser, Debug)]
@@ -29,6 +48,26 @@ pub struct CliCommander {
     pub(crate) config: PathBuf,
     #[arg(long, default_value = PathBuf::from("/etc/ruroco/commands.toml").into_os_string())]
     pub(crate) commands: PathBuf,
+    #[command(subcommand)]
+    pub(crate) command: Option<CommanderCommand>,
+}
+
+// Offline tools; without a subcommand the commander runs. Not a doc comment: clap would show it as
+// the about text of `--help` in place of the crate description.
+#[derive(Debug, Subcommand, PartialEq)]
+pub(crate) enum CommanderCommand {
+    /// Work with the `audit_log` of executed commands.
+    #[command(subcommand)]
+    Audit(AuditCommand),
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub(crate) enum AuditCommand {
+    /// Check that no record was edited, inserted, reordered or deleted (except at the end).
+    Verify {
+        /// Audit log to check; defaults to `audit_log` in `--config`.
+        file: Option<PathBuf>,
+    },
 }
 
 /// The commander's view of `config.toml`. Holds only the fields the commander uses; server-only
@@ -43,16 +82,58 @@ pub struct ConfigCommander {
     /// `systemd/ruroco-commander.service`.
     #[serde(default)]
     pub socket_dir: Option<PathBuf>,
+    /// Directory holding the commander's persisted state (`undo.msgpck`, the pending `undo_cmd`s).
+    /// When unset it defaults to `config_dir`. Point it at a systemd `StateDirectory` (e.g.
+    /// `/var/lib/ruroco-commander`) so `config_dir` can stay read-only. See
+    /// `systemd/ruroco-commander.service`.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
     #[serde(default = "default_socket_user")]
     pub socket_user: String,
     #[serde(default = "default_socket_group")]
     pub socket_group: String,
-    /// Allow non-routable client IPs (loopback, private, link-local, etc.) to reach the executed
-    /// command. Off by default: the `$RUROCO_IP` a command sees is meant to be an outside peer, so
-    /// a client must not be able to name `127.0.0.1` or an internal address. Mainly for local
-    /// testing where the only available source address is loopback.
+    /// Client IP ranges commands run for, e.g. a `10.0.0.0/8` VPN. An entry containing the IP takes
+    /// precedence over the routable check (which alone decides for IPs in neither list, rejecting
+    /// loopback, private, link-local, etc.) and over a less specific `deny_cidrs` entry. See
+    /// `commander::ip_filter`.
+    #[serde(default)]
+    pub allow_cidrs: Vec<Cidr>,
+    /// Client IP ranges no command may run for, routable or not, unless a more specific
+    /// `allow_cidrs` entry lets them in.
+    #[serde(default)]
+    pub deny_cidrs: Vec<Cidr>,
+    /// Publish structured command events (started/finished) to `ruroco-events.socket` in the
+    /// socket dir. Shared with the server; see `common::events`.
+    #[serde(default)]
+    pub publish_events: bool,
+    /// Number of commands (including hooks, alerts and undos run alongside them) that may run at
+    /// the same time. Further requests wait for a free worker. Defaults to 4.
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// How long the process group of a timed-out command gets between `SIGTERM` and `SIGKILL`.
+    /// Defaults to 5.
+    #[serde(default = "default_kill_grace_sec")]
+    pub kill_grace_sec: u64,
+    /// Keep the stdout/stderr of every run in a file here instead of logging it, see
+    /// `commander::output`. Unset (the default): output goes into the log line.
     #[serde(default)]
-    pub allow_non_routable_ips: bool,
+    pub output_dir: Option<PathBuf>,
+    /// Per stream and run; the rest is cut off. Defaults to 1 MiB.
+    #[serde(default = "default_output_max_bytes")]
+    pub output_max_bytes: u64,
+    /// Output files kept per command (hooks, alerts and undos counted separately). Defaults to 20.
+    #[serde(default = "default_output_keep")]
+    pub output_keep: usize,
+    /// Output files older than this are deleted, whichever command they belong to. Unset: no limit.
+    #[serde(default)]
+    pub output_max_age_sec: Option<u64>,
+    /// Append a hash-chained record of every run to this file, see `commander::audit`.
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// Resource limits for every spawned command, unless its `commands.toml` entry sets its own.
+    /// None by default: commands inherit the commander's limits.
+    #[serde(default)]
+    pub rlimits: Rlimits,
 }
 
 impl ConfigCommander {
@@ -64,8 +145,24 @@ impl ConfigCommander {
     }
 
     pub(crate) fn deserialize(data: &str) -> anyhow::Result<ConfigCommander> {
-        toml::from_str::<ConfigCommander>(data)
-            .with_context(|| format!("Could not create ConfigCommander from {data}"))
+        let config = toml::from_str::<ConfigCommander>(data)
+            .with_context(|| format!("Could not create ConfigCommander from {data}"))?;
+        // serde ignores unknown keys (the server's share the file), so a removed one would
+        // otherwise be dropped silently, here turning a loopback or LAN setup into rejections.
+        let table = toml::from_str::<toml::Table>(data)
+            .with_context(|| format!("Could not create ConfigCommander from {data}"))?;
+        if table.contains_key("allow_non_routable_ips") {
+            bail!(
+                "allow_non_routable_ips was replaced by allow_cidrs, e.g. allow_cidrs = \
+                 [\"0.0.0.0/0\", \"::/0\"]"
+            );
+        }
+        if config.max_concurrent == 0 {
+            bail!("max_concurrent must be at least 1");
+        }
+        output::check_config(&config)?;
+        config.rlimits.check().with_context(|| "Invalid rlimits")?;
+        Ok(config)
     }
 }
 
@@ -75,13 +172,40 @@ impl Default for ConfigCommander {
         ConfigCommander {
             config_dir: std::env::current_dir().unwrap_or(PathBuf::from("/tmp")),
             socket_dir: None,
+            state_dir: None,
             socket_user: "".to_string(),
             socket_group: "".to_string(),
-            allow_non_routable_ips: false,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            publish_events: false,
+            max_concurrent: default_max_concurrent(),
+            kill_grace_sec: default_kill_grace_sec(),
+            output_dir: None,
+            output_max_bytes: default_output_max_bytes(),
+            output_keep: default_output_keep(),
+            output_max_age_sec: None,
+            audit_log: None,
+            rlimits: Rlimits::default(),
         }
     }
 }
 
+fn default_max_concurrent() -> usize {
+    4
+}
+
+fn default_kill_grace_sec() -> u64 {
+    5
+}
+
+fn default_output_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_output_keep() -> usize {
+    20
+}
+
 fn default_config_path() -> PathBuf {
     PathBuf::from("/etc/ruroco")
 }
@@ -94,24 +218,244 @@ fn default_socket_group() -> String {
     "ruroco".to_string()
 }
 
+/// What receiving a command's hash means, beyond running its `cmd`.
+///
+/// - `normal`: run `cmd`, nothing else.
+/// - `canary`: a decoy name that is never used legitimately. Nothing is executed except the
+///   global `alert_cmd`; a canary entry has no `cmd` of its own.
+/// - `duress`: behaves like `normal` (so whoever forced the knock sees nothing unusual), but
+///   `alert_cmd` runs first.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CommandKind {
+    #[default]
+    Normal,
+    Canary,
+    Duress,
+}
+
+impl CommandKind {
+    /// Whether receiving this command must trigger `alert_cmd`.
+    pub(crate) fn alerts(self) -> bool {
+        self != CommandKind::Normal
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CommandKind::Normal => "normal",
+            CommandKind::Canary => "canary",
+            CommandKind::Duress => "duress",
+        }
+    }
+}
+
+/// What happens to a request for a command while an earlier request for the same command is still
+/// running or waiting for a worker.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Concurrency {
+    /// Drop the new request.
+    Single,
+    /// Run it once the earlier ones are done.
+    #[default]
+    Queue,
+    /// Run it on the next free worker, next to the earlier ones.
+    Parallel,
+}
+
+/// Whose runs `cooldown_sec` and `max_per_hour` count, see `commander::throttle`.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ThrottleBy {
+    /// All runs of the command, whoever asked.
+    #[default]
+    Command,
+    /// Runs for the same client IP.
+    Ip,
+    /// Runs requested with the same key.
+    Key,
+}
+
+/// Commands run after a command finishes, chosen by how it ended. Set at the top level of
+/// `commands.toml` (applies to every command) and/or per entry; a per-entry hook replaces the
+/// global one of the same name. Hooks never trigger further hooks.
+#[derive(Debug, Deserialize, PartialEq, Clone, Default)]
+pub(crate) struct Hooks {
+    /// Exit status 0.
+    #[serde(default)]
+    pub(crate) on_success: Option<String>,
+    /// Non-zero exit, killed by a signal, or could not be spawned.
+    #[serde(default)]
+    pub(crate) on_failure: Option<String>,
+    /// Killed at `timeout_sec`.
+    #[serde(default)]
+    pub(crate) on_timeout: Option<String>,
+}
+
+impl Hooks {
+    fn or(&self, fallback: &Hooks) -> Hooks {
+        Hooks {
+            on_success: self.on_success.clone().or_else(|| fallback.on_success.clone()),
+            on_failure: self.on_failure.clone().or_else(|| fallback.on_failure.clone()),
+            on_timeout: self.on_timeout.clone().or_else(|| fallback.on_timeout.clone()),
+        }
+    }
+}
+
+/// A typed parameter a client may send along with a command (protocol v2), handed to the command
+/// as `RUROCO_PARAM_<NAME>` once its value passed validation. Without a `default`, every request
+/// for the command has to carry it.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ParamSpec {
+    /// A decimal integer in `min..=max`.
+    Int {
+        min: i64,
+        max: i64,
+        #[serde(default)]
+        default: Option<i64>,
+    },
+    /// One of `values`, compared exactly.
+    Enum {
+        values: Vec<String>,
+        #[serde(default)]
+        default: Option<String>,
+    },
+}
+
+impl ParamSpec {
+    /// Check `value` against the schema, returning it as the command will see it.
+    fn validate(&self, name: &str, value: &str) -> anyhow::Result<String> {
+        match self {
+            ParamSpec::Int { min, max, .. } => {
+                let number = value
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("Parameter {name} must be an integer, got {value:?}"))?;
+                if number < *min || number > *max {
+                    bail!("Parameter {name} must be between {min} and {max}, got {number}");
+                }
+                // Canonical form, so "+08" reaches the command as "8".
+                Ok(number.to_string())
+            }
+            ParamSpec::Enum { values, .. } if values.iter().any(|v| v == value) => {
+                Ok(value.to_string())
+            }
+            ParamSpec::Enum { values, .. } => {
+                bail!("Parameter {name} must be one of {values:?}, got {value:?}")
+            }
+        }
+    }
+
+    fn default_value(&self) -> Option<String> {
+        match self {
+            ParamSpec::Int { default, .. } => default.map(|d| d.to_string()),
+            ParamSpec::Enum { default, .. } => default.clone(),
+        }
+    }
+
+    /// Reject a schema no request could satisfy, at startup.
+    fn check(&self, name: &str) -> anyhow::Result<()> {
+        check_param_name(name)?;
+        match self {
+            ParamSpec::Int { min, max, .. } if min > max => {
+                bail!("Parameter {name} has min {min} above max {max}")
+            }
+            ParamSpec::Enum { values, .. } if values.is_empty() => {
+                bail!("Parameter {name} has no values")
+            }
+            ParamSpec::Enum { values, .. } => {
+                values.iter().try_for_each(|value| check_param_value(name, value))?
+            }
+            ParamSpec::Int { .. } => {}
+        }
+        match self.default_value() {
+            Some(default) => {
+                self.validate(name, &default).map(drop).with_context(|| "Invalid default")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
 /// A single entry in `commands.toml`: either the plain shell command string (default timeout), or
-/// a table overriding the timeout. `#[serde(untagged)]` lets both forms live in the same map.
+/// a table giving the command as `cmd` or as a shell-free `argv`, overriding the timeout, `kind`
+/// and/or `concurrency`, declaring `params` or an `undo_cmd`, limiting how often and for which
+/// client IPs it runs, or setting the `user`, `group`, `cwd`, `env`, `umask` and `rlimits` it runs
+/// with.
+/// `#[serde(untagged)]` lets both forms live in the same map. `cmd`/`argv` may only be omitted for
+/// a `canary`, see `ConfigCommands::get_hash_to_cmd`.
+// Only parsed once at startup and turned into a `CommandSpec`, so the size difference between the
+// variants does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(untagged)]
 pub(crate) enum CommandValue {
     Plain(String),
     Detailed {
+        #[serde(default)]
         cmd: String,
+        #[serde(default)]
+        argv: Option<Vec<String>>,
         #[serde(default = "default_timeout_sec")]
         timeout_sec: u64,
+        #[serde(default)]
+        kind: CommandKind,
+        #[serde(default)]
+        concurrency: Concurrency,
+        #[serde(default)]
+        params: BTreeMap<String, ParamSpec>,
+        #[serde(default)]
+        undo_cmd: Option<String>,
+        #[serde(default)]
+        ttl_sec: Option<u64>,
+        #[serde(default)]
+        cooldown_sec: Option<u64>,
+        #[serde(default)]
+        max_per_hour: Option<u32>,
+        #[serde(default)]
+        throttle_by: Option<ThrottleBy>,
+        #[serde(default)]
+        user: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        cwd: Option<PathBuf>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        umask: Option<u32>,
+        #[serde(default)]
+        rlimits: Rlimits,
+        #[serde(default)]
+        allow_cidrs: Option<Vec<String>>,
+        #[serde(default)]
+        deny_cidrs: Option<Vec<String>>,
+        #[serde(flatten)]
+        hooks: Hooks,
     },
 }
 
 impl CommandValue {
-    fn cmd(&self) -> &str {
+    /// `cmd` and `argv` are two ways of saying the same thing, so at most one may be set.
+    fn program(&self) -> anyhow::Result<Program> {
         match self {
-            CommandValue::Plain(cmd) => cmd,
-            CommandValue::Detailed { cmd, .. } => cmd,
+            CommandValue::Plain(cmd) => Ok(Program::Shell(cmd.clone())),
+            CommandValue::Detailed {
+                cmd, argv: None, ..
+            } => Ok(Program::Shell(cmd.clone())),
+            CommandValue::Detailed {
+                cmd,
+                argv: Some(argv),
+                ..
+            } => {
+                if !cmd.is_empty() {
+                    bail!("cmd and argv cannot both be set")
+                }
+                match argv.first() {
+                    Some(program) if !program.is_empty() => Ok(Program::Argv(argv.clone())),
+                    _ => bail!("argv is empty or starts with an empty string"),
+                }
+            }
         }
     }
 
@@ -121,23 +465,394 @@ impl CommandValue {
             CommandValue::Detailed { timeout_sec, .. } => Duration::from_secs(*timeout_sec),
         }
     }
+
+    fn kind(&self) -> CommandKind {
+        match self {
+            CommandValue::Plain(_) => CommandKind::Normal,
+            CommandValue::Detailed { kind, .. } => *kind,
+        }
+    }
+
+    fn concurrency(&self) -> Concurrency {
+        match self {
+            CommandValue::Plain(_) => Concurrency::default(),
+            CommandValue::Detailed { concurrency, .. } => *concurrency,
+        }
+    }
+
+    /// The entry's own `allow_cidrs`/`deny_cidrs`, each replacing the global list if set. Parsed
+    /// here rather than by serde, which would only report that the entry matches no variant.
+    fn ip_filter(&self) -> anyhow::Result<IpFilter> {
+        let CommandValue::Detailed {
+            allow_cidrs,
+            deny_cidrs,
+            ..
+        } = self
+        else {
+            return Ok(IpFilter::default());
+        };
+        let parse = |list: &Option<Vec<String>>| {
+            list.as_ref().map(|list| list.iter().map(|cidr| cidr.parse()).collect()).transpose()
+        };
+        Ok(IpFilter {
+            allow: parse(allow_cidrs)?,
+            deny: parse(deny_cidrs)?,
+        })
+    }
+
+    /// `throttle_by` alone limits nothing, so it needs at least one of the limits.
+    fn throttle(&self) -> anyhow::Result<Option<Throttle>> {
+        let CommandValue::Detailed {
+            cooldown_sec,
+            max_per_hour,
+            throttle_by,
+            ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        if *cooldown_sec == Some(0) {
+            bail!("cooldown_sec must be greater than 0");
+        }
+        if *max_per_hour == Some(0) {
+            bail!("max_per_hour must be greater than 0");
+        }
+        match (cooldown_sec, max_per_hour, throttle_by) {
+            (None, None, None) => Ok(None),
+            (None, None, Some(_)) => bail!("throttle_by needs a cooldown_sec or max_per_hour"),
+            _ => Ok(Some(Throttle {
+                cooldown: cooldown_sec.map(Duration::from_secs),
+                max_per_hour: *max_per_hour,
+                by: throttle_by.unwrap_or_default(),
+            })),
+        }
+    }
+
+    fn params(&self) -> BTreeMap<String, ParamSpec> {
+        match self {
+            CommandValue::Plain(_) => BTreeMap::new(),
+            CommandValue::Detailed { params, .. } => params.clone(),
+        }
+    }
+
+    /// `undo_cmd` and `ttl_sec` only make sense together; a half-configured pair is rejected.
+    fn undo(&self) -> anyhow::Result<Option<Undo>> {
+        let CommandValue::Detailed {
+            undo_cmd, ttl_sec, ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        match (undo_cmd, ttl_sec) {
+            (None, None) => Ok(None),
+            (Some(cmd), Some(ttl_sec)) if !cmd.trim().is_empty() && *ttl_sec > 0 => {
+                Ok(Some(Undo {
+                    cmd: cmd.clone(),
+                    ttl: Duration::from_secs(*ttl_sec),
+                }))
+            }
+            (Some(cmd), Some(_)) if cmd.trim().is_empty() => bail!("undo_cmd is empty"),
+            (Some(_), Some(_)) => bail!("ttl_sec must be greater than 0"),
+            (Some(_), None) => bail!("undo_cmd needs a ttl_sec"),
+            (None, Some(_)) => bail!("ttl_sec needs an undo_cmd"),
+        }
+    }
+
+    /// Resolve `user`/`group` to ids and check `cwd`, `env` and `umask`, so a typo fails at
+    /// startup instead of on every knock.
+    fn run_as(&self) -> anyhow::Result<RunAs> {
+        let CommandValue::Detailed {
+            user,
+            group,
+            cwd,
+            env,
+            umask,
+            rlimits,
+            ..
+        } = self
+        else {
+            return Ok(RunAs::default());
+        };
+        let user = user
+            .as_deref()
+            .map(|name| {
+                User::from_name(name)
+                    .with_context(|| format!("Could not look up user {name}"))?
+                    .ok_or_else(|| anyhow!("Could not find user {name}"))
+            })
+            .transpose()?;
+        let gid = match (group, &user) {
+            (Some(name), _) => Some(
+                Group::from_name(name)
+                    .with_context(|| format!("Could not look up group {name}"))?
+                    .ok_or_else(|| anyhow!("Could not find group {name}"))?
+                    .gid
+                    .as_raw(),
+            ),
+            (None, Some(user)) => Some(user.gid.as_raw()),
+            (None, None) => None,
+        };
+        if let Some(cwd) = cwd.as_ref().filter(|cwd| !cwd.is_absolute()) {
+            bail!("cwd {cwd:?} is not an absolute path");
+        }
+        for (name, value) in env {
+            check_env_name(name)?;
+            if value.contains('\0') {
+                bail!("env {name} contains a NUL byte");
+            }
+        }
+        if let Some(umask) = umask.filter(|umask| *umask > 0o777) {
+            bail!("umask {umask:o} is not between 0 and 0o777");
+        }
+        rlimits.check().with_context(|| "Invalid rlimits")?;
+        Ok(RunAs {
+            uid: user.map(|user| user.uid.as_raw()),
+            gid,
+            cwd: cwd.clone(),
+            env: env.clone(),
+            umask: *umask,
+            rlimits: *rlimits,
+        })
+    }
+
+    fn hooks(&self) -> Option<&Hooks> {
+        match self {
+            CommandValue::Plain(_) => None,
+            CommandValue::Detailed { hooks, .. } => Some(hooks),
+        }
+    }
 }
 
 fn default_timeout_sec() -> u64 {
     DEFAULT_TIMEOUT_SECS
 }
 
-/// A resolved command: the shell command to run plus how long it may run before being killed.
+/// Replace the `{name}` placeholders in an `argv` element with `lookup(name)`; `{{` and `}}` stand
+/// for literal braces. An unknown name or an unbalanced brace is an error.
+pub(crate) fn expand_placeholders(
+    arg: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            expanded.push_str(&tail[..1]);
+            rest = &tail[2..];
+        } else if tail.starts_with('}') {
+            bail!("Unmatched '}}' in {arg:?}, write '}}}}' for a literal one")
+        } else {
+            let Some(end) = tail.find('}') else {
+                bail!("Unclosed '{{' in {arg:?}, write '{{{{' for a literal one")
+            };
+            let name = &tail[1..end];
+            let value =
+                lookup(name).ok_or_else(|| anyhow!("Unknown placeholder {{{name}}} in {arg:?}"))?;
+            expanded.push_str(&value);
+            rest = &tail[end + 1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// A static `env` name must be a portable shell identifier and must not shadow the `RUROCO_*`
+/// variables describing the request.
+fn check_env_name(name: &str) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("Invalid env name {name:?}: use letters, digits and '_', not starting with a digit");
+    }
+    if name.starts_with("RUROCO_") {
+        bail!("env {name} would shadow a variable set by ruroco");
+    }
+    Ok(())
+}
+
+/// Who a command runs as and in which process environment. Set per entry in `commands.toml` and
+/// applied to its `cmd` and `undo_cmd` only: hooks and `alert_cmd` keep running as the commander,
+/// with the global `rlimits`. Persisted with a pending undo, hence the raw ids instead of names.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Default)]
+pub(crate) struct RunAs {
+    pub(crate) uid: Option<u32>,
+    /// `group`, or the primary group of `user`.
+    pub(crate) gid: Option<u32>,
+    pub(crate) cwd: Option<PathBuf>,
+    /// Added to the commander's environment, before the `RUROCO_*` variables.
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) umask: Option<u32>,
+    /// Filled in from the global `rlimits` where the entry sets none, see `Commander::create`.
+    #[serde(default)]
+    pub(crate) rlimits: Rlimits,
+}
+
+/// `setrlimit` limits for a spawned command, each set as both soft and hard limit so the command
+/// cannot raise it again. Unset ones are inherited from the commander.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rlimits {
+    /// `RLIMIT_CPU`: CPU time, in seconds.
+    #[serde(default)]
+    pub cpu_sec: Option<u64>,
+    /// `RLIMIT_AS`: virtual memory, in bytes.
+    #[serde(default)]
+    pub as_bytes: Option<u64>,
+    /// `RLIMIT_NOFILE`: open file descriptors.
+    #[serde(default)]
+    pub nofile: Option<u64>,
+    /// `RLIMIT_NPROC`: processes of the user the command runs as, counting ones it did not start.
+    #[serde(default)]
+    pub nproc: Option<u64>,
+    /// `RLIMIT_CORE`: core dump size, in bytes.
+    #[serde(default)]
+    pub core_bytes: Option<u64>,
+}
+
+impl Rlimits {
+    /// Each limit set here, or else the one in `defaults`.
+    pub(crate) fn or(self, defaults: Rlimits) -> Rlimits {
+        Rlimits {
+            cpu_sec: self.cpu_sec.or(defaults.cpu_sec),
+            as_bytes: self.as_bytes.or(defaults.as_bytes),
+            nofile: self.nofile.or(defaults.nofile),
+            nproc: self.nproc.or(defaults.nproc),
+            core_bytes: self.core_bytes.or(defaults.core_bytes),
+        }
+    }
+
+    /// A command could not even be started with no CPU time, memory or file descriptors. `nproc`
+    /// and `core_bytes` may be 0: no child processes, no core dumps.
+    fn check(&self) -> anyhow::Result<()> {
+        for (name, limit) in [
+            ("cpu_sec", self.cpu_sec),
+            ("as_bytes", self.as_bytes),
+            ("nofile", self.nofile),
+        ] {
+            if limit == Some(0) {
+                bail!("{name} must be greater than 0");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often a command may run, see `commander::throttle`.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct CommandSpec {
+pub(crate) struct Throttle {
+    pub(crate) cooldown: Option<Duration>,
+    pub(crate) max_per_hour: Option<u32>,
+    pub(crate) by: ThrottleBy,
+}
+
+/// Reverts a command `ttl` after it succeeded, e.g. closes the port it opened. See `commander::undo`.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Undo {
     pub(crate) cmd: String,
+    pub(crate) ttl: Duration,
+}
+
+/// What a command executes: a string through `sh -c`, or an argv run directly, without a shell.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Program {
+    Shell(String),
+    /// Still contains the placeholders; `expand` fills them in per request.
+    Argv(Vec<String>),
+}
+
+impl Program {
+    fn is_empty(&self) -> bool {
+        match self {
+            Program::Shell(cmd) => cmd.trim().is_empty(),
+            Program::Argv(argv) => argv.is_empty(),
+        }
+    }
+
+    /// Fill in the placeholders of an argv from `lookup`; a shell command is returned unchanged,
+    /// it reads the request from its environment instead.
+    pub(crate) fn expand(&self, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        match self {
+            Program::Shell(cmd) => Ok(Program::Shell(cmd.clone())),
+            Program::Argv(argv) => argv
+                .iter()
+                .map(|arg| expand_placeholders(arg, &lookup))
+                .collect::<anyhow::Result<_>>()
+                .map(Program::Argv),
+        }
+    }
+}
+
+/// For log lines: the shell command as written, or the arguments separated by spaces.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Program::Shell(cmd) => f.write_str(cmd),
+            Program::Argv(argv) => f.write_str(&argv.join(" ")),
+        }
+    }
+}
+
+/// A resolved command: what to run plus how long it may run before being killed. `name` is kept
+/// (the map is keyed by hash) so alerts can tell which entry was triggered.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: String,
+    pub(crate) program: Program,
     pub(crate) timeout: Duration,
+    pub(crate) kind: CommandKind,
+    pub(crate) concurrency: Concurrency,
+    /// Already merged with the global hooks.
+    pub(crate) hooks: Hooks,
+    pub(crate) params: BTreeMap<String, ParamSpec>,
+    pub(crate) undo: Option<Undo>,
+    pub(crate) run_as: RunAs,
+    pub(crate) throttle: Option<Throttle>,
+    /// Filled in from the global lists where the entry sets none, see `Commander::create`.
+    pub(crate) ip_filter: IpFilter,
+}
+
+impl CommandSpec {
+    /// Decode the parameters in the extension area of a request and check them against `params`:
+    /// every parameter sent must be declared and valid, and every declared one without a default
+    /// must be sent.
+    /// Returns the `(name, value)` pairs for the environment, defaults filled in, sorted by name.
+    pub(crate) fn resolve_params(
+        &self,
+        area: &[u8; EXTENSIONS_SIZE],
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let sent = decode_params(area)?;
+        if let Some((name, _)) = sent.iter().find(|(name, _)| !self.params.contains_key(name)) {
+            bail!("Command {} has no parameter {name}", self.name);
+        }
+        self.params
+            .iter()
+            .map(|(name, spec)| {
+                let value = match sent.iter().find(|(sent_name, _)| sent_name == name) {
+                    Some((_, value)) => spec.validate(name, value)?,
+                    None => spec.default_value().ok_or_else(|| {
+                        anyhow!("Command {} requires parameter {name}", self.name)
+                    })?,
+                };
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
 }
 
 /// Commander-only configuration: the map of command name -> shell command. Kept in a separate
 /// file (`commands.toml`) so the network-facing server process never loads it.
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct ConfigCommands {
+    /// Shell command run whenever a `canary` or `duress` entry is received. Mandatory as soon as
+    /// one such entry exists, so a decoy can never be configured without anyone being told.
+    #[serde(default)]
+    pub(crate) alert_cmd: Option<String>,
+    /// Global `on_success`/`on_failure`/`on_timeout`, the fallback for every entry.
+    #[serde(flatten)]
+    pub(crate) hooks: Hooks,
     pub(crate) commands: HashMap<String, CommandValue>,
 }
 
@@ -161,20 +876,82 @@ impl ConfigCommands {
     /// directly, before per-command timeouts existed.
     pub fn from_map(commands: HashMap<String, String>) -> ConfigCommands {
         ConfigCommands {
+            alert_cmd: None,
+            hooks: Hooks::default(),
             commands: commands.into_iter().map(|(k, v)| (k, CommandValue::Plain(v))).collect(),
         }
     }
 
+    /// Resolve the command map, keyed by the hash of each name. Also rejects the inconsistent
+    /// `canary`/`duress` setups here, at startup, rather than on the first (possibly hostile) knock.
     pub(crate) fn get_hash_to_cmd(&self) -> anyhow::Result<HashMap<u64, CommandSpec>> {
         self.commands
             .iter()
             .map(|(k, v)| {
+                let kind = v.kind();
+                let program = v.program().with_context(|| format!("Invalid command {k}"))?;
+                match (kind, program.is_empty()) {
+                    (CommandKind::Canary, false) => {
+                        bail!("Canary command {k} must not have a cmd or argv, it only alerts")
+                    }
+                    (CommandKind::Normal | CommandKind::Duress, true) => {
+                        bail!("Command {k} has an empty cmd")
+                    }
+                    _ => {}
+                }
+                let undo = v.undo().with_context(|| format!("Invalid undo for command {k}"))?;
+                let run_as = v.run_as().with_context(|| format!("Invalid command {k}"))?;
+                if kind == CommandKind::Canary && undo.is_some() {
+                    bail!("Canary command {k} must not have an undo_cmd, it never runs anything")
+                }
+                let throttle = v.throttle().with_context(|| format!("Invalid command {k}"))?;
+                if kind == CommandKind::Canary && throttle.is_some() {
+                    bail!("Canary command {k} must not be throttled, its alert always goes out")
+                }
+                let ip_filter = v.ip_filter().with_context(|| format!("Invalid command {k}"))?;
+                if kind == CommandKind::Canary && ip_filter != IpFilter::default() {
+                    bail!(
+                        "Canary command {k} must not have allow_cidrs or deny_cidrs, its alert \
+                           always goes out"
+                    )
+                }
+                // A repeat knock only extends the TTL if it is handled after the first one is done.
+                if v.concurrency() == Concurrency::Parallel && undo.is_some() {
+                    bail!("Command {k} has an undo_cmd, so its concurrency cannot be parallel")
+                }
+                let params = v.params();
+                for (name, spec) in &params {
+                    spec.check(name).with_context(|| format!("Invalid params for command {k}"))?;
+                }
+                // Expanding with dummy values rejects unknown placeholders now, not per request.
+                let known = |name: &str| {
+                    let param = name.strip_prefix("param_");
+                    let is_param = param.is_some_and(|p| params.contains_key(p));
+                    (is_param || PLACEHOLDERS.contains(&name)).then(String::new)
+                };
+                program.expand(known).with_context(|| format!("Invalid argv for command {k}"))?;
+                if kind.alerts() && self.alert_cmd.is_none() {
+                    bail!(
+                        "Command {k} is a {} command, but no alert_cmd is configured",
+                        kind.as_str()
+                    )
+                }
+
                 let hash = blake2b_u64(k).with_context(|| format!("Could not hash {k}"))?;
                 Ok((
                     hash,
                     CommandSpec {
-                        cmd: v.cmd().to_string(),
+                        name: k.to_string(),
+                        program,
                         timeout: v.timeout(),
+                        kind,
+                        concurrency: v.concurrency(),
+                        hooks: v.hooks().map_or_else(|| self.hooks.clone(), |h| h.or(&self.hooks)),
+                        params,
+                        undo,
+                        run_as,
+                        throttle,
+                        ip_filter,
                     },
                 ))
             })
@@ -184,11 +961,20 @@ impl ConfigCommands {
 
 #[cfg(test)]
 mod tests {
-    use super::{ConfigCommander, ConfigCommands, DEFAULT_TIMEOUT_SECS};
-    use std::collections::HashMap;
+    use super::{
+        expand_placeholders, CommandKind, Concurrency, ConfigCommander, ConfigCommands, Hooks,
+        IpFilter, ParamSpec, Program, Rlimits, RunAs, Throttle, ThrottleBy, Undo,
+        DEFAULT_TIMEOUT_SECS,
+    };
+    use crate::common::protocol::EXTENSIONS_SIZE;
+    use std::collections::{BTreeMap, HashMap};
     use std::path::PathBuf;
     use std::time::Duration;
 
+    fn shell(cmd: &str) -> Program {
+        Program::Shell(cmd.to_string())
+    }
+
     #[test]
     fn test_config_commander_reads_shared_fields_and_ignores_server_fields() {
         // A real config.toml carries server-only fields too; the commander view ignores them.
@@ -210,6 +996,12 @@ mod tests {
         let config = ConfigCommander::deserialize("ips = [\"127.0.0.1\"]").unwrap();
         assert_eq!(config.config_dir, PathBuf::from("/etc/ruroco"));
         assert_eq!(config.socket_dir, None);
+        assert_eq!(config.state_dir, None);
+        assert_eq!(config.max_concurrent, 4);
+        assert_eq!(config.output_dir, None);
+        assert_eq!(config.output_max_bytes, 1024 * 1024);
+        assert_eq!(config.output_keep, 20);
+        assert_eq!(config.output_max_age_sec, None);
         assert_eq!(config.socket_user, "ruroco");
         assert_eq!(config.socket_group, "ruroco");
     }
@@ -230,8 +1022,8 @@ mod tests {
         let config = ConfigCommands::from_map(commands);
         let hash_map = config.get_hash_to_cmd().unwrap();
         assert_eq!(hash_map.len(), 2);
-        assert!(hash_map.values().any(|v| v.cmd == "echo hello"));
-        assert!(hash_map.values().any(|v| v.cmd == "systemctl restart foo"));
+        assert!(hash_map.values().any(|v| v.program == shell("echo hello")));
+        assert!(hash_map.values().any(|v| v.program == shell("systemctl restart foo")));
         assert!(hash_map.values().all(|v| v.timeout == Duration::from_secs(DEFAULT_TIMEOUT_SECS)));
     }
 
@@ -244,7 +1036,7 @@ mod tests {
         "#;
         let config = ConfigCommands::deserialize(toml).unwrap();
         assert_eq!(config.commands.len(), 2);
-        assert_eq!(config.commands.get("default").unwrap().cmd(), "echo hello");
+        assert_eq!(config.commands.get("default").unwrap().program().unwrap(), shell("echo hello"));
         assert_eq!(
             config.commands.get("default").unwrap().timeout(),
             Duration::from_secs(DEFAULT_TIMEOUT_SECS)
@@ -260,13 +1052,13 @@ mod tests {
         "#;
         let config = ConfigCommands::deserialize(toml).unwrap();
         let slow = config.commands.get("slow").unwrap();
-        assert_eq!(slow.cmd(), "sleep 5");
+        assert_eq!(slow.program().unwrap(), shell("sleep 5"));
         assert_eq!(slow.timeout(), Duration::from_secs(1));
 
         let hash_map = config.get_hash_to_cmd().unwrap();
         assert!(hash_map
             .values()
-            .any(|v| v.cmd == "sleep 5" && v.timeout == Duration::from_secs(1)));
+            .any(|v| v.program == shell("sleep 5") && v.timeout == Duration::from_secs(1)));
     }
 
     #[test]
@@ -277,7 +1069,7 @@ mod tests {
         "#;
         let config = ConfigCommands::deserialize(toml).unwrap();
         let entry = config.commands.get("default").unwrap();
-        assert_eq!(entry.cmd(), "echo hello");
+        assert_eq!(entry.program().unwrap(), shell("echo hello"));
         assert_eq!(entry.timeout(), Duration::from_secs(DEFAULT_TIMEOUT_SECS));
     }
 
@@ -298,7 +1090,7 @@ mod tests {
         let path = dir.path().join("commands.toml");
         std::fs::write(&path, "[commands]\ndefault = \"echo hi\"\n").unwrap();
         let commands = ConfigCommands::create_from_path(&path).unwrap();
-        assert_eq!(commands.commands.get("default").unwrap().cmd(), "echo hi");
+        assert_eq!(commands.commands.get("default").unwrap().program().unwrap(), shell("echo hi"));
     }
 
     #[test]
@@ -307,4 +1099,503 @@ mod tests {
         let err = ConfigCommands::create_from_path(&path).unwrap_err().to_string();
         assert!(err.contains("Could not read"), "unexpected error: {err}");
     }
+
+    #[test]
+    fn test_deserialize_canary_and_duress() {
+        let toml = r#"
+            alert_cmd = "logger -p auth.crit ruroco alert"
+            [commands]
+            open = "echo open"
+            backup = { kind = "canary" }
+            open_duress = { cmd = "echo open", kind = "duress", timeout_sec = 5 }
+        "#;
+        let config = ConfigCommands::deserialize(toml).unwrap();
+        assert_eq!(config.alert_cmd.as_deref(), Some("logger -p auth.crit ruroco alert"));
+        assert_eq!(config.commands.get("open").unwrap().kind(), CommandKind::Normal);
+        assert_eq!(config.commands.get("backup").unwrap().kind(), CommandKind::Canary);
+        assert_eq!(config.commands.get("open_duress").unwrap().kind(), CommandKind::Duress);
+
+        let hash_map = config.get_hash_to_cmd().unwrap();
+        assert!(hash_map
+            .values()
+            .any(|v| v.name == "backup" && v.kind == CommandKind::Canary && v.program.is_empty()));
+        assert!(hash_map.values().any(|v| v.name == "open_duress"
+            && v.kind == CommandKind::Duress
+            && v.program == shell("echo open")
+            && v.timeout == Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_canary_without_alert_cmd_is_rejected() {
+        let config =
+            ConfigCommands::deserialize("[commands]\nbackup = { kind = \"canary\" }\n").unwrap();
+        let err = config.get_hash_to_cmd().unwrap_err().to_string();
+        assert!(err.contains("no alert_cmd is configured"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_canary_with_cmd_is_rejected() {
+        let toml = r#"
+            alert_cmd = "true"
+            [commands]
+            backup = { cmd = "echo hi", kind = "canary" }
+        "#;
+        let err = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap_err();
+        assert!(err.to_string().contains("must not have a cmd"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_duress_without_cmd_is_rejected() {
+        let toml = r#"
+            alert_cmd = "true"
+            [commands]
+            open = { kind = "duress" }
+        "#;
+        let err = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap_err();
+        assert!(err.to_string().contains("has an empty cmd"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_unknown_kind_is_rejected() {
+        let toml = "[commands]\nopen = { cmd = \"echo\", kind = \"decoy\" }\n";
+        assert!(ConfigCommands::deserialize(toml).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_hooks_global_and_per_command() {
+        let toml = r#"
+            on_success = "echo global ok"
+            on_failure = "echo global fail"
+            [commands]
+            plain = "echo plain"
+            custom = { cmd = "echo custom", on_failure = "echo custom fail", on_timeout = "echo slow" }
+        "#;
+        let config = ConfigCommands::deserialize(toml).unwrap();
+        let hash_map = config.get_hash_to_cmd().unwrap();
+
+        let plain = hash_map.values().find(|v| v.name == "plain").unwrap();
+        assert_eq!(
+            plain.hooks,
+            Hooks {
+                on_success: Some("echo global ok".to_string()),
+                on_failure: Some("echo global fail".to_string()),
+                on_timeout: None,
+            }
+        );
+
+        let custom = hash_map.values().find(|v| v.name == "custom").unwrap();
+        assert_eq!(
+            custom.hooks,
+            Hooks {
+                on_success: Some("echo global ok".to_string()),
+                on_failure: Some("echo custom fail".to_string()),
+                on_timeout: Some("echo slow".to_string()),
+            }
+        );
+    }
+
+    /// An extension area holding `entries` (`name=value`), as a v2 client would send it.
+    fn params_area(entries: &[&str]) -> [u8; EXTENSIONS_SIZE] {
+        let mut area = [0u8; EXTENSIONS_SIZE];
+        let mut pos = 0;
+        for entry in entries {
+            area[pos] = 1;
+            area[pos + 1] = entry.len() as u8;
+            area[pos + 2..pos + 2 + entry.len()].copy_from_slice(entry.as_bytes());
+            pos += 2 + entry.len();
+        }
+        area
+    }
+
+    #[test]
+    fn test_deserialize_params() {
+        let toml = r#"
+            [commands]
+            open_ssh = { cmd = "echo $RUROCO_PARAM_HOURS", params = { hours = { type = "int", min = 1, max = 8, default = 1 }, proto = { type = "enum", values = ["tcp", "udp"] } } }
+        "#;
+        let config = ConfigCommands::deserialize(toml).unwrap();
+        let spec = config.get_hash_to_cmd().unwrap().into_values().next().unwrap();
+        assert_eq!(
+            spec.params.get("hours"),
+            Some(&ParamSpec::Int {
+                min: 1,
+                max: 8,
+                default: Some(1)
+            })
+        );
+        assert_eq!(
+            spec.params.get("proto"),
+            Some(&ParamSpec::Enum {
+                values: vec!["tcp".to_string(), "udp".to_string()],
+                default: None
+            })
+        );
+
+        let resolved = spec.resolve_params(&params_area(&["proto=udp", "hours=+08"])).unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                ("hours".to_string(), "8".to_string()),
+                ("proto".to_string(), "udp".to_string())
+            ]
+        );
+        let resolved = spec.resolve_params(&params_area(&["proto=tcp"])).unwrap();
+        assert_eq!(resolved[0], ("hours".to_string(), "1".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_params_rejects_invalid_requests() {
+        let toml = r#"
+            [commands]
+            open_ssh = { cmd = "true", params = { hours = { type = "int", min = 1, max = 8 }, proto = { type = "enum", values = ["tcp"], default = "tcp" } } }
+        "#;
+        let config = ConfigCommands::deserialize(toml).unwrap();
+        let spec = config.get_hash_to_cmd().unwrap().into_values().next().unwrap();
+        for (entries, expected) in [
+            (vec![], "requires parameter hours"),
+            (vec!["hours=9"], "must be between 1 and 8, got 9"),
+            (vec!["hours=many"], "must be an integer"),
+            (vec!["hours=1", "proto=udp"], "must be one of [\"tcp\"]"),
+            (vec!["hours=1", "port=22"], "has no parameter port"),
+        ] {
+            let err = spec.resolve_params(&params_area(&entries)).unwrap_err().to_string();
+            assert!(err.contains(expected), "{entries:?}: {err}");
+        }
+
+        // Commands without a schema accept requests without parameters only.
+        let plain = ConfigCommands::deserialize("[commands]\nopen = \"true\"\n").unwrap();
+        let spec = plain.get_hash_to_cmd().unwrap().into_values().next().unwrap();
+        assert_eq!(spec.resolve_params(&[0u8; EXTENSIONS_SIZE]).unwrap(), vec![]);
+        assert!(spec.resolve_params(&params_area(&["hours=1"])).is_err());
+    }
+
+    #[test]
+    fn test_invalid_param_schemas_are_rejected() {
+        for (params, expected) in [
+            (r#"{ Hours = { type = "int", min = 1, max = 8 } }"#, "Invalid parameter name"),
+            (r#"{ hours = { type = "int", min = 8, max = 1 } }"#, "min 8 above max 1"),
+            (r#"{ hours = { type = "int", min = 1, max = 8, default = 9 } }"#, "Invalid default"),
+            (r#"{ proto = { type = "enum", values = [] } }"#, "has no values"),
+            (r#"{ proto = { type = "enum", values = ["a b"] } }"#, "Invalid value"),
+        ] {
+            let toml = format!("[commands]\nopen = {{ cmd = \"true\", params = {params} }}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{params}: {err:#}");
+        }
+        let toml = "[commands]\nopen = { cmd = \"true\", params = { n = { type = \"float\" } } }\n";
+        assert!(ConfigCommands::deserialize(toml).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_undo() {
+        let toml = r#"
+            [commands]
+            open = { cmd = "ufw allow from $RUROCO_IP", undo_cmd = "ufw delete allow from $RUROCO_IP", ttl_sec = 3600 }
+            plain = "true"
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let open = hash_map.values().find(|v| v.name == "open").unwrap();
+        assert_eq!(
+            open.undo,
+            Some(Undo {
+                cmd: "ufw delete allow from $RUROCO_IP".to_string(),
+                ttl: Duration::from_secs(3600),
+            })
+        );
+        assert_eq!(hash_map.values().find(|v| v.name == "plain").unwrap().undo, None);
+    }
+
+    #[test]
+    fn test_invalid_undo_is_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", undo_cmd = "true" }"#, "undo_cmd needs a ttl_sec"),
+            (r#"{ cmd = "true", ttl_sec = 60 }"#, "ttl_sec needs an undo_cmd"),
+            (r#"{ cmd = "true", undo_cmd = "true", ttl_sec = 0 }"#, "greater than 0"),
+            (r#"{ cmd = "true", undo_cmd = " ", ttl_sec = 60 }"#, "undo_cmd is empty"),
+            (
+                r#"{ kind = "canary", undo_cmd = "true", ttl_sec = 60 }"#,
+                "must not have an undo_cmd",
+            ),
+        ] {
+            let toml = format!("alert_cmd = \"true\"\n[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_run_as() {
+        let toml = r#"
+            [commands]
+            plain = "true"
+            open = { cmd = "true", user = "root", cwd = "/tmp", umask = 0o077, env = { A = "1" } }
+            grouped = { cmd = "true", group = "root" }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let run_as =
+            |name: &str| hash_map.values().find(|v| v.name == name).unwrap().run_as.clone();
+        assert_eq!(run_as("plain"), RunAs::default());
+        assert_eq!(
+            run_as("open"),
+            RunAs {
+                uid: Some(0),
+                gid: Some(0),
+                cwd: Some(PathBuf::from("/tmp")),
+                env: BTreeMap::from([("A".to_string(), "1".to_string())]),
+                umask: Some(0o077),
+                rlimits: Rlimits::default(),
+            }
+        );
+        assert_eq!(
+            run_as("grouped"),
+            RunAs {
+                gid: Some(0),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_run_as_is_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", user = "no-such-user-ruroco" }"#, "Could not find user"),
+            (r#"{ cmd = "true", group = "no-such-group-ruroco" }"#, "Could not find group"),
+            (r#"{ cmd = "true", cwd = "tmp" }"#, "not an absolute path"),
+            (r#"{ cmd = "true", env = { "A-B" = "1" } }"#, "Invalid env name"),
+            (r#"{ cmd = "true", env = { RUROCO_IP = "1" } }"#, "would shadow"),
+            (r#"{ cmd = "true", umask = 0o1000 }"#, "not between"),
+        ] {
+            let toml = format!("[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_rlimits() {
+        let config = ConfigCommander::deserialize("").unwrap();
+        assert_eq!(config.rlimits, Rlimits::default());
+        let config = ConfigCommander::deserialize("[rlimits]\ncpu_sec = 60\n").unwrap();
+        assert_eq!(
+            config.rlimits,
+            Rlimits {
+                cpu_sec: Some(60),
+                ..Default::default()
+            }
+        );
+
+        let toml = r#"
+            [commands]
+            open = { cmd = "true", rlimits = { as_bytes = 1073741824, nproc = 0 } }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let rlimits = hash_map.values().next().unwrap().run_as.rlimits;
+        assert_eq!(
+            rlimits.or(config.rlimits),
+            Rlimits {
+                cpu_sec: Some(60),
+                as_bytes: Some(1073741824),
+                nproc: Some(0),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_rlimits_are_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", rlimits = { nofile = 0 } }"#, "nofile must be greater than 0"),
+            (r#"{ cmd = "true", rlimits = { cpu_sec = 0 } }"#, "cpu_sec must be greater than 0"),
+        ] {
+            let toml = format!("[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+        let err = ConfigCommander::deserialize("[rlimits]\nas_bytes = 0\n").unwrap_err();
+        assert!(format!("{err:#}").contains("as_bytes must be greater than 0"), "{err:#}");
+        assert!(ConfigCommander::deserialize("[rlimits]\nfiles = 10\n").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_throttle() {
+        let toml = r#"
+            [commands]
+            plain = "true"
+            open = { cmd = "true", cooldown_sec = 30, max_per_hour = 5, throttle_by = "ip" }
+            capped = { cmd = "true", max_per_hour = 2 }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let throttle =
+            |name: &str| hash_map.values().find(|v| v.name == name).unwrap().throttle.clone();
+        assert_eq!(throttle("plain"), None);
+        assert_eq!(
+            throttle("open"),
+            Some(Throttle {
+                cooldown: Some(Duration::from_secs(30)),
+                max_per_hour: Some(5),
+                by: ThrottleBy::Ip,
+            })
+        );
+        assert_eq!(
+            throttle("capped"),
+            Some(Throttle {
+                cooldown: None,
+                max_per_hour: Some(2),
+                by: ThrottleBy::Command,
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_throttle_is_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", cooldown_sec = 0 }"#, "cooldown_sec must be greater than 0"),
+            (r#"{ cmd = "true", max_per_hour = 0 }"#, "max_per_hour must be greater than 0"),
+            (r#"{ cmd = "true", throttle_by = "key" }"#, "throttle_by needs"),
+            (r#"{ kind = "canary", max_per_hour = 1 }"#, "must not be throttled"),
+        ] {
+            let toml = format!("[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+        let toml = "[commands]\nopen = { cmd = \"true\", throttle_by = \"user\" }\n";
+        assert!(ConfigCommands::deserialize(toml).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_cidrs() {
+        let config = ConfigCommander::deserialize(
+            "allow_cidrs = [\"10.0.0.0/8\"]\ndeny_cidrs = [\"0.0.0.0/0\", \"::/0\"]",
+        )
+        .unwrap();
+        assert_eq!(config.allow_cidrs, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(config.deny_cidrs.len(), 2);
+        let err = ConfigCommander::deserialize("allow_cidrs = [\"10.0.0.1/8\"]").unwrap_err();
+        assert!(format!("{err:#}").contains("host bits are set"), "{err:#}");
+
+        let toml = r#"
+            [commands]
+            plain = "true"
+            vpn = { cmd = "true", allow_cidrs = ["10.8.0.0/24"], deny_cidrs = [] }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let ip_filter =
+            |name: &str| hash_map.values().find(|v| v.name == name).unwrap().ip_filter.clone();
+        assert_eq!(ip_filter("plain"), IpFilter::default());
+        assert_eq!(
+            ip_filter("vpn"),
+            IpFilter {
+                allow: Some(vec!["10.8.0.0/24".parse().unwrap()]),
+                deny: Some(vec![]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_allow_non_routable_ips_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "allow_non_routable_ips = true\n").unwrap();
+        let err = ConfigCommander::create_from_path(&path).unwrap_err();
+        assert!(err.to_string().contains("replaced by allow_cidrs"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid_cidrs_are_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", allow_cidrs = ["vpn.example.com"] }"#, "Invalid CIDR vpn"),
+            (r#"{ cmd = "true", deny_cidrs = ["::/129"] }"#, "prefix length is not 0 to 128"),
+            (r#"{ kind = "canary", deny_cidrs = ["::/0"] }"#, "must not have allow_cidrs"),
+        ] {
+            let toml = format!("alert_cmd = \"true\"\n[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_argv() {
+        let toml = r#"
+            [commands]
+            open = { argv = ["ufw", "allow", "from", "{ip}", "port", "{param_port}"], params = { port = { type = "int", min = 1, max = 65535 } } }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let spec = hash_map.values().next().unwrap();
+        let argv = ["ufw", "allow", "from", "{ip}", "port", "{param_port}"];
+        assert_eq!(spec.program, Program::Argv(argv.map(String::from).to_vec()));
+        let expanded = spec.program.expand(|name| Some(name.to_uppercase())).unwrap();
+        assert_eq!(expanded.to_string(), "ufw allow from IP port PARAM_PORT");
+    }
+
+    #[test]
+    fn test_invalid_argv_is_rejected() {
+        for (entry, expected) in [
+            (r#"{ cmd = "true", argv = ["true"] }"#, "cannot both be set"),
+            (r#"{ argv = [] }"#, "argv is empty"),
+            (r#"{ argv = ["", "x"] }"#, "argv is empty"),
+            (r#"{ argv = ["echo", "{host}"] }"#, "Unknown placeholder {host}"),
+            (r#"{ argv = ["echo", "{param_port}"] }"#, "Unknown placeholder {param_port}"),
+            (r#"{ argv = ["echo", "{ip"] }"#, "Unclosed"),
+            (r#"{ kind = "canary", argv = ["true"] }"#, "must not have a cmd or argv"),
+        ] {
+            let toml = format!("alert_cmd = \"true\"\n[commands]\nopen = {entry}\n");
+            let err = ConfigCommands::deserialize(&toml).unwrap().get_hash_to_cmd().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{entry}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn test_expand_placeholders() {
+        let lookup = |name: &str| (name == "ip").then(|| "1.2.3.4".to_string());
+        assert_eq!(expand_placeholders("{ip}", lookup).unwrap(), "1.2.3.4");
+        assert_eq!(expand_placeholders("from={ip}/32", lookup).unwrap(), "from=1.2.3.4/32");
+        assert_eq!(expand_placeholders("{{ip}} {ip}", lookup).unwrap(), "{ip} 1.2.3.4");
+        assert_eq!(expand_placeholders("a}}b", lookup).unwrap(), "a}b");
+        assert_eq!(expand_placeholders("plain", lookup).unwrap(), "plain");
+        // A value is inserted as it is, never parsed for further placeholders.
+        let lookup = |_: &str| Some("{ip}".to_string());
+        assert_eq!(expand_placeholders("{ip}", lookup).unwrap(), "{ip}");
+        for arg in ["{}", "{ip", "ip}", "{nope}"] {
+            assert!(expand_placeholders(arg, |_| None).is_err(), "{arg}");
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_must_be_positive() {
+        let config = ConfigCommander::deserialize("max_concurrent = 2").unwrap();
+        assert_eq!(config.max_concurrent, 2);
+        let err = ConfigCommander::deserialize("max_concurrent = 0").unwrap_err();
+        assert!(err.to_string().contains("at least 1"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid_output_settings_are_rejected() {
+        let err = ConfigCommander::deserialize("output_keep = 0").unwrap_err();
+        assert!(err.to_string().contains("output_keep must be at least 1"), "{err}");
+        let err = ConfigCommander::deserialize("output_max_age_sec = 0").unwrap_err();
+        assert!(err.to_string().contains("greater than 0"), "{err}");
+    }
+
+    #[test]
+    fn test_deserialize_concurrency() {
+        let toml = r#"
+            [commands]
+            plain = "true"
+            once = { cmd = "true", concurrency = "single" }
+            many = { cmd = "true", concurrency = "parallel" }
+        "#;
+        let hash_map = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap();
+        let concurrency =
+            |name: &str| hash_map.values().find(|v| v.name == name).unwrap().concurrency;
+        assert_eq!(concurrency("plain"), Concurrency::Queue);
+        assert_eq!(concurrency("once"), Concurrency::Single);
+        assert_eq!(concurrency("many"), Concurrency::Parallel);
+
+        let toml = "[commands]\nopen = { cmd = \"true\", concurrency = \"always\" }\n";
+        assert!(ConfigCommands::deserialize(toml).is_err());
+        let toml = "[commands]\nopen = { cmd = \"true\", undo_cmd = \"true\", ttl_sec = 60, \
+                    concurrency = \"parallel\" }\n";
+        let err = ConfigCommands::deserialize(toml).unwrap().get_hash_to_cmd().unwrap_err();
+        assert!(err.to_string().contains("cannot be parallel"), "{err}");
+    }
 }
@@ -1,29 +1,78 @@
 use super::Commander;
-use crate::commander::ip_filter;
+use crate::commander::audit::{self, AuditRecord};
+use crate::commander::config::{AuditCommand, CommanderCommand};
+use crate::commander::config::{CommandSpec, Program, Rlimits, RunAs, DEFAULT_TIMEOUT_SECS};
+use crate::commander::output::RunOutput;
 use crate::commander::CliCommander;
+use crate::common::events::EventKind;
+use crate::common::fs::{get_gid_by_name, get_uid_by_name};
 use crate::common::instance_lock::InstanceLock;
+use crate::common::ipc::{key_id_hex, CommanderData};
 use crate::common::logging::error;
-use crate::common::{change_file_ownership, info};
-use anyhow::{bail, Context};
+use crate::common::notify::NOTIFY_ENV_VARS;
+use crate::common::protocol::KEY_ID_SIZE;
+use crate::common::{change_file_ownership, format_nanos, info};
+use anyhow::{anyhow, bail, Context};
+use chrono::{SecondsFormat, Utc};
+use nix::errno::Errno;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::socket::getsockopt;
+use nix::sys::socket::sockopt::PeerCredentials;
 use nix::sys::stat::{umask, Mode};
+use nix::unistd::{Gid, Pid, Uid};
 use std::fs::Permissions;
+use std::io;
 use std::net::IpAddr;
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, fs, process, str, thread};
 
 const ENV_PREFIX: &str = "RUROCO_";
 const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait for a process group to disappear after `SIGKILL` before reporting survivors.
+const KILL_WAIT: Duration = Duration::from_secs(1);
+/// Cap on the stdout/stderr handed to a hook via the environment, in bytes per stream. Keeps a
+/// chatty command from hitting the kernel's per-variable limit (`MAX_ARG_STRLEN`, 128 KiB).
+const HOOK_OUTPUT_LIMIT: usize = 4096;
+
+/// Whom a run is for: the client IP and the key that authenticated the request. Taken from the
+/// request itself, never from the child's environment, since it ends up in the audit log.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Requester {
+    pub(super) ip: IpAddr,
+    pub(super) key_id: [u8; KEY_ID_SIZE],
+}
+
+impl From<&CommanderData> for Requester {
+    fn from(data: &CommanderData) -> Self {
+        Requester {
+            ip: data.ip,
+            key_id: data.key_id,
+        }
+    }
+}
 
 /// How a spawned command finished: on its own, or killed at the timeout deadline.
-enum CommandExit {
+pub(super) enum CommandExit {
     Completed(std::process::ExitStatus),
     TimedOut,
 }
 
+impl CommandExit {
+    /// For the output file and the audit log: `exit status: 0`, `signal: 9 (SIGKILL)`, ...
+    fn describe(&self, timeout: Duration) -> String {
+        match self {
+            CommandExit::Completed(status) => status.to_string(),
+            CommandExit::TimedOut => format!("timed out after {timeout:?} and was killed"),
+        }
+    }
+}
+
 impl Commander {
     /// Also returns an `InstanceLock` the caller must keep alive for as long as the listener is
     /// served: without it, a second commander instance accidentally started alongside this one
@@ -72,41 +121,249 @@ impl Commander {
         change_file_ownership(&self.socket_path, self.socket_user.trim(), self.socket_group.trim())
     }
 
-    /// Run `command` via `sh -c`, killing it (SIGKILL, the `sh` process only) once `timeout`
+    /// Who may send requests: `socket_user`/`socket_group`, the server, or the commander's own
+    /// user/group where they are empty. That is who the socket ends up owned by, see
+    /// `create_listener`.
+    pub(super) fn expected_peer(&self) -> anyhow::Result<Peer> {
+        let (user, group) = (self.socket_user.trim(), self.socket_group.trim());
+        Ok(Peer {
+            uid: if user.is_empty() {
+                Uid::effective().as_raw()
+            } else {
+                get_uid_by_name(user)?
+            },
+            gid: if group.is_empty() {
+                Gid::effective().as_raw()
+            } else {
+                get_gid_by_name(group)?
+            },
+        })
+    }
+
+    /// Run `command` via `sh -c`, killing its process group (see `kill_group`) once `timeout`
     /// elapses. Output is captured through temp files instead of pipes so a chatty command can
     /// never dead-lock on a full pipe buffer while we poll for its exit. Errors are logged, never
-    /// returned: one bad command must not take down the accept loop.
-    pub(super) fn run_command(&self, command: &str, timeout: Duration, ip: IpAddr) {
-        if !self.allow_non_routable_ips && !Self::is_ip_allowed(ip) {
+    /// returned: one bad command must not take down the accept loop. Returns whether the command
+    /// ran and exited 0. The IP filter is up to the caller, see `handle_request`.
+    pub(super) fn run_command(&self, spec: &CommandSpec, data: &CommanderData) -> bool {
+        let ip = data.ip;
+
+        self.events.publish(EventKind::CommandStarted {
+            name: spec.name.clone(),
+            ip: ip.to_string(),
+        });
+        let start = Instant::now();
+        let env = request_env(spec, data);
+        let outcome = match spec.program.expand(|name| placeholder_value(&env, name)) {
+            Ok(program) => {
+                let requester = Requester::from(data);
+                self.execute_and_log(
+                    &spec.name,
+                    &program,
+                    spec.timeout,
+                    requester,
+                    &env,
+                    &spec.run_as,
+                )
+            }
+            Err(e) => {
+                error(format!("Could not fill in argv of {} for {ip}: {e}", spec.name));
+                None
+            }
+        };
+        let duration_ms = u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX);
+        self.events.publish(EventKind::CommandFinished {
+            name: spec.name.clone(),
+            ip: ip.to_string(),
+            exit_code: match &outcome {
+                Some((CommandExit::Completed(status), ..)) => status.code(),
+                _ => None,
+            },
+            timed_out: matches!(outcome, Some((CommandExit::TimedOut, ..))),
+            duration_ms,
+        });
+        self.run_hook(spec, data, outcome.as_ref(), duration_ms);
+        matches!(outcome, Some((CommandExit::Completed(status), ..)) if status.success())
+    }
+
+    /// Run the `on_success`/`on_failure`/`on_timeout` hook matching how `spec` ended, if one is
+    /// configured. The hook sees the command's context through `RUROCO_*` variables, with its
+    /// output truncated to `HOOK_OUTPUT_LIMIT`.
+    fn run_hook(
+        &self,
+        spec: &CommandSpec,
+        data: &CommanderData,
+        outcome: Option<&(CommandExit, String, String)>,
+        duration_ms: u64,
+    ) {
+        let (hook, result, exit_code, stdout, stderr) = match outcome {
+            Some((CommandExit::Completed(status), stdout, stderr)) if status.success() => {
+                (&spec.hooks.on_success, "success", status.code(), stdout.as_str(), stderr.as_str())
+            }
+            Some((CommandExit::Completed(status), stdout, stderr)) => {
+                (&spec.hooks.on_failure, "failure", status.code(), stdout.as_str(), stderr.as_str())
+            }
+            Some((CommandExit::TimedOut, stdout, stderr)) => {
+                (&spec.hooks.on_timeout, "timeout", None, stdout.as_str(), stderr.as_str())
+            }
+            None => (&spec.hooks.on_failure, "failure", None, "", ""),
+        };
+        let Some(hook) = hook else {
             return;
-        }
+        };
 
-        match Self::execute_with_timeout(command, timeout, ip) {
-            Ok((CommandExit::Completed(status), stdout, stderr)) => {
-                let msg = format!("{command} for {ip}\nstdout: {stdout}\nstderr: {stderr}");
-                if status.success() {
-                    info(format!("Execution was successful: {msg}"))
-                } else {
-                    error(format!("Execution was not successful: {msg}"))
+        info(format!("Running on_{result} hook for {}", spec.name));
+        let mut env = request_env(spec, data);
+        env.extend([
+            (format!("{ENV_PREFIX}RESULT"), result.to_string()),
+            // Empty when there is no exit code: killed by a signal, timed out, or never spawned.
+            (
+                format!("{ENV_PREFIX}EXIT_CODE"),
+                exit_code.map(|c| c.to_string()).unwrap_or_default(),
+            ),
+            (format!("{ENV_PREFIX}DURATION_MS"), duration_ms.to_string()),
+            (format!("{ENV_PREFIX}STDOUT"), truncate_for_env(stdout)),
+            (format!("{ENV_PREFIX}STDERR"), truncate_for_env(stderr)),
+        ]);
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+        let hook = Program::Shell(hook.clone());
+        let name = format!("{}.on_{result}", spec.name);
+        let run_as = RunAs {
+            rlimits: self.rlimits,
+            ..Default::default()
+        };
+        self.execute_and_log(&name, &hook, timeout, data.into(), &env, &run_as);
+    }
+
+    /// Run `alert_cmd` for a received `canary`/`duress` command. Deliberately not subject to the
+    /// IP filter (`allow_cidrs`/`deny_cidrs`): a decoy knock is worth reporting whatever address
+    /// it claims, and `alert_cmd` is admin-written, so the client cannot steer what it does with
+    /// the values.
+    pub(super) fn run_alert(&self, spec: &CommandSpec, data: &CommanderData) -> anyhow::Result<()> {
+        let alert_cmd = self.alert_cmd.as_deref().ok_or_else(|| {
+            anyhow!("No alert_cmd configured for {} command {}", spec.kind.as_str(), spec.name)
+        })?;
+
+        error(format!(
+            "ALERT: {} command {} received from {} with key {} ({})",
+            spec.kind.as_str(),
+            spec.name,
+            data.ip,
+            data.key_label,
+            key_id_hex(&data.key_id)
+        ));
+
+        let mut env = request_env(spec, data);
+        env.extend([
+            (format!("{ENV_PREFIX}ALERT_KIND"), spec.kind.as_str().to_string()),
+            (
+                format!("{ENV_PREFIX}ALERT_TIME"),
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+        ]);
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+        let alert_cmd = Program::Shell(alert_cmd.to_string());
+        let name = format!("{}.alert", spec.name);
+        let run_as = RunAs {
+            rlimits: self.rlimits,
+            ..Default::default()
+        };
+        self.execute_and_log(&name, &alert_cmd, timeout, data.into(), &env, &run_as);
+        Ok(())
+    }
+
+    /// Returns how the command exited plus its captured stdout/stderr, or `None` if it could not
+    /// be run at all. `name` is what the output file is named after, see `output`.
+    pub(super) fn execute_and_log(
+        &self,
+        name: &str,
+        command: &Program,
+        timeout: Duration,
+        requester: Requester,
+        env: &[(String, String)],
+        run_as: &RunAs,
+    ) -> Option<(CommandExit, String, String)> {
+        let ip = requester.ip;
+        let start = Instant::now();
+        let outcome = self.execute_with_timeout(command, timeout, env, run_as);
+        if let Some(audit) = &self.audit {
+            audit.append(&AuditRecord {
+                name,
+                key_id: &key_id_hex(&requester.key_id),
+                ip,
+                result: &match &outcome {
+                    Ok((exit, ..)) => exit.describe(timeout),
+                    Err(_) => "not run".to_string(),
+                },
+                duration_ms: u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+            });
+        }
+        match outcome {
+            Ok((exit, stdout, stderr)) => {
+                let result = exit.describe(timeout);
+                let output = self.store_output(name, command, ip, &result, &stdout, &stderr);
+                match &exit {
+                    CommandExit::Completed(status) => {
+                        let msg = format!("{command} for {ip}{output}");
+                        if status.success() {
+                            info(format!("Execution was successful: {msg}"))
+                        } else {
+                            error(format!("Execution was not successful: {msg}"))
+                        }
+                    }
+                    CommandExit::TimedOut => error(format!(
+                        "Execution timed out after {timeout:?} and was killed: {command} for \
+                         {ip}{output}"
+                    )),
                 }
+                Some((exit, stdout, stderr))
+            }
+            Err(e) => {
+                error(format!("Error executing {command} for {ip}: {e}"));
+                None
+            }
+        }
+    }
+
+    /// The end of the log line for a run: where its output was stored, or with no `output_dir`
+    /// (or if storing failed) the output itself.
+    fn store_output(
+        &self,
+        name: &str,
+        command: &Program,
+        ip: IpAddr,
+        result: &str,
+        stdout: &str,
+        stderr: &str,
+    ) -> String {
+        if let Some(output) = &self.output {
+            let command = command.to_string();
+            let run = RunOutput {
+                command: &command,
+                ip,
+                result,
+                stdout,
+                stderr,
+            };
+            match output.store(name, &run) {
+                Ok(path) => return format!(", output in {}", path.display()),
+                Err(e) => error(format!("Could not store output of {name}: {e:#}")),
             }
-            Ok((CommandExit::TimedOut, stdout, stderr)) => error(format!(
-                "Execution timed out after {timeout:?} and was killed: {command} for {ip}\n\
-                 stdout: {stdout}\nstderr: {stderr}"
-            )),
-            Err(e) => error(format!("Error executing {command} for {ip}: {e}")),
         }
+        format!("\nstdout: {stdout}\nstderr: {stderr}")
     }
 
     fn execute_with_timeout(
-        command: &str,
+        &self,
+        command: &Program,
         timeout: Duration,
-        ip: IpAddr,
+        env: &[(String, String)],
+        run_as: &RunAs,
     ) -> anyhow::Result<(CommandExit, String, String)> {
         let stdout_path = Self::temp_output_path("out")?;
         let stderr_path = Self::temp_output_path("err")?;
 
-        let result = Self::spawn_and_wait(command, timeout, ip, &stdout_path, &stderr_path);
+        let result = self.spawn_and_wait(command, timeout, env, run_as, &stdout_path, &stderr_path);
 
         // Whatever happened, collect the partial output and clean the temp files up.
         let stdout = fs::read_to_string(&stdout_path).unwrap_or_default();
@@ -118,9 +375,11 @@ impl Commander {
     }
 
     fn spawn_and_wait(
-        command: &str,
+        &self,
+        command: &Program,
         timeout: Duration,
-        ip: IpAddr,
+        env: &[(String, String)],
+        run_as: &RunAs,
         stdout_path: &Path,
         stderr_path: &Path,
     ) -> anyhow::Result<CommandExit> {
@@ -129,10 +388,30 @@ impl Commander {
         let stderr_file = fs::File::create(stderr_path)
             .with_context(|| format!("Could not create stderr capture {stderr_path:?}"))?;
 
-        let mut child = Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .env(format!("{ENV_PREFIX}IP"), ip.to_string())
+        let mut cmd = match command {
+            Program::Shell(shell_cmd) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(shell_cmd);
+                cmd
+            }
+            // Looked up in PATH, like the shell would; never empty, checked in get_hash_to_cmd.
+            Program::Argv(argv) => {
+                let Some((program, args)) = argv.split_first() else {
+                    bail!("Empty argv")
+                };
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                cmd
+            }
+        };
+        for var in NOTIFY_ENV_VARS {
+            cmd.env_remove(var);
+        }
+        apply_run_as(&mut cmd, run_as);
+        // Its own process group, so a timeout reaches pipelines and background jobs too.
+        cmd.process_group(0);
+        let mut child = cmd
+            .envs(env.iter().map(|(k, v)| (k, v)))
             .stdout(stdout_file)
             .stderr(stderr_file)
             .spawn()
@@ -146,17 +425,69 @@ impl Commander {
             match child.try_wait().with_context(|| format!("Could not poll {command}"))? {
                 Some(status) => return Ok(CommandExit::Completed(status)),
                 None if deadline.is_some_and(|d| Instant::now() >= d) => {
-                    // SIGKILL the `sh` process only (not its process group); then reap it so no
-                    // zombie is left behind.
-                    child.kill().with_context(|| format!("Could not kill {command}"))?;
-                    child.wait().with_context(|| format!("Could not reap {command}"))?;
+                    self.kill_group(&mut child, command)?;
                     return Ok(CommandExit::TimedOut);
                 }
+                // No watchdog ping here: this runs on a worker, and the accept loop keeps feeding
+                // the watchdog however long a command takes.
                 None => thread::sleep(POLL_INTERVAL),
             }
         }
     }
 
+    /// `SIGTERM` the process group of a timed-out `child`, give it `kill_grace` to exit, then
+    /// `SIGKILL` whatever is left and reap `child`. Processes that moved to a group or session of
+    /// their own are out of reach.
+    fn kill_group(&self, child: &mut Child, command: &Program) -> anyhow::Result<()> {
+        let pgid = Pid::from_raw(
+            i32::try_from(child.id()).with_context(|| format!("Invalid pid {}", child.id()))?,
+        );
+        signal_group(pgid, Signal::SIGTERM)?;
+        let grace_end = Instant::now().checked_add(self.kill_grace);
+        loop {
+            match group_members(pgid) {
+                Ok(members) if members.is_empty() => {
+                    child.wait().with_context(|| format!("Could not reap {command}"))?;
+                    return Ok(());
+                }
+                Ok(members) if grace_end.is_some_and(|end| Instant::now() >= end) => {
+                    error(format!(
+                        "{} process(es) of {command} still running {:?} after SIGTERM, sending \
+                         SIGKILL: {}",
+                        members.len(),
+                        self.kill_grace,
+                        members.join(", ")
+                    ));
+                    break;
+                }
+                Ok(_) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    error(format!("{e:#}, sending SIGKILL to {command} right away"));
+                    break;
+                }
+            }
+        }
+        signal_group(pgid, Signal::SIGKILL)?;
+        child.wait().with_context(|| format!("Could not reap {command}"))?;
+        // SIGKILL cannot be handled, but a process blocked in the kernel only dies once it returns.
+        let kill_end = Instant::now() + KILL_WAIT;
+        let survivors = loop {
+            let members = group_members(pgid)?;
+            if members.is_empty() || Instant::now() >= kill_end {
+                break members;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        if !survivors.is_empty() {
+            error(format!(
+                "{} process(es) of {command} survived SIGKILL: {}",
+                survivors.len(),
+                survivors.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
     /// A unique per-invocation path in the temp dir for capturing one output stream.
     fn temp_output_path(kind: &str) -> anyhow::Result<PathBuf> {
         let nanos = SystemTime::now()
@@ -165,38 +496,196 @@ impl Commander {
             .as_nanos();
         Ok(env::temp_dir().join(format!("ruroco-cmd-{}-{nanos}-{kind}", process::id())))
     }
+}
 
-    /// Returns `true` if the command may run for this IP. The IP reaches the executed command via
-    /// `$RUROCO_IP`, so only allow globally-routable unicast peers: reject loopback, private, and
-    /// other non-routable addresses a client must not be able to whitelist.
-    fn is_ip_allowed(ip: IpAddr) -> bool {
-        let allowed = ip_filter::is_routable(ip);
-        if !allowed {
-            error(format!("refusing to execute with non-routable IP: {ip}"));
+/// Switch user and group, working directory, static environment, umask and resource limits for a
+/// command, as configured for its entry. Setting a uid as root also drops the supplementary groups
+/// (std calls `setgroups(0)` before `setuid`).
+#[allow(unsafe_code)]
+fn apply_run_as(cmd: &mut Command, run_as: &RunAs) {
+    if let Some(cwd) = &run_as.cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd.envs(&run_as.env);
+    if let Some(gid) = run_as.gid {
+        cmd.gid(gid);
+    }
+    if let Some(uid) = run_as.uid {
+        cmd.uid(uid);
+    }
+    if let Some(mask) = run_as.umask {
+        let mode = Mode::from_bits_truncate(mask);
+        // SAFETY: the closure runs in the forked child before exec and only calls umask(2), which
+        // is async-signal-safe, allocates nothing and cannot fail.
+        unsafe {
+            cmd.pre_exec(move || {
+                umask(mode);
+                Ok(())
+            });
         }
-        allowed
+    }
+    apply_rlimits(cmd, run_as.rlimits);
+}
+
+/// Set both the soft and the hard limit. This runs after the switch to `run_as.uid`, so without
+/// `CAP_SYS_RESOURCE` a limit can only be lowered: one above the commander's own hard limit makes
+/// the spawn fail.
+#[allow(unsafe_code)]
+fn apply_rlimits(cmd: &mut Command, rlimits: Rlimits) {
+    let limits: Vec<(Resource, u64)> = [
+        (Resource::RLIMIT_CPU, rlimits.cpu_sec),
+        (Resource::RLIMIT_AS, rlimits.as_bytes),
+        (Resource::RLIMIT_NOFILE, rlimits.nofile),
+        (Resource::RLIMIT_NPROC, rlimits.nproc),
+        (Resource::RLIMIT_CORE, rlimits.core_bytes),
+    ]
+    .into_iter()
+    .filter_map(|(resource, limit)| limit.map(|limit| (resource, limit)))
+    .collect();
+    if limits.is_empty() {
+        return;
+    }
+    // SAFETY: the closure runs in the forked child before exec and only calls setrlimit(2), which
+    // is async-signal-safe, on the vector allocated before the fork; an error becomes an
+    // `io::Error` from the raw errno, which does not allocate either.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, limit) in &limits {
+                setrlimit(*resource, *limit, *limit).map_err(io::Error::from)?;
+            }
+            Ok(())
+        });
     }
 }
 
+/// The user and group a connection on the socket must come from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) struct Peer {
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+}
+
+/// Check the `SO_PEERCRED` of `stream`, the credentials its peer had when it connected, against
+/// `expected`. The socket mode alone is not enough: a wrong owner or a lax `socket_dir` would let
+/// anyone run commands.
+pub(super) fn check_peer(stream: &UnixStream, expected: Peer) -> anyhow::Result<()> {
+    let creds = getsockopt(stream, PeerCredentials)
+        .with_context(|| "Could not read the peer credentials of a connection")?;
+    if creds.uid() != expected.uid || creds.gid() != expected.gid {
+        bail!(
+            "Rejected connection from pid {} with uid {} and gid {}, expected uid {} and gid {}",
+            creds.pid(),
+            creds.uid(),
+            creds.gid(),
+            expected.uid,
+            expected.gid
+        );
+    }
+    Ok(())
+}
+
+/// A group that is already gone is fine: everything in it exited on its own.
+fn signal_group(pgid: Pid, signal: Signal) -> anyhow::Result<()> {
+    match killpg(pgid, signal) {
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(anyhow!("Could not send {signal} to process group {pgid}: {e}")),
+    }
+}
+
+/// The live (not zombie) processes in process group `pgid`, as `<pid> (<name>)`, from `/proc`.
+fn group_members(pgid: Pid) -> anyhow::Result<Vec<String>> {
+    let mut members = Vec::new();
+    for entry in fs::read_dir("/proc").with_context(|| "Could not list processes in /proc")? {
+        let Ok(entry) = entry else { continue };
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else {
+            continue;
+        };
+        // Processes come and go while this runs; one that is gone is not a member.
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // `<pid> (<comm>) <state> <ppid> <pgrp> ...`, where comm may itself contain `)` or spaces.
+        let (Some(open), Some(close)) = (stat.find('('), stat.rfind(')')) else {
+            continue;
+        };
+        let mut fields = stat[close + 1..].split_whitespace();
+        let (state, pgrp) = (fields.next(), fields.nth(1));
+        if state != Some("Z") && pgrp == Some(pgid.as_raw().to_string().as_str()) {
+            members.push(format!("{pid} ({})", &stat[open + 1..close]));
+        }
+    }
+    Ok(members)
+}
+
+/// The value of the argv placeholder `{name}`: the `RUROCO_*` variable of the same name, so `{ip}`
+/// is `$RUROCO_IP` and `{param_port}` is `$RUROCO_PARAM_PORT`.
+fn placeholder_value(env: &[(String, String)], name: &str) -> Option<String> {
+    let var = format!("{ENV_PREFIX}{}", name.to_ascii_uppercase());
+    env.iter().find(|(k, _)| *k == var).map(|(_, v)| v.clone())
+}
+
+/// The `RUROCO_*` variables describing the request that triggered `spec`, shared by the command, its
+/// hooks and `alert_cmd`. Parameters come last, as `RUROCO_PARAM_<NAME>`.
+pub(super) fn request_env(spec: &CommandSpec, data: &CommanderData) -> Vec<(String, String)> {
+    let ip_family = if data.ip.is_ipv4() { "ipv4" } else { "ipv6" };
+    let mut env = vec![
+        (format!("{ENV_PREFIX}IP"), data.ip.to_string()),
+        (format!("{ENV_PREFIX}IP_FAMILY"), ip_family.to_string()),
+        (format!("{ENV_PREFIX}DST_IP"), data.dst_ip.to_string()),
+        (format!("{ENV_PREFIX}KEY_ID"), key_id_hex(&data.key_id)),
+        (format!("{ENV_PREFIX}KEY_LABEL"), data.key_label.clone()),
+        (format!("{ENV_PREFIX}COMMAND_NAME"), spec.name.clone()),
+        (format!("{ENV_PREFIX}COUNTER"), data.counter.to_string()),
+        (format!("{ENV_PREFIX}RECEIVED_AT"), format_nanos(data.received_at)),
+    ];
+    // Validated in `run_cycle` before anything runs; an alert for a request with invalid
+    // parameters simply goes out without them.
+    let params = spec.resolve_params(&data.extensions).unwrap_or_default();
+    env.extend(
+        params.into_iter().map(|(name, value)| {
+            (format!("{ENV_PREFIX}PARAM_{}", name.to_ascii_uppercase()), value)
+        }),
+    );
+    env
+}
+
+/// Cut `output` to at most `HOOK_OUTPUT_LIMIT` bytes (on a char boundary) and drop NUL bytes, which
+/// an environment variable cannot carry.
+fn truncate_for_env(output: &str) -> String {
+    let mut end = output.len().min(HOOK_OUTPUT_LIMIT);
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    output[..end].replace('\0', "")
+}
+
 pub fn run_commander(commander: CliCommander) -> anyhow::Result<()> {
-    Commander::create_from_paths(&commander.config, &commander.commands)?.run()
+    match commander.command {
+        Some(CommanderCommand::Audit(AuditCommand::Verify { file })) => {
+            audit::run_verify(&commander.config, file)
+        }
+        None => Commander::create_from_paths(&commander.config, &commander.commands)?.run(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{run_commander, Commander};
+    use super::{
+        check_peer, placeholder_value, request_env, run_commander, truncate_for_env, CommandExit,
+        Commander, Peer, Requester, HOOK_OUTPUT_LIMIT,
+    };
+    use crate::commander::config::{Program, Rlimits, RunAs};
+    use crate::commander::tests::{command_spec, request, TEST_TIMEOUT};
     use crate::commander::{CliCommander, ConfigCommander, ConfigCommands};
-    use std::collections::HashMap;
+    use crate::common::ipc::CommanderData;
+    use std::collections::{BTreeMap, HashMap};
+    use std::os::unix::net::UnixStream;
     use std::path::PathBuf;
-    use std::time::Duration;
 
-    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
-
-    fn create_commander(config_dir: PathBuf, allow_non_routable_ips: bool) -> Commander {
+    fn create_commander(config_dir: PathBuf) -> Commander {
         Commander::create(
             ConfigCommander {
                 config_dir,
-                allow_non_routable_ips,
                 ..Default::default()
             },
             ConfigCommands::from_map(HashMap::new()),
@@ -210,7 +699,7 @@ mod tests {
         use std::os::unix::fs::PermissionsExt;
 
         let dir = tempfile::tempdir().unwrap();
-        let commander = create_commander(dir.path().to_path_buf(), false);
+        let commander = create_commander(dir.path().to_path_buf());
 
         // Reading the current umask requires setting one; restore it immediately after.
         let before = umask(Mode::from_bits_truncate(0o022));
@@ -232,12 +721,12 @@ mod tests {
     #[test]
     fn test_create_listener_refuses_second_instance() {
         let dir = tempfile::tempdir().unwrap();
-        let commander = create_commander(dir.path().to_path_buf(), false);
+        let commander = create_commander(dir.path().to_path_buf());
 
         // Held for the rest of the test so the socket stays bound.
         let _first = commander.create_listener().unwrap();
 
-        let second = create_commander(dir.path().to_path_buf(), false);
+        let second = create_commander(dir.path().to_path_buf());
         let err = second.create_listener().unwrap_err().to_string();
 
         assert!(err.contains("Commander already running"), "unexpected error: {err}");
@@ -250,33 +739,237 @@ mod tests {
         let commander = CliCommander {
             config: PathBuf::from("/nonexistent/ruroco_test_path.toml"),
             commands: PathBuf::from("/nonexistent/ruroco_test_commands.toml"),
+            command: None,
         };
         assert!(run_commander(commander).is_err());
     }
 
     #[test]
-    fn test_run_command_rejects_loopback_when_not_allowed() {
+    fn test_parse_audit_verify() {
+        use crate::commander::config::{AuditCommand, CommanderCommand};
+        use clap::Parser;
+
+        let cli = CliCommander::try_parse_from(["ruroco-commander", "audit", "verify", "a.log"]);
+        assert_eq!(
+            cli.unwrap().command,
+            Some(CommanderCommand::Audit(AuditCommand::Verify {
+                file: Some(PathBuf::from("a.log"))
+            }))
+        );
+        let cli = CliCommander::try_parse_from(["ruroco-commander"]).unwrap();
+        assert_eq!(cli.command, None);
+    }
+
+    #[test]
+    fn test_request_env_ip_family() {
+        let data = CommanderData {
+            dst_ip: "2001:db8::1".parse().unwrap(),
+            received_at: 0,
+            ..request("2001:db8::7")
+        };
+        let env = request_env(&command_spec("true", TEST_TIMEOUT), &data);
+        let get = |name: &str| env.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("RUROCO_IP"), Some("2001:db8::7"));
+        assert_eq!(get("RUROCO_IP_FAMILY"), Some("ipv6"));
+        assert_eq!(get("RUROCO_DST_IP"), Some("2001:db8::1"));
+        assert_eq!(get("RUROCO_RECEIVED_AT"), Some("1970-01-01T00:00:00.000Z"));
+        assert!(env.iter().all(|(k, _)| !k.starts_with("RUROCO_PARAM_")));
+    }
+
+    #[cfg(feature = "with-client")]
+    #[test]
+    fn test_request_env_params() {
+        use crate::commander::config::ParamSpec;
+        use crate::common::protocol::params::encode_params;
+
+        let mut spec = command_spec("true", TEST_TIMEOUT);
+        spec.params.insert(
+            "max_hours".to_string(),
+            ParamSpec::Int {
+                min: 1,
+                max: 8,
+                default: None,
+            },
+        );
+        let data = CommanderData {
+            extensions: encode_params(&[("max_hours".to_string(), "4".to_string())]).unwrap(),
+            ..request("1.2.3.4")
+        };
+        let env = request_env(&spec, &data);
+        assert_eq!(env.last().unwrap(), &("RUROCO_PARAM_MAX_HOURS".to_string(), "4".to_string()));
+    }
+
+    #[test]
+    fn test_run_as_is_applied() {
         let dir = tempfile::tempdir().unwrap();
-        let output_file = dir.path().join("rejected.txt");
-        let output_path = output_file.to_str().unwrap();
-        create_commander(dir.path().to_path_buf(), false).run_command(
-            &format!("touch {output_path}"),
-            TEST_TIMEOUT,
-            "127.0.0.1".parse().unwrap(),
+        let commander = create_commander(dir.path().to_path_buf());
+        let mut run_as = RunAs {
+            cwd: Some(PathBuf::from("/")),
+            env: BTreeMap::from([("GREETING".to_string(), "hello".to_string())]),
+            umask: Some(0o027),
+            ..Default::default()
+        };
+        let running_as_root = nix::unistd::Uid::effective().is_root();
+        if running_as_root {
+            run_as.uid = Some(65534);
+            run_as.gid = Some(65534);
+        }
+        let (exit, stdout, _) = commander
+            .execute_with_timeout(
+                &Program::Shell("pwd; echo $GREETING; umask; id -u; id -G".to_string()),
+                TEST_TIMEOUT,
+                &[],
+                &run_as,
+            )
+            .unwrap();
+        assert!(matches!(exit, CommandExit::Completed(status) if status.success()), "{stdout}");
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[..3], ["/", "hello", "0027"]);
+        if running_as_root {
+            // Switching the uid also drops root's supplementary groups.
+            assert_eq!(lines[3..], ["65534", "65534"]);
+        }
+    }
+
+    #[test]
+    fn test_check_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        // Without socket_user/socket_group, the commander's own user and group.
+        let expected = create_commander(dir.path().to_path_buf()).expected_peer().unwrap();
+        assert_eq!(
+            expected,
+            Peer {
+                uid: nix::unistd::Uid::effective().as_raw(),
+                gid: nix::unistd::Gid::effective().as_raw(),
+            }
+        );
+
+        let (stream, _other) = UnixStream::pair().unwrap();
+        assert!(check_peer(&stream, expected).is_ok());
+        for other in [
+            Peer {
+                uid: expected.uid + 1,
+                ..expected
+            },
+            Peer {
+                gid: expected.gid + 1,
+                ..expected
+            },
+        ] {
+            let err = check_peer(&stream, other).unwrap_err().to_string();
+            let pid = std::process::id();
+            assert!(err.starts_with(&format!("Rejected connection from pid {pid} ")), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_rlimits_are_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let commander = create_commander(dir.path().to_path_buf());
+        let run_as = RunAs {
+            rlimits: Rlimits {
+                cpu_sec: Some(30),
+                nofile: Some(64),
+                core_bytes: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (exit, stdout, stderr) = commander
+            .execute_with_timeout(
+                &Program::Shell("ulimit -t; ulimit -n; ulimit -Hn; ulimit -c".to_string()),
+                TEST_TIMEOUT,
+                &[],
+                &run_as,
+            )
+            .unwrap();
+        assert!(matches!(exit, CommandExit::Completed(status) if status.success()), "{stderr}");
+        assert_eq!(stdout, "30\n64\n64\n0\n");
+    }
+
+    #[test]
+    fn test_argv_runs_without_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let commander = create_commander(dir.path().to_path_buf());
+        let env = [("RUROCO_IP".to_string(), "1.2.3.4; echo injected".to_string())];
+        let argv = ["printf", "%s\\n", "{ip}", "$RUROCO_IP", "{{ip}}"].map(String::from);
+        let program =
+            Program::Argv(argv.to_vec()).expand(|name| placeholder_value(&env, name)).unwrap();
+        let (exit, stdout, _) = commander
+            .execute_with_timeout(&program, TEST_TIMEOUT, &env, &RunAs::default())
+            .unwrap();
+        assert!(matches!(exit, CommandExit::Completed(status) if status.success()));
+        assert_eq!(stdout, "1.2.3.4; echo injected\n$RUROCO_IP\n{ip}\n");
+    }
+
+    #[test]
+    fn test_output_goes_to_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("output");
+        let commander = Commander::create(
+            ConfigCommander {
+                config_dir: dir.path().to_path_buf(),
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+            ConfigCommands::from_map(HashMap::new()),
+        )
+        .unwrap();
+        let program = Program::Shell("echo out; echo err >&2; exit 3".to_string());
+        let requester = Requester::from(&request("1.2.3.4"));
+        let (_, stdout, _) = commander
+            .execute_and_log("open", &program, TEST_TIMEOUT, requester, &[], &RunAs::default())
+            .unwrap();
+        // Hooks still get the output.
+        assert_eq!(stdout, "out\n");
+
+        let files: Vec<_> = std::fs::read_dir(&output_dir).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(files.len(), 1);
+        let file_name = files[0].file_name().into_string().unwrap();
+        assert!(file_name.starts_with("open.") && file_name.ends_with(".log"), "{file_name}");
+        assert_eq!(
+            std::fs::read_to_string(files[0].path()).unwrap(),
+            "command: echo out; echo err >&2; exit 3\nip: 1.2.3.4\nresult: exit status: 3\n\
+             --- stdout ---\nout\n--- stderr ---\nerr\n"
         );
-        assert!(!output_file.exists(), "command must not run for a rejected IP");
     }
 
     #[test]
-    fn test_run_command_accepts_loopback_when_allowed() {
+    fn test_execute_and_log_audits_requester() {
         let dir = tempfile::tempdir().unwrap();
-        let output_file = dir.path().join("accepted.txt");
-        let output_path = output_file.to_str().unwrap();
-        create_commander(dir.path().to_path_buf(), true).run_command(
-            &format!("touch {output_path}"),
+        let audit_log = dir.path().join("audit.log");
+        let commander = Commander::create(
+            ConfigCommander {
+                config_dir: dir.path().to_path_buf(),
+                audit_log: Some(audit_log.clone()),
+                ..Default::default()
+            },
+            ConfigCommands::from_map(HashMap::new()),
+        )
+        .unwrap();
+        // No RUROCO_* variables at all: the key id must come from the request, not the env.
+        let requester = Requester::from(&request("1.2.3.4"));
+        let program = Program::Shell("true".to_string());
+        commander.execute_and_log(
+            "open",
+            &program,
             TEST_TIMEOUT,
-            "127.0.0.1".parse().unwrap(),
+            requester,
+            &[],
+            &RunAs::default(),
         );
-        assert!(output_file.exists(), "command must run when non-routable IPs are allowed");
+
+        let record = std::fs::read_to_string(&audit_log).unwrap();
+        let fields: Vec<&str> = record.trim_end().split('\t').collect();
+        assert_eq!(fields[2..6], ["open", "1A2B3C4D5E6F7081", "1.2.3.4", "exit status: 0"]);
+    }
+
+    #[test]
+    fn test_truncate_for_env() {
+        assert_eq!(truncate_for_env("hello\0world"), "helloworld");
+        assert_eq!(truncate_for_env(&"a".repeat(HOOK_OUTPUT_LIMIT + 10)).len(), HOOK_OUTPUT_LIMIT);
+        // A multi-byte char straddling the limit is dropped rather than split.
+        let output = format!("{}ä", "a".repeat(HOOK_OUTPUT_LIMIT - 1));
+        assert_eq!(truncate_for_env(&output), "a".repeat(HOOK_OUTPUT_LIMIT - 1));
     }
 }
@@ -1,8 +1,123 @@
 //! Decides whether a client-supplied IP may be exposed to a command as `$RUROCO_IP`. The IP
-//! reaches the executed shell command, so only globally-routable unicast peers are allowed:
-//! reject loopback, private, and other non-routable ranges a client must not be able to whitelist.
+//! reaches the executed shell command, so by default only globally-routable unicast peers are
+//! allowed: reject loopback, private, and other non-routable ranges a client must not be able to
+//! whitelist.
+//!
+//! `allow_cidrs` and `deny_cidrs` (`config.toml`, or per command in `commands.toml`) change that
+//! for the networks they list. The most specific entry containing the IP decides, a `deny_cidrs`
+//! entry winning over an equally specific `allow_cidrs` one; an IP in neither list falls back to
+//! the routable check.
 
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network, `10.0.0.0/8`, or a single address without a prefix length.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.network.is_ipv4() && network_of(ip, self.prefix) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Cidr> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: IpAddr = address.parse().with_context(|| format!("Invalid CIDR {s}"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow!("Invalid CIDR {s}: prefix length is not 0 to {max}"))?,
+        };
+        // Most likely a typo for the network, or for a single address: say which it matches.
+        let network = network_of(ip, prefix);
+        if network != ip {
+            bail!("Invalid CIDR {s}: host bits are set, the network is {network}/{prefix}");
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Cidr> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// `ip` with all but the first `prefix` bits cleared.
+fn network_of(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// The `allow_cidrs`/`deny_cidrs` of a command. `None` until `Commander::create` fills in the
+/// global lists for the ones the entry does not set.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct IpFilter {
+    pub(crate) allow: Option<Vec<Cidr>>,
+    pub(crate) deny: Option<Vec<Cidr>>,
+}
+
+impl IpFilter {
+    /// Each list set here, or else the one given.
+    pub(crate) fn or(self, allow: &[Cidr], deny: &[Cidr]) -> IpFilter {
+        IpFilter {
+            allow: self.allow.or_else(|| Some(allow.to_vec())),
+            deny: self.deny.or_else(|| Some(deny.to_vec())),
+        }
+    }
+
+    /// Whether a command may run for `ip`, and why not. An IPv4-mapped IPv6 address is checked
+    /// as the IPv4 address it stands for.
+    pub(crate) fn check(&self, ip: IpAddr) -> anyhow::Result<()> {
+        let ip = ip.to_canonical();
+        match (most_specific(&self.allow, ip), most_specific(&self.deny, ip)) {
+            (Some(allow), Some(deny)) if allow.prefix > deny.prefix => Ok(()),
+            (_, Some(deny)) => bail!("{ip} is in deny_cidrs entry {deny}"),
+            (Some(_), None) => Ok(()),
+            (None, None) if is_routable(ip) => Ok(()),
+            (None, None) => bail!("{ip} is not routable and in no allow_cidrs entry"),
+        }
+    }
+}
+
+/// The entry of `list` with the longest prefix that contains `ip`.
+fn most_specific(list: &Option<Vec<Cidr>>, ip: IpAddr) -> Option<&Cidr> {
+    list.iter().flatten().filter(|cidr| cidr.contains(ip)).max_by_key(|cidr| cidr.prefix)
+}
 
 pub(crate) fn is_routable(ip: IpAddr) -> bool {
     let reject = ip.is_unspecified()
@@ -76,9 +191,73 @@ fn is_ipv4_reserved(v4: Ipv4Addr) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::is_routable;
+    use super::{is_routable, Cidr, IpFilter};
     use std::net::IpAddr;
 
+    fn cidrs(list: &[&str]) -> Option<Vec<Cidr>> {
+        Some(list.iter().map(|cidr| cidr.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        for (cidr, expected) in [
+            ("10.0.0.0/8", "10.0.0.0/8"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+            ("192.0.2.7", "192.0.2.7/32"),
+            ("2001:db8::/32", "2001:db8::/32"),
+            ("::1", "::1/128"),
+        ] {
+            assert_eq!(cidr.parse::<Cidr>().unwrap().to_string(), expected);
+        }
+        for (cidr, expected) in [
+            ("10.0.0.1/8", "host bits are set, the network is 10.0.0.0/8"),
+            ("10.0.0.0/33", "prefix length is not 0 to 32"),
+            ("::/129", "prefix length is not 0 to 128"),
+            ("10.0.0/8", "Invalid CIDR 10.0.0/8"),
+        ] {
+            let err = cidr.parse::<Cidr>().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{cidr}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = IpFilter {
+            allow: cidrs(&["10.0.0.0/8", "fd00::/8"]),
+            deny: cidrs(&["10.66.0.0/16", "1.2.3.0/24"]),
+        };
+        let check = |ip: &str| filter.check(ip.parse().unwrap()).map_err(|e| e.to_string());
+        assert!(check("10.1.2.3").is_ok());
+        assert!(check("::ffff:10.1.2.3").is_ok());
+        assert!(check("fd12::1").is_ok());
+        assert!(check("8.8.8.8").is_ok());
+        assert_eq!(
+            check("10.66.0.1").unwrap_err(),
+            "10.66.0.1 is in deny_cidrs entry 10.66.0.0/16"
+        );
+        assert_eq!(check("1.2.3.4").unwrap_err(), "1.2.3.4 is in deny_cidrs entry 1.2.3.0/24");
+        assert_eq!(
+            check("127.0.0.1").unwrap_err(),
+            "127.0.0.1 is not routable and in no allow_cidrs entry"
+        );
+
+        // Only the VPN: everything else denied, the more specific allow entry wins.
+        let filter = IpFilter {
+            allow: cidrs(&["10.8.0.0/24"]),
+            deny: cidrs(&["0.0.0.0/0", "::/0"]),
+        };
+        assert!(filter.check("10.8.0.5".parse().unwrap()).is_ok());
+        assert!(filter.check("8.8.8.8".parse().unwrap()).is_err());
+        assert!(filter.check("2606:4700:4700::1111".parse().unwrap()).is_err());
+
+        // Equally specific: deny wins.
+        let filter = IpFilter {
+            allow: cidrs(&["10.0.0.0/8"]),
+            deny: cidrs(&["10.0.0.0/8"]),
+        };
+        assert!(filter.check("10.0.0.1".parse().unwrap()).is_err());
+    }
+
     #[test]
     fn test_is_routable_rejects_non_routable() {
         // Every category the guard rejects: unspecified, loopback, multicast, and the v4/v6
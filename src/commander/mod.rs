@@ -1,35 +1,72 @@
-//! The privileged executor (runs as root). Owns the Unix socket, reads a 24-byte `CommanderData`
-//! off it, looks the `cmd_hash` up in its `cmds` map (built from `ConfigCommands`), and runs the
-//! configured shell command. Never touches crypto, keys, or the network: it trusts the Unix socket
-//! (see the threat-model discussion in `.todo/03`) and links neither OpenSSL nor the decrypt path.
+//! The privileged executor (runs as root). Owns the Unix socket, reads a 208-byte `CommanderData`
+//! off it, looks the `cmd_hash` up in its `cmds` map (built from `ConfigCommands`), and has a worker
+//! (see `pool`) run the configured shell command (plus `alert_cmd` for `canary`/`duress` entries).
+//! Never touches crypto, keys, or the network: it trusts the Unix socket (see the threat-model
+//! discussion in `.todo/03`) and links neither OpenSSL nor the decrypt path.
 
+mod audit;
 mod config;
 mod exec;
 mod ip_filter;
+mod output;
+mod pool;
 #[cfg(test)]
 mod tests;
+mod throttle;
+mod undo;
 
 pub use config::{CliCommander, ConfigCommander, ConfigCommands};
 pub use exec::run_commander;
+pub use ip_filter::Cidr;
 
-use crate::commander::config::CommandSpec;
+use crate::commander::audit::AuditLog;
+use crate::commander::config::{CommandKind, CommandSpec, Concurrency, Rlimits};
+use crate::commander::exec::{check_peer, Peer};
+use crate::commander::output::OutputLog;
+use crate::commander::pool::WorkerPool;
+use crate::commander::throttle::Throttles;
+use crate::commander::undo::UndoSchedule;
+use crate::common::events::{EventKind, EventPublisher, EventSource};
 use crate::common::info;
 use crate::common::ipc::{get_commander_unix_socket_path, CommanderData, CMDR_DATA_SIZE};
 use crate::common::logging::error;
-use anyhow::{anyhow, Context};
+use crate::common::notify::Notifier;
+use crate::common::signal::{install_signal_handlers, shutdown_requested};
+use anyhow::{anyhow, bail, Context};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use std::collections::HashMap;
 use std::io::Read;
-use std::os::unix::net::UnixStream;
+use std::os::fd::AsFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
+use std::thread;
 use std::time::Duration;
 
+const ACCEPT_POLL_TIMEOUT_MS: u16 = 1000;
+
 #[derive(Debug, PartialEq)]
 pub struct Commander {
     pub(super) socket_path: PathBuf,
     pub(super) cmds: HashMap<u64, CommandSpec>,
     pub(super) socket_user: String,
     pub(super) socket_group: String,
-    pub(super) allow_non_routable_ips: bool,
+    pub(super) alert_cmd: Option<String>,
+    pub(super) events: EventPublisher,
+    pub(super) notifier: Notifier,
+    pub(super) max_concurrent: usize,
+    /// `kill_grace_sec`, see `Commander::kill_group`.
+    pub(super) kill_grace: Duration,
+    /// Pending `undo_cmd`s, see `undo`.
+    pub(super) undo: UndoSchedule,
+    /// Where command output goes instead of the log, if `output_dir` is set.
+    pub(super) output: Option<OutputLog>,
+    /// Where every run is recorded, if `audit_log` is set.
+    pub(super) audit: Option<AuditLog>,
+    /// Recent runs, for `cooldown_sec` and `max_per_hour`.
+    pub(super) throttles: Throttles,
+    /// The global `rlimits`, for hooks and alerts; commands have them merged into their `run_as`.
+    pub(super) rlimits: Rlimits,
 }
 
 impl Commander {
@@ -43,23 +80,98 @@ impl Commander {
     }
 
     pub fn create(config: ConfigCommander, commands: ConfigCommands) -> anyhow::Result<Commander> {
+        let socket_dir = config.socket_dir.as_ref().unwrap_or(&config.config_dir);
+        let state_dir = config.state_dir.as_ref().unwrap_or(&config.config_dir);
+        let output = OutputLog::create(&config)?;
+        let audit = config.audit_log.as_deref().map(AuditLog::open).transpose()?;
+        let mut cmds = commands.get_hash_to_cmd()?;
+        for spec in cmds.values_mut() {
+            spec.run_as.rlimits = spec.run_as.rlimits.or(config.rlimits);
+            spec.ip_filter =
+                std::mem::take(&mut spec.ip_filter).or(&config.allow_cidrs, &config.deny_cidrs);
+        }
         Ok(Commander {
-            cmds: commands.get_hash_to_cmd()?,
-            socket_path: get_commander_unix_socket_path(
-                config.socket_dir.as_ref().unwrap_or(&config.config_dir),
-            ),
+            cmds,
+            socket_path: get_commander_unix_socket_path(socket_dir),
+            events: EventPublisher::create(
+                config.publish_events,
+                socket_dir,
+                EventSource::Commander,
+            )?,
             socket_user: config.socket_user,
             socket_group: config.socket_group,
-            allow_non_routable_ips: config.allow_non_routable_ips,
+            alert_cmd: commands.alert_cmd,
+            notifier: Notifier::from_env(),
+            max_concurrent: config.max_concurrent,
+            kill_grace: Duration::from_secs(config.kill_grace_sec),
+            undo: UndoSchedule::load(state_dir)?,
+            output,
+            audit,
+            throttles: Throttles::default(),
+            rlimits: config.rlimits,
         })
     }
 
+    /// Serve until `SIGTERM`/`SIGINT` with `max_concurrent` workers. On shutdown, requests that
+    /// have not started yet are dropped and running ones are waited for.
     pub fn run(&self) -> anyhow::Result<()> {
         let (_instance_lock, listener) = self.create_listener()?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    if let Err(e) = self.run_cycle(&mut stream) {
+        let peer = self.expected_peer()?;
+        install_signal_handlers();
+        self.notifier.ready(&format!("Listening, {} command(s) configured", self.cmds.len()));
+        let pending = self.undo.len();
+        if pending > 0 {
+            info(format!("Loaded {pending} pending undo(s)"));
+        }
+        let pool = WorkerPool::default();
+        thread::scope(|scope| {
+            for _ in 0..self.max_concurrent {
+                scope.spawn(|| pool.work());
+            }
+            let result = self.serve(&listener, peer, &pool);
+            let dropped = pool.shutdown();
+            if dropped > 0 {
+                error(format!("Dropped {dropped} request(s) that had not started yet"));
+            }
+            result
+        })
+    }
+
+    /// The accept loop: reads requests from `peer` and hands them to `pool`, never runs a command
+    /// itself.
+    fn serve<'a>(
+        &'a self,
+        listener: &UnixListener,
+        peer: Peer,
+        pool: &WorkerPool<'a>,
+    ) -> anyhow::Result<()> {
+        loop {
+            self.notifier.watchdog();
+            if shutdown_requested() {
+                info("Shutdown requested, stopping commander loop");
+                self.notifier.stopping();
+                break;
+            }
+            // Checked at least once a second thanks to the poll timeout below; undos that came due
+            // while the commander was down are submitted on the first iteration.
+            if let Err(e) = self.submit_due_undos(pool) {
+                error(e)
+            }
+            // Wait at most a second for a connection, so the watchdog keeps being fed and a
+            // shutdown request is noticed while the server is idle.
+            let mut fds = [PollFd::new(listener.as_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, PollTimeout::from(ACCEPT_POLL_TIMEOUT_MS)) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => {}
+                Err(e) => bail!("Could not poll {:?}: {e}", &self.socket_path),
+            }
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    if let Err(e) = check_peer(&stream, peer) {
+                        error(format!("{e:#} on {:?}", &self.socket_path));
+                        continue;
+                    }
+                    if let Err(e) = self.run_cycle(&mut stream, pool) {
                         error(e)
                     }
                 }
@@ -70,7 +182,11 @@ impl Commander {
         Ok(())
     }
 
-    fn run_cycle(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+    fn run_cycle<'a>(
+        &'a self,
+        stream: &mut UnixStream,
+        pool: &WorkerPool<'a>,
+    ) -> anyhow::Result<()> {
         stream
             .set_read_timeout(Some(Duration::from_secs(1)))
             .with_context(|| format!("Could not set read timeout for {:?}", &self.socket_path))?;
@@ -81,9 +197,85 @@ impl Commander {
         let spec =
             self.cmds.get(cmd_hash).ok_or_else(|| anyhow!("Unknown command name: {cmd_hash}"))?;
 
-        info(format!("Running command ({cmd_hash}) {}", spec.cmd));
-        self.run_command(&spec.cmd, spec.timeout, cmdr_data.ip);
-        Ok(())
+        match spec.kind {
+            CommandKind::Normal => {}
+            // A canary has no cmd of its own: the alert is all that happens.
+            CommandKind::Canary => return self.submit_alert(spec, &cmdr_data, pool),
+            // Submitted first, so a hung or slow duress command cannot delay the notification. A
+            // failed alert must not change what the coerced user observes, so the command runs
+            // anyway.
+            CommandKind::Duress => {
+                if let Err(e) = self.submit_alert(spec, &cmdr_data, pool) {
+                    error(e)
+                }
+            }
+        }
+
+        // Checked only now: a duress alert must go out whatever parameters came with the knock.
+        let params = spec
+            .resolve_params(&cmdr_data.extensions)
+            .map_err(|e| anyhow!("Rejected request for command {}: {e}", spec.name))?;
+        pool.submit(&spec.name, spec.concurrency, move || {
+            self.handle_request(spec, &cmdr_data, params)
+        })
+        .map_err(|e| anyhow!("Dropped request for command {}: {e}", spec.name))
+    }
+
+    fn submit_alert<'a>(
+        &'a self,
+        spec: &'a CommandSpec,
+        data: &CommanderData,
+        pool: &WorkerPool<'a>,
+    ) -> anyhow::Result<()> {
+        let data = data.clone();
+        pool.submit(&spec.name, Concurrency::Parallel, move || {
+            if let Err(e) = self.run_alert(spec, &data) {
+                error(e)
+            }
+        })
+        .map_err(|e| {
+            anyhow!("Could not start alert for {} command {}: {e}", spec.kind.as_str(), spec.name)
+        })
+    }
+
+    /// Runs on a worker. The pending undo is looked up here rather than in `run_cycle`: a queued
+    /// repeat knock only gets here once the earlier request is done and has scheduled its undo.
+    fn handle_request(
+        &self,
+        spec: &CommandSpec,
+        data: &CommanderData,
+        params: Vec<(String, String)>,
+    ) {
+        // First: a client the IP filter refuses must neither extend an undo nor use up throttle
+        // slots that allowed clients need.
+        if let Err(e) = spec.ip_filter.check(data.ip) {
+            return error(format!("Refusing to execute {} for {}: {e}", spec.name, data.ip));
+        }
+        if let Some(undo) = &spec.undo {
+            match self.extend_undo(spec, undo, data, &params) {
+                Ok(false) => {}
+                Ok(true) => return,
+                // Extended, but not persisted.
+                Err(e) => return error(e),
+            }
+        }
+        // After the undo check: a repeat knock that only extends the TTL runs nothing.
+        if let Err(e) = self.throttles.admit(spec, data) {
+            error(format!("Throttled command {} for {}: {e}", spec.name, data.ip));
+            return self.events.publish(EventKind::CommandThrottled {
+                name: spec.name.clone(),
+                ip: data.ip.to_string(),
+                reason: e.to_string(),
+            });
+        }
+        info(format!("Running command ({}) {}", data.cmd_hash, spec.program));
+        if self.run_command(spec, data) {
+            if let Some(undo) = &spec.undo {
+                if let Err(e) = self.schedule_undo(spec, undo, data, params) {
+                    error(e)
+                }
+            }
+        }
     }
 
     fn read(stream: &mut UnixStream) -> anyhow::Result<[u8; CMDR_DATA_SIZE]> {
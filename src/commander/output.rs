@@ -0,0 +1,251 @@
+//! Persistent command output. With `output_dir` set, the stdout and stderr of every run (command,
+//! hook, alert or undo) are kept in `<output_dir>/<name>.<timestamp>.log` and the journal line only
+//! names that file, instead of carrying the whole output.
+//!
+//! Each stream is cut at `output_max_bytes`, with a marker saying how much was dropped. After every
+//! write the files of the same name beyond the newest `output_keep` are deleted, and with
+//! `output_max_age_sec` so is every log in the directory older than that.
+
+use crate::commander::config::ConfigCommander;
+use crate::common::logging::error;
+use anyhow::{bail, Context};
+use chrono::{NaiveDateTime, Utc};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Sorts the same as the time it stands for, and has a fixed length, see `log_name`.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.9fZ";
+const TIMESTAMP_LEN: usize = "20261018T203008.138000000Z".len();
+const EXTENSION: &str = ".log";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct OutputLog {
+    dir: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    max_age: Option<Duration>,
+}
+
+/// What a run left behind, as stored in the log file.
+pub(crate) struct RunOutput<'a> {
+    pub(crate) command: &'a str,
+    pub(crate) ip: IpAddr,
+    pub(crate) result: &'a str,
+    pub(crate) stdout: &'a str,
+    pub(crate) stderr: &'a str,
+}
+
+impl OutputLog {
+    /// `None` unless `output_dir` is set. Creates the directory (`0700`, output may hold secrets)
+    /// if it does not exist yet.
+    pub(crate) fn create(config: &ConfigCommander) -> anyhow::Result<Option<OutputLog>> {
+        let Some(dir) = &config.output_dir else {
+            return Ok(None);
+        };
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Could not create output_dir {dir:?}"))?;
+        Ok(Some(OutputLog {
+            dir: dir.clone(),
+            max_bytes: config.output_max_bytes,
+            keep: config.output_keep,
+            max_age: config.output_max_age_sec.map(Duration::from_secs),
+        }))
+    }
+
+    /// Write the output of one run of `name` to a new file, then rotate. Returns the file's path.
+    pub(crate) fn store(&self, name: &str, output: &RunOutput) -> anyhow::Result<PathBuf> {
+        let name = file_name_safe(name);
+        let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
+        let path = self.dir.join(format!("{name}.{timestamp}{EXTENSION}"));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Could not create output file {path:?}"))?;
+        let mut content =
+            format!("command: {}\nip: {}\nresult: {}\n", output.command, output.ip, output.result)
+                .into_bytes();
+        for (stream, text) in [("stdout", output.stdout), ("stderr", output.stderr)] {
+            content.extend(format!("--- {stream} ---\n").into_bytes());
+            content.extend(self.truncated(text.as_bytes()));
+        }
+        file.write_all(&content).with_context(|| format!("Could not write {path:?}"))?;
+        self.rotate(&name);
+        Ok(path)
+    }
+
+    /// At most `max_bytes` of `output`, plus a marker line if anything was cut off.
+    fn truncated(&self, output: &[u8]) -> Vec<u8> {
+        let max = usize::try_from(self.max_bytes).unwrap_or(usize::MAX);
+        if output.len() <= max {
+            let mut kept = output.to_vec();
+            if !kept.is_empty() && !kept.ends_with(b"\n") {
+                kept.push(b'\n');
+            }
+            return kept;
+        }
+        let mut kept = output[..max].to_vec();
+        kept.extend(format!("\n[truncated {} bytes]\n", output.len() - max).into_bytes());
+        kept
+    }
+
+    /// Errors are logged, not returned: the output of the run at hand was stored already.
+    fn rotate(&self, name: &str) {
+        if let Err(e) = self.delete_old(name) {
+            error(format!("Could not rotate output files in {:?}: {e:#}", self.dir))
+        }
+    }
+
+    fn delete_old(&self, name: &str) -> anyhow::Result<()> {
+        let mut logs = Vec::new();
+        let cutoff = self.max_age.and_then(|age| SystemTime::now().checked_sub(age));
+        for entry in fs::read_dir(&self.dir).with_context(|| "Could not list")? {
+            let entry = entry.with_context(|| "Could not read entry")?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(cutoff) = cutoff {
+                if is_log(&file_name) && entry.metadata()?.modified()? < cutoff {
+                    remove(&entry.path())?;
+                    continue;
+                }
+            }
+            if is_log_of(&file_name, name) {
+                logs.push(entry.path());
+            }
+        }
+        // Newest first: the timestamps sort like the times they stand for.
+        logs.sort_unstable_by(|a, b| b.cmp(a));
+        logs.iter().skip(self.keep).try_for_each(|path| remove(path))
+    }
+}
+
+fn remove(path: &Path) -> anyhow::Result<()> {
+    fs::remove_file(path).with_context(|| format!("Could not delete {path:?}"))
+}
+
+/// Command names come from `commands.toml` and may hold anything; keep the file name to a portable
+/// set of characters, and never `.`-prefixed.
+fn file_name_safe(name: &str) -> String {
+    let safe: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_-.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if safe.starts_with('.') || safe.is_empty() {
+        format!("_{safe}")
+    } else {
+        safe
+    }
+}
+
+/// `<anything>.<timestamp>.log`, as written by `store`.
+fn is_log(file_name: &str) -> bool {
+    log_name(file_name).is_some()
+}
+
+/// `<name>.<timestamp>.log` exactly, so rotating `open` leaves `open.undo` alone.
+fn is_log_of(file_name: &str, name: &str) -> bool {
+    log_name(file_name) == Some(name)
+}
+
+/// The `<name>` of a file written by `store`, `None` for any other file.
+fn log_name(file_name: &str) -> Option<&str> {
+    let stem = file_name.strip_suffix(EXTENSION)?;
+    let split = stem.len().checked_sub(TIMESTAMP_LEN)?;
+    let (name, timestamp) = (stem.get(..split)?.strip_suffix('.')?, stem.get(split..)?);
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok().map(|_| name)
+}
+
+/// `output_*` settings that cannot work, rejected when loading `config.toml`.
+pub(crate) fn check_config(config: &ConfigCommander) -> anyhow::Result<()> {
+    if config.output_keep == 0 {
+        bail!("output_keep must be at least 1");
+    }
+    if config.output_max_age_sec == Some(0) {
+        bail!("output_max_age_sec must be greater than 0");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name_safe, is_log_of, OutputLog, RunOutput};
+    use crate::commander::config::ConfigCommander;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn output_log(dir: &std::path::Path, max_bytes: u64, keep: usize) -> OutputLog {
+        let config = ConfigCommander {
+            output_dir: Some(dir.join("output")),
+            output_max_bytes: max_bytes,
+            output_keep: keep,
+            ..Default::default()
+        };
+        OutputLog::create(&config).unwrap().unwrap()
+    }
+
+    fn run_output<'a>(stdout: &'a str, stderr: &'a str) -> RunOutput<'a> {
+        RunOutput {
+            command: "echo hi",
+            ip: "1.2.3.4".parse().unwrap(),
+            result: "exit code 0",
+            stdout,
+            stderr,
+        }
+    }
+
+    #[test]
+    fn test_store_truncates_each_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = output_log(dir.path(), 5, 10);
+        let path = log.store("open", &run_output("0123456789", "err")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "command: echo hi\nip: 1.2.3.4\nresult: exit code 0\n--- stdout ---\n01234\n\
+             [truncated 5 bytes]\n--- stderr ---\nerr\n"
+        );
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            fs::metadata(path.parent().unwrap()).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+    }
+
+    #[test]
+    fn test_rotation_keeps_newest_per_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = output_log(dir.path(), 100, 2);
+        let undo = log.store("open.undo", &run_output("undo", "")).unwrap();
+        let paths: Vec<_> =
+            (0..4).map(|i| log.store("open", &run_output(&i.to_string(), "")).unwrap()).collect();
+        let mut left: Vec<_> =
+            fs::read_dir(dir.path().join("output")).unwrap().map(|e| e.unwrap().path()).collect();
+        left.sort();
+        let mut expected = vec![paths[2].clone(), paths[3].clone(), undo];
+        expected.sort();
+        assert_eq!(left, expected);
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(file_name_safe("open_port"), "open_port");
+        assert_eq!(file_name_safe("../etc/x y"), "_.._etc_x_y");
+        assert_eq!(file_name_safe(""), "_");
+        assert!(is_log_of("open.20261018T203008.138000000Z.log", "open"));
+        assert!(!is_log_of("open.undo.20261018T203008.138000000Z.log", "open"));
+        assert!(!is_log_of("open.20261018T203008.log", "open"));
+        assert!(!is_log_of("open.20261018T203008.138000000Z.txt", "open"));
+    }
+}
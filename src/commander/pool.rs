@@ -0,0 +1,204 @@
+//! The commander's workers. The accept loop only reads a request and submits a job; up to
+//! `max_concurrent` workers run the jobs, so a slow command no longer holds up every other knock
+//! (nor makes the server's write to the socket time out).
+//!
+//! Jobs carry the name of the command they belong to, and its `Concurrency` decides what happens
+//! while another job of that name is running or waiting: `single` drops the new one, `queue` runs
+//! it once the earlier ones are done, `parallel` runs it on the next free worker regardless.
+
+use crate::commander::config::Concurrency;
+use anyhow::bail;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Cap on jobs waiting for a worker. Beyond it new jobs are rejected, so a flood of knocks for a
+/// slow command cannot grow the queue without bound.
+const MAX_QUEUED_JOBS: usize = 64;
+
+struct Job<'a> {
+    name: String,
+    concurrency: Concurrency,
+    run: Box<dyn FnOnce() + Send + 'a>,
+}
+
+#[derive(Default)]
+struct State<'a> {
+    queued: VecDeque<Job<'a>>,
+    /// Names with a running `single`/`queue` job.
+    busy: HashSet<String>,
+    stopping: bool,
+}
+
+impl State<'_> {
+    /// The oldest job allowed to start now.
+    fn next_runnable(&self) -> Option<usize> {
+        self.queued
+            .iter()
+            .position(|j| j.concurrency == Concurrency::Parallel || !self.busy.contains(&j.name))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct WorkerPool<'a> {
+    state: Mutex<State<'a>>,
+    changed: Condvar,
+}
+
+impl<'a> WorkerPool<'a> {
+    fn lock(&self) -> MutexGuard<'_, State<'a>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue `run` for the command `name`. Fails if the queue is full, or for `single` if a job of
+    /// the same name is already running or waiting.
+    pub(crate) fn submit(
+        &self,
+        name: &str,
+        concurrency: Concurrency,
+        run: impl FnOnce() + Send + 'a,
+    ) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        if state.stopping {
+            bail!("Shutting down");
+        }
+        if concurrency == Concurrency::Single
+            && (state.busy.contains(name) || state.queued.iter().any(|j| j.name == name))
+        {
+            bail!("{name} is already running");
+        }
+        if state.queued.len() >= MAX_QUEUED_JOBS {
+            bail!("{MAX_QUEUED_JOBS} jobs are already waiting");
+        }
+        state.queued.push_back(Job {
+            name: name.to_string(),
+            concurrency,
+            run: Box::new(run),
+        });
+        drop(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// A worker: run jobs until `shutdown`. A job that is running when `shutdown` is called
+    /// finishes first.
+    pub(crate) fn work(&self) {
+        loop {
+            let job = {
+                let mut state = self.lock();
+                loop {
+                    if state.stopping {
+                        return;
+                    }
+                    if let Some(index) = state.next_runnable() {
+                        if let Some(job) = state.queued.remove(index) {
+                            if job.concurrency != Concurrency::Parallel {
+                                state.busy.insert(job.name.clone());
+                            }
+                            break job;
+                        }
+                    }
+                    state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+            };
+            (job.run)();
+            if job.concurrency != Concurrency::Parallel {
+                self.lock().busy.remove(&job.name);
+            }
+            // A job of the same name may have become runnable.
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stop the workers and drop the jobs that have not started. Returns how many were dropped.
+    pub(crate) fn shutdown(&self) -> usize {
+        let mut state = self.lock();
+        state.stopping = true;
+        let dropped = state.queued.len();
+        state.queued.clear();
+        drop(state);
+        self.changed.notify_all();
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WorkerPool, MAX_QUEUED_JOBS};
+    use crate::commander::config::Concurrency;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Submit three 100 ms jobs named "cmd" to a pool of three workers and return the highest
+    /// number of them that ran at the same time, and how many ran at all.
+    fn run_three(concurrency: Concurrency) -> (usize, usize) {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let ran = AtomicUsize::new(0);
+        let pool = WorkerPool::default();
+        thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| pool.work());
+            }
+            for _ in 0..3 {
+                let _ = pool.submit("cmd", concurrency, || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(100));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            thread::sleep(Duration::from_millis(500));
+            pool.shutdown();
+        });
+        (peak.load(Ordering::SeqCst), ran.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_concurrency_modes() {
+        assert_eq!(run_three(Concurrency::Single), (1, 1));
+        assert_eq!(run_three(Concurrency::Queue), (1, 3));
+        assert_eq!(run_three(Concurrency::Parallel), (3, 3));
+    }
+
+    #[test]
+    fn test_queue_does_not_block_other_commands() {
+        let order = Mutex::new(Vec::new());
+        let pool = WorkerPool::default();
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| pool.work());
+            }
+            for name in ["slow", "slow", "fast"] {
+                let order = &order;
+                pool.submit(name, Concurrency::Queue, move || {
+                    if name == "slow" {
+                        thread::sleep(Duration::from_millis(200));
+                    }
+                    order.lock().unwrap().push(name);
+                })
+                .unwrap();
+            }
+            thread::sleep(Duration::from_millis(600));
+            pool.shutdown();
+        });
+        assert_eq!(*order.lock().unwrap(), ["fast", "slow", "slow"]);
+    }
+
+    #[test]
+    fn test_full_queue_and_shutdown() {
+        let pool = WorkerPool::default();
+        for _ in 0..MAX_QUEUED_JOBS {
+            pool.submit("cmd", Concurrency::Queue, || {}).unwrap();
+        }
+        let err = pool.submit("cmd", Concurrency::Queue, || {}).unwrap_err();
+        assert!(err.to_string().contains("already waiting"), "{err}");
+
+        assert_eq!(pool.shutdown(), MAX_QUEUED_JOBS);
+        assert!(pool.submit("cmd", Concurrency::Queue, || {}).is_err());
+        // A worker started after shutdown returns right away.
+        pool.work();
+    }
+}
@@ -1,8 +1,15 @@
 #![allow(clippy::panic)]
 
-use crate::commander::{Commander, ConfigCommander, ConfigCommands};
+use crate::commander::config::{
+    CommandKind, CommandSpec, Concurrency, Hooks, Program, Rlimits, RunAs, Throttle, ThrottleBy,
+};
+use crate::commander::ip_filter::IpFilter;
+use crate::commander::{Cidr, Commander, ConfigCommander, ConfigCommands};
+use crate::common::events::{Event, EventKind, EventPublisher, EventSource};
 use crate::common::ipc::{CommanderData, CMDR_DATA_SIZE};
-use std::collections::HashMap;
+use crate::common::notify::Notifier;
+use crate::common::protocol::EXTENSIONS_SIZE;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
@@ -11,13 +18,17 @@ use std::{env, fs, thread};
 
 // Generous enough that normal test commands never trip it, short enough that a hung test fails
 // fast.
-const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+pub(super) const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn allow_all() -> Vec<Cidr> {
+    vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]
+}
 
 fn create_commander(commands: HashMap<String, String>, config_dir: PathBuf) -> Commander {
     Commander::create(
         ConfigCommander {
             config_dir,
-            allow_non_routable_ips: true,
+            allow_cidrs: allow_all(),
             ..Default::default()
         },
         ConfigCommands::from_map(commands),
@@ -25,6 +36,37 @@ fn create_commander(commands: HashMap<String, String>, config_dir: PathBuf) -> C
     .unwrap()
 }
 
+/// A plain shell command named `test`, for tests that hand a spec to `run_command` directly.
+pub(super) fn command_spec(cmd: &str, timeout: Duration) -> CommandSpec {
+    CommandSpec {
+        name: "test".to_string(),
+        program: Program::Shell(cmd.to_string()),
+        timeout,
+        kind: CommandKind::Normal,
+        concurrency: Concurrency::Queue,
+        hooks: Hooks::default(),
+        params: BTreeMap::new(),
+        undo: None,
+        run_as: RunAs::default(),
+        throttle: None,
+        ip_filter: IpFilter::default(),
+    }
+}
+
+/// What the server sends for a packet from `ip`, authenticated with the key labelled `laptop`.
+pub(super) fn request(ip: &str) -> CommanderData {
+    CommanderData {
+        cmd_hash: 0,
+        ip: ip.parse().unwrap(),
+        key_id: [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F, 0x70, 0x81],
+        dst_ip: "192.0.2.1".parse().unwrap(),
+        counter: 1_792_359_522_546_895_639,
+        received_at: 1_792_359_522_600_000_000,
+        key_label: "laptop".to_string(),
+        extensions: [0u8; EXTENSIONS_SIZE],
+    }
+}
+
 fn wait_for_path(path: &Path) {
     for _ in 0..50 {
         if path.exists() {
@@ -86,9 +128,20 @@ fn test_create_from_path() {
             ConfigCommander {
                 config_dir: PathBuf::from("tests/conf_dir"),
                 socket_dir: None,
+                state_dir: None,
                 socket_user: "ruroco".to_string(),
                 socket_group: "ruroco".to_string(),
-                allow_non_routable_ips: false,
+                allow_cidrs: vec![],
+                deny_cidrs: vec![],
+                publish_events: false,
+                max_concurrent: 4,
+                kill_grace_sec: 5,
+                output_dir: None,
+                output_max_bytes: 1024 * 1024,
+                output_keep: 20,
+                output_max_age_sec: None,
+                audit_log: None,
+                rlimits: Rlimits::default(),
             },
             ConfigCommands::from_map(commands),
         )
@@ -112,6 +165,33 @@ fn test_run() {
     assert!(socket_file_path.exists());
 }
 
+#[test]
+fn test_create_fills_in_global_cidrs() {
+    let dir = tempfile::tempdir().unwrap();
+    let commands = ConfigCommands::deserialize(
+        "[commands]\nopen = \"true\"\nvpn = { cmd = \"true\", allow_cidrs = [\"10.8.0.0/24\"] }\n",
+    )
+    .unwrap();
+    let commander = Commander::create(
+        ConfigCommander {
+            config_dir: dir.path().to_path_buf(),
+            deny_cidrs: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        },
+        commands,
+    )
+    .unwrap();
+    let check = |name: &str, ip: &str| {
+        let spec = commander.cmds.values().find(|v| v.name == name).unwrap();
+        spec.ip_filter.check(ip.parse().unwrap()).is_ok()
+    };
+    assert!(check("open", "1.2.3.4"));
+    assert!(!check("open", "10.8.0.5"));
+    assert!(check("vpn", "10.8.0.5"));
+    assert!(!check("vpn", "10.9.0.5"));
+    assert!(!check("vpn", "127.0.0.1"));
+}
+
 #[test]
 fn test_create_with_empty_commands() {
     let dir = tempfile::tempdir().unwrap();
@@ -131,21 +211,15 @@ fn test_create_with_multiple_commands() {
 #[test]
 fn test_run_command_success() {
     let dir = tempfile::tempdir().unwrap();
-    create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        "echo hello",
-        TEST_TIMEOUT,
-        "1.2.3.4".parse().unwrap(),
-    );
+    create_commander(HashMap::new(), dir.path().to_path_buf())
+        .run_command(&command_spec("echo hello", TEST_TIMEOUT), &request("1.2.3.4"));
 }
 
 #[test]
 fn test_run_command_failure() {
     let dir = tempfile::tempdir().unwrap();
-    create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        "false",
-        TEST_TIMEOUT,
-        "1.2.3.4".parse().unwrap(),
-    );
+    create_commander(HashMap::new(), dir.path().to_path_buf())
+        .run_command(&command_spec("false", TEST_TIMEOUT), &request("1.2.3.4"));
 }
 
 #[test]
@@ -154,12 +228,21 @@ fn test_run_command_sets_env_var() {
     let output_file = dir.path().join("env_output.txt");
     let output_path = output_file.to_str().unwrap();
     create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        &format!("echo $RUROCO_IP > {output_path}"),
-        TEST_TIMEOUT,
-        "1.2.3.4".parse().unwrap(),
+        &command_spec(
+            &format!(
+                "echo $RUROCO_IP $RUROCO_IP_FAMILY $RUROCO_DST_IP $RUROCO_KEY_ID $RUROCO_KEY_LABEL \
+                 $RUROCO_COMMAND_NAME $RUROCO_COUNTER $RUROCO_RECEIVED_AT > {output_path}"
+            ),
+            TEST_TIMEOUT,
+        ),
+        &request("1.2.3.4"),
     );
     wait_for_path(&output_file);
-    assert_eq!(fs::read_to_string(&output_file).unwrap().trim(), "1.2.3.4");
+    assert_eq!(
+        fs::read_to_string(&output_file).unwrap().trim(),
+        "1.2.3.4 ipv4 192.0.2.1 1A2B3C4D5E6F7081 laptop test 1792359522546895639 \
+         2026-10-18T21:38:42.600Z"
+    );
 }
 
 #[test]
@@ -170,9 +253,8 @@ fn test_run_command_timeout_kills_slow_command() {
 
     let start = Instant::now();
     create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        &format!("sleep 5 && touch {output_path}"),
-        Duration::from_secs(1),
-        "1.2.3.4".parse().unwrap(),
+        &command_spec(&format!("sleep 5 && touch {output_path}"), Duration::from_secs(1)),
+        &request("1.2.3.4"),
     );
     let elapsed = start.elapsed();
 
@@ -183,6 +265,64 @@ fn test_run_command_timeout_kills_slow_command() {
     assert!(!output_file.exists(), "command must not complete once killed");
 }
 
+/// Whether `pid` (read from `pid_file`) is gone or only a zombie waiting for its new parent.
+fn process_gone(pid_file: &Path) -> bool {
+    let pid = fs::read_to_string(pid_file).unwrap();
+    match fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+        Ok(stat) => stat.rsplit_once(')').unwrap().1.trim_start().starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn test_run_command_timeout_kills_background_jobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("pid");
+
+    let start = Instant::now();
+    create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
+        &command_spec(
+            &format!("sleep 30 & echo $! > {}; wait", pid_file.to_str().unwrap()),
+            Duration::from_secs(1),
+        ),
+        &request("1.2.3.4"),
+    );
+    let elapsed = start.elapsed();
+
+    // The background job exits on SIGTERM, well within the default 5 s kill_grace_sec.
+    assert!(elapsed < Duration::from_secs(4), "took {elapsed:?}");
+    assert!(process_gone(&pid_file));
+}
+
+#[test]
+fn test_run_command_timeout_kills_group_after_grace() {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("pid");
+    let commander = Commander::create(
+        ConfigCommander {
+            config_dir: dir.path().to_path_buf(),
+            allow_cidrs: allow_all(),
+            kill_grace_sec: 1,
+            ..Default::default()
+        },
+        ConfigCommands::from_map(HashMap::new()),
+    )
+    .unwrap();
+
+    // Ignores SIGTERM, so only the SIGKILL after kill_grace_sec ends it.
+    let stubborn = format!("trap '' TERM; echo $$ > {}; exec sleep 30", pid_file.to_str().unwrap());
+    let start = Instant::now();
+    commander.run_command(
+        &command_spec(&format!("sh -c \"{stubborn}\" & wait"), Duration::from_secs(1)),
+        &request("1.2.3.4"),
+    );
+    let elapsed = start.elapsed();
+
+    assert!(elapsed >= Duration::from_secs(2), "killed before the grace period: {elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "took {elapsed:?}");
+    assert!(process_gone(&pid_file));
+}
+
 #[test]
 fn test_run_command_within_timeout_completes() {
     let dir = tempfile::tempdir().unwrap();
@@ -190,9 +330,8 @@ fn test_run_command_within_timeout_completes() {
     let output_path = output_file.to_str().unwrap();
 
     create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        &format!("touch {output_path}"),
-        Duration::from_secs(1),
-        "1.2.3.4".parse().unwrap(),
+        &command_spec(&format!("touch {output_path}"), Duration::from_secs(1)),
+        &request("1.2.3.4"),
     );
 
     wait_for_path(&output_file);
@@ -206,9 +345,8 @@ fn test_run_command_with_overflowing_timeout_does_not_panic() {
     let output_path = output_file.to_str().unwrap();
 
     create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
-        &format!("touch {output_path}"),
-        Duration::MAX,
-        "1.2.3.4".parse().unwrap(),
+        &command_spec(&format!("touch {output_path}"), Duration::MAX),
+        &request("1.2.3.4"),
     );
 
     wait_for_path(&output_file);
@@ -239,6 +377,12 @@ fn test_run_cycle_over_socket() {
         CommanderData {
             cmd_hash,
             ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: String::new(),
+            extensions: [0u8; EXTENSIONS_SIZE],
         },
     );
 
@@ -261,6 +405,12 @@ fn test_run_cycle_unknown_command() {
         CommanderData {
             cmd_hash: 99999,
             ip: "127.0.0.1".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: String::new(),
+            extensions: [0u8; EXTENSIONS_SIZE],
         },
     );
 
@@ -282,6 +432,12 @@ fn test_read_from_stream() {
             CommanderData {
                 cmd_hash: 42,
                 ip: "10.0.0.1".parse().unwrap(),
+                key_id: [0u8; 8],
+                dst_ip: "127.0.0.1".parse().unwrap(),
+                counter: 0,
+                received_at: 0,
+                key_label: String::new(),
+                extensions: [0u8; EXTENSIONS_SIZE],
             },
         );
     });
@@ -299,7 +455,16 @@ fn test_create_listener_no_parent_dir() {
         cmds: HashMap::new(),
         socket_user: String::new(),
         socket_group: String::new(),
-        allow_non_routable_ips: false,
+        alert_cmd: None,
+        events: EventPublisher::create(false, Path::new("/"), EventSource::Commander).unwrap(),
+        notifier: Notifier::disabled(),
+        max_concurrent: 1,
+        undo: Default::default(),
+        output: None,
+        audit: None,
+        throttles: Default::default(),
+        rlimits: Default::default(),
+        kill_grace: Duration::from_secs(5),
     };
     assert!(commander
         .create_listener()
@@ -307,3 +472,467 @@ fn test_create_listener_no_parent_dir() {
         .to_string()
         .contains("Could not get parent dir"));
 }
+
+fn start_commander_from_toml(commands_toml: &str, socket_dir: PathBuf) -> PathBuf {
+    let commander = Commander::create(
+        ConfigCommander {
+            config_dir: socket_dir.clone(),
+            allow_cidrs: allow_all(),
+            ..Default::default()
+        },
+        ConfigCommands::deserialize(commands_toml).unwrap(),
+    )
+    .unwrap();
+    thread::spawn(move || commander.run());
+
+    let socket_path = socket_dir.join("ruroco.socket");
+    wait_for_path(&socket_path);
+    socket_path
+}
+
+#[test]
+fn test_canary_runs_alert_only() {
+    use crate::common::blake2b_u64;
+
+    let dir = tempfile::tempdir().unwrap();
+    let alert_file = dir.path().join("alert.txt");
+    let commands_toml = format!(
+        "alert_cmd = \"echo $RUROCO_ALERT_KIND $RUROCO_COMMAND_NAME $RUROCO_IP $RUROCO_KEY_ID \
+         $RUROCO_KEY_LABEL $RUROCO_ALERT_TIME > {}\"\n[commands]\nbackup = {{ kind = \"canary\" }}\n",
+        alert_file.to_str().unwrap()
+    );
+    let socket_path = start_commander_from_toml(&commands_toml, dir.path().to_path_buf());
+
+    send_to_socket(
+        &socket_path,
+        CommanderData {
+            cmd_hash: blake2b_u64("backup").unwrap(),
+            ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F, 0x70, 0x81],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: "laptop".to_string(),
+            extensions: [0u8; EXTENSIONS_SIZE],
+        },
+    );
+
+    wait_for_path(&alert_file);
+    thread::sleep(Duration::from_millis(100));
+    let alert = fs::read_to_string(&alert_file).unwrap();
+    let fields: Vec<&str> = alert.split_whitespace().collect();
+    assert_eq!(fields[..5], ["canary", "backup", "1.2.3.4", "1A2B3C4D5E6F7081", "laptop"]);
+    assert!(chrono::DateTime::parse_from_rfc3339(fields[5]).is_ok(), "bad time: {alert}");
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn test_duress_runs_alert_and_command() {
+    use crate::common::blake2b_u64;
+
+    let dir = tempfile::tempdir().unwrap();
+    let alert_file = dir.path().join("alert.txt");
+    let cmd_file = dir.path().join("cmd.txt");
+    let commands_toml = format!(
+        "alert_cmd = \"echo $RUROCO_ALERT_KIND > {}\"\n[commands]\n\
+         open = {{ cmd = \"touch {}\", kind = \"duress\" }}\n",
+        alert_file.to_str().unwrap(),
+        cmd_file.to_str().unwrap()
+    );
+    let socket_path = start_commander_from_toml(&commands_toml, dir.path().to_path_buf());
+
+    send_to_socket(
+        &socket_path,
+        CommanderData {
+            cmd_hash: blake2b_u64("open").unwrap(),
+            ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: String::new(),
+            extensions: [0u8; EXTENSIONS_SIZE],
+        },
+    );
+
+    wait_for_path(&cmd_file);
+    assert_eq!(fs::read_to_string(&alert_file).unwrap().trim(), "duress");
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[cfg(feature = "with-client")]
+#[test]
+fn test_params_reach_command_only_when_valid() {
+    use crate::common::blake2b_u64;
+    use crate::common::protocol::params::encode_params;
+
+    let dir = tempfile::tempdir().unwrap();
+    let cmd_file = dir.path().join("cmd.txt");
+    let commands_toml = format!(
+        "[commands]\nopen = {{ cmd = \"echo $RUROCO_PARAM_HOURS $RUROCO_PARAM_PROTO >> {}\", \
+         params = {{ hours = {{ type = \"int\", min = 1, max = 8 }}, \
+         proto = {{ type = \"enum\", values = [\"tcp\", \"udp\"], default = \"tcp\" }} }} }}\n",
+        cmd_file.to_str().unwrap()
+    );
+    let socket_path = start_commander_from_toml(&commands_toml, dir.path().to_path_buf());
+
+    for hours in ["9", "8"] {
+        let params = vec![("hours".to_string(), hours.to_string())];
+        send_to_socket(
+            &socket_path,
+            CommanderData {
+                cmd_hash: blake2b_u64("open").unwrap(),
+                extensions: encode_params(&params).unwrap(),
+                ..request("1.2.3.4")
+            },
+        );
+    }
+
+    wait_for_path(&cmd_file);
+    thread::sleep(Duration::from_millis(100));
+    // The out-of-range request never ran; the valid one got the default for `proto`.
+    assert_eq!(fs::read_to_string(&cmd_file).unwrap(), "8 tcp\n");
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn test_create_rejects_canary_without_alert_cmd() {
+    let commands =
+        ConfigCommands::deserialize("[commands]\nbackup = { kind = \"canary\" }\n").unwrap();
+    let err = Commander::create(ConfigCommander::default(), commands).unwrap_err().to_string();
+    assert!(err.contains("no alert_cmd is configured"), "unexpected error: {err}");
+}
+
+#[test]
+fn test_run_cycle_publishes_command_events() {
+    use crate::common::blake2b_u64;
+    use std::os::unix::net::UnixDatagram;
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket_dir = dir.path().to_path_buf();
+    let subscriber = UnixDatagram::bind(socket_dir.join("ruroco-events.socket")).unwrap();
+    subscriber.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+    let mut commands = HashMap::new();
+    commands.insert("fail".to_string(), "exit 3".to_string());
+    let commander = Commander::create(
+        ConfigCommander {
+            config_dir: socket_dir.clone(),
+            allow_cidrs: allow_all(),
+            publish_events: true,
+            ..Default::default()
+        },
+        ConfigCommands::from_map(commands),
+    )
+    .unwrap();
+    thread::spawn(move || commander.run());
+
+    let socket_path = socket_dir.join("ruroco.socket");
+    wait_for_path(&socket_path);
+    send_to_socket(
+        &socket_path,
+        CommanderData {
+            cmd_hash: blake2b_u64("fail").unwrap(),
+            ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: String::new(),
+            extensions: [0u8; EXTENSIONS_SIZE],
+        },
+    );
+
+    let mut buf = [0u8; 1024];
+    let mut recv_event = || -> Event {
+        let len = subscriber.recv(&mut buf).unwrap();
+        rmp_serde::from_slice(&buf[..len]).unwrap()
+    };
+    let started = recv_event();
+    assert_eq!(started.source, EventSource::Commander);
+    assert_eq!(
+        started.event,
+        EventKind::CommandStarted {
+            name: "fail".to_string(),
+            ip: "1.2.3.4".to_string(),
+        }
+    );
+    match recv_event().event {
+        EventKind::CommandFinished {
+            name,
+            exit_code,
+            timed_out,
+            ..
+        } => {
+            assert_eq!(name, "fail");
+            assert_eq!(exit_code, Some(3));
+            assert!(!timed_out);
+        }
+        other => panic!("unexpected event {other:?}"),
+    }
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn test_handle_request_rejects_loopback_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_file = dir.path().join("rejected.txt");
+    create_commander(HashMap::new(), dir.path().to_path_buf()).handle_request(
+        &command_spec(&format!("touch {}", output_file.to_str().unwrap()), TEST_TIMEOUT),
+        &request("127.0.0.1"),
+        vec![],
+    );
+    assert!(!output_file.exists(), "command must not run for a rejected IP");
+}
+
+#[test]
+fn test_handle_request_accepts_loopback_when_allowed() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_file = dir.path().join("accepted.txt");
+    let spec = CommandSpec {
+        ip_filter: IpFilter {
+            allow: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+            deny: None,
+        },
+        ..command_spec(&format!("touch {}", output_file.to_str().unwrap()), TEST_TIMEOUT)
+    };
+    create_commander(HashMap::new(), dir.path().to_path_buf()).handle_request(
+        &spec,
+        &request("127.0.0.1"),
+        vec![],
+    );
+    assert!(output_file.exists(), "command must run for an IP in allow_cidrs");
+}
+
+#[test]
+fn test_denied_ip_does_not_use_up_throttle() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("log.txt");
+    let spec = CommandSpec {
+        throttle: Some(Throttle {
+            cooldown: None,
+            max_per_hour: Some(1),
+            by: ThrottleBy::Command,
+        }),
+        ip_filter: IpFilter {
+            allow: None,
+            deny: Some(vec!["198.51.100.0/24".parse().unwrap()]),
+        },
+        ..command_spec(&format!("echo $RUROCO_IP >> {}", log.to_str().unwrap()), TEST_TIMEOUT)
+    };
+    let commander = create_commander(HashMap::new(), dir.path().to_path_buf());
+    commander.handle_request(&spec, &request("198.51.100.7"), vec![]);
+    commander.handle_request(&spec, &request("1.2.3.4"), vec![]);
+    assert_eq!(fs::read_to_string(&log).unwrap(), "1.2.3.4\n");
+}
+
+#[test]
+fn test_throttled_request_is_not_run() {
+    use crate::common::blake2b_u64;
+    use std::os::unix::net::UnixDatagram;
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket_dir = dir.path().to_path_buf();
+    let subscriber = UnixDatagram::bind(socket_dir.join("ruroco-events.socket")).unwrap();
+    subscriber.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+    let commands =
+        ConfigCommands::deserialize("[commands]\nonce = { cmd = \"true\", cooldown_sec = 3600 }\n")
+            .unwrap();
+    let commander = Commander::create(
+        ConfigCommander {
+            config_dir: socket_dir.clone(),
+            allow_cidrs: allow_all(),
+            publish_events: true,
+            ..Default::default()
+        },
+        commands,
+    )
+    .unwrap();
+    thread::spawn(move || commander.run());
+
+    let socket_path = socket_dir.join("ruroco.socket");
+    wait_for_path(&socket_path);
+    let data = CommanderData {
+        cmd_hash: blake2b_u64("once").unwrap(),
+        ip: "1.2.3.4".parse().unwrap(),
+        key_id: [0u8; 8],
+        dst_ip: "127.0.0.1".parse().unwrap(),
+        counter: 0,
+        received_at: 0,
+        key_label: String::new(),
+        extensions: [0u8; EXTENSIONS_SIZE],
+    };
+    send_to_socket(&socket_path, data.clone());
+    send_to_socket(&socket_path, data);
+
+    let mut buf = [0u8; 1024];
+    let kinds: Vec<EventKind> = (0..3)
+        .map(|_| {
+            let len = subscriber.recv(&mut buf).unwrap();
+            rmp_serde::from_slice::<Event>(&buf[..len]).unwrap().event
+        })
+        .collect();
+    assert!(matches!(kinds[0], EventKind::CommandStarted { .. }), "{kinds:?}");
+    assert!(matches!(kinds[1], EventKind::CommandFinished { .. }), "{kinds:?}");
+    match &kinds[2] {
+        EventKind::CommandThrottled { name, ip, reason } => {
+            assert_eq!((name.as_str(), ip.as_str()), ("once", "1.2.3.4"));
+            assert!(reason.starts_with("cooldown, "), "{reason}");
+        }
+        other => panic!("unexpected event {other:?}"),
+    }
+    let _ = fs::remove_file(&socket_path);
+}
+
+fn hooked_spec(cmd: &str, timeout: Duration, hooks_dir: &Path) -> CommandSpec {
+    let hook = |result: &str| {
+        Some(format!(
+            "echo \"$RUROCO_RESULT|$RUROCO_COMMAND_NAME|$RUROCO_KEY_LABEL|$RUROCO_EXIT_CODE|$RUROCO_STDOUT\" > {}",
+            hooks_dir.join(result).to_str().unwrap()
+        ))
+    };
+    CommandSpec {
+        hooks: Hooks {
+            on_success: hook("success"),
+            on_failure: hook("failure"),
+            on_timeout: hook("timeout"),
+        },
+        ..command_spec(cmd, timeout)
+    }
+}
+
+#[test]
+fn test_run_command_runs_on_success_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    create_commander(HashMap::new(), dir.path().to_path_buf())
+        .run_command(&hooked_spec("echo hi", TEST_TIMEOUT, dir.path()), &request("1.2.3.4"));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("success")).unwrap().trim(),
+        "success|test|laptop|0|hi"
+    );
+    assert!(!dir.path().join("failure").exists());
+}
+
+#[test]
+fn test_run_command_runs_on_failure_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
+        &hooked_spec("echo oops; exit 4", TEST_TIMEOUT, dir.path()),
+        &request("1.2.3.4"),
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("failure")).unwrap().trim(),
+        "failure|test|laptop|4|oops"
+    );
+    assert!(!dir.path().join("success").exists());
+}
+
+#[test]
+fn test_run_command_runs_on_timeout_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    create_commander(HashMap::new(), dir.path().to_path_buf()).run_command(
+        &hooked_spec("sleep 5", Duration::from_millis(200), dir.path()),
+        &request("1.2.3.4"),
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("timeout")).unwrap().trim(),
+        "timeout|test|laptop||"
+    );
+}
+
+/// Wait until `path` holds exactly `expected`.
+fn wait_for_content(path: &Path, expected: &str) {
+    for _ in 0..50 {
+        if fs::read_to_string(path).is_ok_and(|content| content == expected) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{path:?} never held {expected:?}, got {:?}", fs::read_to_string(path));
+}
+
+#[test]
+fn test_undo_runs_after_ttl_and_repeat_knock_does_not_rerun() {
+    use crate::common::blake2b_u64;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("log.txt");
+    let commands_toml = format!(
+        "[commands]\nopen = {{ cmd = \"echo open $RUROCO_IP >> {0}\", \
+         undo_cmd = \"echo close $RUROCO_IP >> {0}\", ttl_sec = 1 }}\n",
+        log.to_str().unwrap()
+    );
+    let socket_path = start_commander_from_toml(&commands_toml, dir.path().to_path_buf());
+
+    for _ in 0..2 {
+        send_to_socket(
+            &socket_path,
+            CommanderData {
+                cmd_hash: blake2b_u64("open").unwrap(),
+                ..request("1.2.3.4")
+            },
+        );
+    }
+
+    wait_for_path(&dir.path().join("undo.msgpck"));
+    wait_for_content(&log, "open 1.2.3.4\nclose 1.2.3.4\n");
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn test_persisted_undo_runs_after_restart() {
+    use crate::commander::undo::{PendingUndo, UndoSchedule};
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("log.txt");
+    let schedule = UndoSchedule::load(dir.path()).unwrap();
+    schedule
+        .schedule(PendingUndo {
+            command: "open".to_string(),
+            ip: "1.2.3.4".parse().unwrap(),
+            params: vec![],
+            undo_cmd: format!("echo close $RUROCO_IP >> {}", log.to_str().unwrap()),
+            timeout_sec: 5,
+            run_as: RunAs::default(),
+            env: vec![("RUROCO_IP".to_string(), "1.2.3.4".to_string())],
+            due: 0,
+            key_id: request("1.2.3.4").key_id,
+            in_flight: false,
+        })
+        .unwrap();
+
+    // The entry is gone from commands.toml; the persisted undo still runs.
+    let socket_path = start_commander_from_toml("[commands]\n", dir.path().to_path_buf());
+    wait_for_content(&log, "close 1.2.3.4\n");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(UndoSchedule::load(dir.path()).unwrap().len(), 0);
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn test_slow_command_does_not_block_other_commands() {
+    use crate::common::blake2b_u64;
+
+    let dir = tempfile::tempdir().unwrap();
+    let fast_file = dir.path().join("fast.txt");
+    let commands_toml = format!(
+        "[commands]\nslow = \"sleep 5\"\nfast = \"touch {}\"\n",
+        fast_file.to_str().unwrap()
+    );
+    let socket_path = start_commander_from_toml(&commands_toml, dir.path().to_path_buf());
+
+    let start = Instant::now();
+    for name in ["slow", "fast"] {
+        send_to_socket(
+            &socket_path,
+            CommanderData {
+                cmd_hash: blake2b_u64(name).unwrap(),
+                ..request("1.2.3.4")
+            },
+        );
+    }
+    wait_for_path(&fast_file);
+    assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+    let _ = fs::remove_file(&socket_path);
+}
@@ -0,0 +1,192 @@
+//! Per-command cooldowns and hourly caps (`cooldown_sec`, `max_per_hour` in `commands.toml`). The
+//! server's rate limiter only sees source IPs before authentication; this limits what a key holder
+//! can make the commander run.
+//!
+//! Runs are counted per command, or per command and client IP or key with `throttle_by`. Only runs
+//! that were let through count, and only in memory: a restart forgets them.
+
+use crate::commander::config::{CommandSpec, Throttle, ThrottleBy};
+use crate::common::ipc::{key_id_hex, CommanderData};
+use anyhow::bail;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// The runs of one command and scope, by `(command, scope)`.
+#[derive(Debug, Default)]
+pub(crate) struct Throttles {
+    runs: Mutex<HashMap<(String, String), Runs>>,
+}
+
+/// Start times of the runs within `window`, newest last.
+#[derive(Debug, Default)]
+struct Runs {
+    /// The last hour, or the command's cooldown if longer. Kept per entry: pruning has to use the
+    /// window of the command that ran, not of the one being admitted.
+    window: Duration,
+    starts: VecDeque<Instant>,
+}
+
+/// Only the configuration matters; the counters are runtime state.
+impl PartialEq for Throttles {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Throttles {
+    /// Record a run of `spec` for `data` now, or fail with why it may not run yet.
+    pub(crate) fn admit(&self, spec: &CommandSpec, data: &CommanderData) -> anyhow::Result<()> {
+        let Some(throttle) = &spec.throttle else {
+            return Ok(());
+        };
+        self.admit_at(spec, throttle, data, Instant::now())
+    }
+
+    fn admit_at(
+        &self,
+        spec: &CommandSpec,
+        throttle: &Throttle,
+        data: &CommanderData,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let scope = match throttle.by {
+            ThrottleBy::Command => String::new(),
+            ThrottleBy::Ip => data.ip.to_string(),
+            ThrottleBy::Key => key_id_hex(&data.key_id),
+        };
+        let window = throttle.cooldown.map_or(HOUR, |cooldown| cooldown.max(HOUR));
+        let mut runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        // Forget what no longer counts, for every command and scope, so the map stays small.
+        runs.retain(|_, Runs { window, starts }| {
+            while starts.front().is_some_and(|start| now.duration_since(*start) >= *window) {
+                starts.pop_front();
+            }
+            !starts.is_empty()
+        });
+        let entry = runs.entry((spec.name.clone(), scope)).or_default();
+        entry.window = window;
+        let starts = &mut entry.starts;
+
+        if let (Some(cooldown), Some(last)) = (throttle.cooldown, starts.back()) {
+            let since = now.duration_since(*last);
+            if since < cooldown {
+                bail!("cooldown, {}s left", (cooldown - since).as_secs_f64().ceil());
+            }
+        }
+        if let Some(max) = throttle.max_per_hour {
+            let in_hour = starts.iter().filter(|start| now.duration_since(**start) < HOUR).count();
+            if in_hour >= max as usize {
+                bail!("already ran {in_hour} time(s) in the last hour, max_per_hour is {max}");
+            }
+        }
+        starts.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throttles;
+    use crate::commander::config::{CommandSpec, Throttle, ThrottleBy};
+    use crate::commander::tests::{command_spec, request, TEST_TIMEOUT};
+    use crate::common::ipc::CommanderData;
+    use std::time::{Duration, Instant};
+
+    fn spec(throttle: Throttle) -> CommandSpec {
+        CommandSpec {
+            throttle: Some(throttle),
+            ..command_spec("true", TEST_TIMEOUT)
+        }
+    }
+
+    fn data(ip: &str, key: u8) -> CommanderData {
+        CommanderData {
+            key_id: [key; 8],
+            ..request(ip)
+        }
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let throttle = Throttle {
+            cooldown: Some(Duration::from_secs(60)),
+            max_per_hour: None,
+            by: ThrottleBy::Command,
+        };
+        let (spec, throttles, start) =
+            (spec(throttle.clone()), Throttles::default(), Instant::now());
+        let admit = |ip, secs| {
+            throttles.admit_at(&spec, &throttle, &data(ip, 1), start + Duration::from_secs(secs))
+        };
+        assert!(admit("1.2.3.4", 0).is_ok());
+        let err = admit("5.6.7.8", 59).unwrap_err();
+        assert_eq!(err.to_string(), "cooldown, 1s left");
+        assert!(admit("1.2.3.4", 60).is_ok());
+    }
+
+    #[test]
+    fn test_long_cooldown_outlives_other_commands() {
+        let long = Throttle {
+            cooldown: Some(Duration::from_secs(7200)),
+            max_per_hour: None,
+            by: ThrottleBy::Command,
+        };
+        let short = Throttle {
+            cooldown: Some(Duration::from_secs(60)),
+            ..long.clone()
+        };
+        let (backup, open) = (
+            spec(long.clone()),
+            CommandSpec {
+                name: "open".to_string(),
+                ..spec(short.clone())
+            },
+        );
+        let (throttles, start) = (Throttles::default(), Instant::now());
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(throttles.admit_at(&backup, &long, &data("1.2.3.4", 1), at(0)).is_ok());
+        // Admitting another command 90 minutes later must not forget the first one's run.
+        assert!(throttles.admit_at(&open, &short, &data("1.2.3.4", 1), at(5400)).is_ok());
+        let err = throttles.admit_at(&backup, &long, &data("1.2.3.4", 1), at(5401)).unwrap_err();
+        assert_eq!(err.to_string(), "cooldown, 1799s left");
+        assert!(throttles.admit_at(&backup, &long, &data("1.2.3.4", 1), at(7200)).is_ok());
+    }
+
+    #[test]
+    fn test_max_per_hour_by_ip() {
+        let throttle = Throttle {
+            cooldown: None,
+            max_per_hour: Some(2),
+            by: ThrottleBy::Ip,
+        };
+        let (spec, throttles, start) =
+            (spec(throttle.clone()), Throttles::default(), Instant::now());
+        let admit = |ip, secs| {
+            throttles.admit_at(&spec, &throttle, &data(ip, 1), start + Duration::from_secs(secs))
+        };
+        assert!(admit("1.2.3.4", 0).is_ok());
+        assert!(admit("1.2.3.4", 10).is_ok());
+        let err = admit("1.2.3.4", 20).unwrap_err();
+        assert!(err.to_string().contains("max_per_hour is 2"), "{err}");
+        // Another IP has its own budget; a rejected run does not count.
+        assert!(admit("5.6.7.8", 20).is_ok());
+        assert!(admit("1.2.3.4", 3600).is_ok());
+        assert!(admit("1.2.3.4", 3605).is_err());
+    }
+
+    #[test]
+    fn test_by_key() {
+        let throttle = Throttle {
+            cooldown: Some(Duration::from_secs(60)),
+            max_per_hour: None,
+            by: ThrottleBy::Key,
+        };
+        let (spec, throttles, now) = (spec(throttle.clone()), Throttles::default(), Instant::now());
+        assert!(throttles.admit_at(&spec, &throttle, &data("1.2.3.4", 1), now).is_ok());
+        assert!(throttles.admit_at(&spec, &throttle, &data("5.6.7.8", 1), now).is_err());
+        assert!(throttles.admit_at(&spec, &throttle, &data("1.2.3.4", 2), now).is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+//! Pending `undo_cmd`s: after a command with an `undo_cmd` and `ttl_sec` succeeds, its undo is
+//! scheduled `ttl_sec` later, with the environment of the request that triggered it (so it sees the
+//! same `$RUROCO_IP`). The schedule is persisted to `undo.msgpck` in the commander's state dir
+//! whenever it changes, so undos still run after a restart; one that came due while the commander
+//! was down runs right after startup.
+//!
+//! A pending undo is identified by command name, client IP and parameters. A repeat knock for the
+//! same triple does not run the command again (which could stack a second, identical firewall
+//! rule); it only moves the undo to `ttl_sec` from now.
+
+use super::Commander;
+use crate::commander::config::{CommandSpec, Concurrency, Program, RunAs, Undo};
+use crate::commander::exec::{request_env, Requester};
+use crate::commander::pool::WorkerPool;
+use crate::common::fs::write_atomic;
+use crate::common::ipc::CommanderData;
+use crate::common::logging::error;
+use crate::common::protocol::KEY_ID_SIZE;
+use crate::common::{info, resolve_path};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An `undo_cmd` waiting for its deadline.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub(crate) struct PendingUndo {
+    pub(crate) command: String,
+    pub(crate) ip: IpAddr,
+    pub(crate) params: Vec<(String, String)>,
+    /// Copied from the config when scheduled, so an undo still runs as intended after the entry
+    /// was edited or removed from `commands.toml`.
+    pub(crate) undo_cmd: String,
+    pub(crate) timeout_sec: u64,
+    /// The command's `user`/`group`/`cwd`/`env`/`umask`, so the undo runs like the command did.
+    pub(crate) run_as: RunAs,
+    /// The `RUROCO_*` variables of the request that scheduled it.
+    pub(crate) env: Vec<(String, String)>,
+    /// Unix time in seconds.
+    pub(crate) due: u64,
+    /// Of the request that scheduled it, for the audit log. All zero for undos scheduled by a
+    /// commander that did not store it yet.
+    #[serde(default)]
+    pub(crate) key_id: [u8; KEY_ID_SIZE],
+    /// Handed to a worker and not finished yet. Not persisted, so an undo interrupted by a crash
+    /// runs again on the next start.
+    #[serde(skip)]
+    pub(crate) in_flight: bool,
+}
+
+impl PendingUndo {
+    fn is_for(&self, command: &str, ip: IpAddr, params: &[(String, String)]) -> bool {
+        self.command == command && self.ip == ip && self.params == params
+    }
+}
+
+/// Stability: like the server's blocklist, the on-disk format is msgpack of this struct without a
+/// version field; a schema change makes loading fail, and the commander refuses to start rather
+/// than silently dropping pending undos.
+#[derive(Deserialize, Serialize, Default)]
+struct UndoFile {
+    entries: Vec<PendingUndo>,
+}
+
+/// The pending undos plus where they are persisted. Shared by the accept loop and the workers, so
+/// every change locks the entries and is saved before the lock is released: two saves can never
+/// overtake each other.
+#[derive(Debug, Default)]
+pub(crate) struct UndoSchedule {
+    entries: Mutex<Vec<PendingUndo>>,
+    path: PathBuf,
+}
+
+impl UndoSchedule {
+    /// Load `state_dir/undo.msgpck`, or start empty if it does not exist. Never writes: the file is
+    /// only created once something is scheduled.
+    pub(crate) fn load(state_dir: &Path) -> anyhow::Result<UndoSchedule> {
+        let path = resolve_path(state_dir).join("undo.msgpck");
+        let file = if path.exists() {
+            let bytes = fs::read(&path)
+                .with_context(|| format!("Could not read undo schedule from path {path:?}"))?;
+            rmp_serde::from_slice::<UndoFile>(&bytes)
+                .with_context(|| format!("Could not create undo schedule from {path:?}"))?
+        } else {
+            UndoFile::default()
+        };
+        Ok(UndoSchedule {
+            entries: Mutex::new(file.entries),
+            path,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<PendingUndo>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Move the pending undo for this request to `due`, if there is one that is not already
+    /// running (never earlier than it already was). Returns whether there was one.
+    pub(crate) fn extend(
+        &self,
+        command: &str,
+        ip: IpAddr,
+        params: &[(String, String)],
+        due: u64,
+    ) -> anyhow::Result<bool> {
+        let mut entries = self.lock();
+        match entries.iter_mut().find(|e| !e.in_flight && e.is_for(command, ip, params)) {
+            Some(entry) => {
+                entry.due = entry.due.max(due);
+                self.save(&entries)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub(crate) fn schedule(&self, entry: PendingUndo) -> anyhow::Result<()> {
+        let mut entries = self.lock();
+        entries.retain(|e| e.in_flight || !e.is_for(&entry.command, entry.ip, &entry.params));
+        entries.push(entry);
+        self.save(&entries)
+    }
+
+    /// Mark every undo due at `now` as in flight and return them, earliest first. They stay in the
+    /// schedule (and the file) until `finish`ed.
+    pub(crate) fn take_due(&self, now: u64) -> Vec<PendingUndo> {
+        let mut entries = self.lock();
+        let mut due: Vec<PendingUndo> = entries
+            .iter_mut()
+            .filter(|e| !e.in_flight && e.due <= now)
+            .map(|e| {
+                e.in_flight = true;
+                e.clone()
+            })
+            .collect();
+        due.sort_by_key(|e| e.due);
+        due
+    }
+
+    /// Hand an undo that could not be started back, so it is taken again on the next tick.
+    pub(crate) fn release(&self, undo: &PendingUndo) {
+        if let Some(entry) = self.lock().iter_mut().find(|e| e.in_flight && Self::same(e, undo)) {
+            entry.in_flight = false;
+        }
+    }
+
+    /// Remove an undo that ran.
+    pub(crate) fn finish(&self, undo: &PendingUndo) -> anyhow::Result<()> {
+        let mut entries = self.lock();
+        entries.retain(|e| !(e.in_flight && Self::same(e, undo)));
+        self.save(&entries)
+    }
+
+    fn same(a: &PendingUndo, b: &PendingUndo) -> bool {
+        a.is_for(&b.command, b.ip, &b.params) && a.due == b.due
+    }
+
+    fn save(&self, entries: &[PendingUndo]) -> anyhow::Result<()> {
+        let file = UndoFile {
+            entries: entries.to_vec(),
+        };
+        let vec = rmp_serde::to_vec(&file).with_context(|| "Error serializing undo schedule")?;
+        write_atomic(&self.path, &vec)
+            .with_context(|| format!("Error persisting undo schedule to {:?}", self.path))
+    }
+}
+
+impl PartialEq for UndoSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && *self.lock() == *other.lock()
+    }
+}
+
+impl Commander {
+    /// For a repeat knock while the undo of an earlier one is still pending: push the undo back by
+    /// `ttl_sec` and return `true`, so the caller skips running the command again.
+    pub(super) fn extend_undo(
+        &self,
+        spec: &CommandSpec,
+        undo: &Undo,
+        data: &CommanderData,
+        params: &[(String, String)],
+    ) -> anyhow::Result<bool> {
+        if !self.undo.extend(&spec.name, data.ip, params, due_in(undo.ttl)?)? {
+            return Ok(false);
+        }
+        info(format!(
+            "{} already ran for {}, extended its undo by {}s instead of running it again",
+            spec.name,
+            data.ip,
+            undo.ttl.as_secs()
+        ));
+        Ok(true)
+    }
+
+    /// Schedule `undo` for a request whose command just succeeded.
+    pub(super) fn schedule_undo(
+        &self,
+        spec: &CommandSpec,
+        undo: &Undo,
+        data: &CommanderData,
+        params: Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        self.undo.schedule(PendingUndo {
+            command: spec.name.clone(),
+            ip: data.ip,
+            params,
+            undo_cmd: undo.cmd.clone(),
+            timeout_sec: spec.timeout.as_secs(),
+            run_as: spec.run_as.clone(),
+            env: request_env(spec, data),
+            due: due_in(undo.ttl)?,
+            key_id: data.key_id,
+            in_flight: false,
+        })?;
+        info(format!("Scheduled undo of {} for {} in {}s", spec.name, data.ip, undo.ttl.as_secs()));
+        Ok(())
+    }
+
+    /// Hand every undo that is due to the worker pool, queued behind a running request for the same
+    /// command. Each one runs once: a failed undo is logged, not retried, so a broken `undo_cmd`
+    /// cannot fire on every loop iteration forever.
+    pub(super) fn submit_due_undos<'a>(&'a self, pool: &WorkerPool<'a>) -> anyhow::Result<()> {
+        for entry in self.undo.take_due(unix_now()?) {
+            let name = entry.command.clone();
+            let job = {
+                let entry = entry.clone();
+                move || self.run_undo(&entry)
+            };
+            if let Err(e) = pool.submit(&name, Concurrency::Queue, job) {
+                self.undo.release(&entry);
+                error(format!("Could not start undo of {name} for {}: {e}", entry.ip));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removed from the schedule only after it ran: if the commander dies halfway, the undo runs
+    /// again on the next start instead of leaving the rule it reverts in place.
+    fn run_undo(&self, entry: &PendingUndo) {
+        info(format!("Running undo of {} for {}", entry.command, entry.ip));
+        self.execute_and_log(
+            &format!("{}.undo", entry.command),
+            &Program::Shell(entry.undo_cmd.clone()),
+            Duration::from_secs(entry.timeout_sec),
+            Requester {
+                ip: entry.ip,
+                key_id: entry.key_id,
+            },
+            &entry.env,
+            &entry.run_as,
+        );
+        if let Err(e) = self.undo.finish(entry) {
+            error(e)
+        }
+    }
+}
+
+fn unix_now() -> anyhow::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .with_context(|| "system clock before epoch")?
+        .as_secs())
+}
+
+fn due_in(ttl: Duration) -> anyhow::Result<u64> {
+    Ok(unix_now()?.saturating_add(ttl.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingUndo, UndoSchedule};
+    use crate::commander::config::RunAs;
+    use crate::common::protocol::KEY_ID_SIZE;
+    use std::net::IpAddr;
+
+    fn pending(command: &str, ip: &str, due: u64) -> PendingUndo {
+        PendingUndo {
+            command: command.to_string(),
+            ip: ip.parse::<IpAddr>().unwrap(),
+            params: vec![],
+            undo_cmd: "true".to_string(),
+            timeout_sec: 30,
+            run_as: RunAs::default(),
+            env: vec![("RUROCO_IP".to_string(), ip.to_string())],
+            due,
+            key_id: [0u8; KEY_ID_SIZE],
+            in_flight: false,
+        }
+    }
+
+    #[test]
+    fn test_extend_moves_the_matching_entry_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let schedule = UndoSchedule::load(dir.path()).unwrap();
+        schedule.schedule(pending("open", "1.2.3.4", 100)).unwrap();
+        let ip = "1.2.3.4".parse().unwrap();
+
+        assert!(schedule.extend("open", ip, &[], 200).unwrap());
+        assert!(!schedule.extend("open", "5.6.7.8".parse().unwrap(), &[], 200).unwrap());
+        let params = [("hours".to_string(), "2".to_string())];
+        assert!(!schedule.extend("open", ip, &params, 200).unwrap());
+        // Never earlier than already scheduled.
+        assert!(schedule.extend("open", ip, &[], 150).unwrap());
+        assert_eq!(schedule.lock()[0].due, 200);
+        assert_eq!(schedule.len(), 1);
+    }
+
+    #[test]
+    fn test_take_due_finish_and_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let schedule = UndoSchedule::load(dir.path()).unwrap();
+        schedule.schedule(pending("a", "1.2.3.4", 300)).unwrap();
+        schedule.schedule(pending("b", "1.2.3.4", 200)).unwrap();
+        schedule.schedule(pending("c", "1.2.3.4", 100)).unwrap();
+
+        let due = schedule.take_due(200);
+        let names: Vec<&str> = due.iter().map(|e| e.command.as_str()).collect();
+        assert_eq!(names, ["c", "b"]);
+        // In flight: not taken twice, not extended, and still persisted until finished.
+        assert!(schedule.take_due(299).is_empty());
+        assert!(!schedule.extend("b", due[1].ip, &[], 400).unwrap());
+        assert_eq!(UndoSchedule::load(dir.path()).unwrap().len(), 3);
+
+        schedule.finish(&due[0]).unwrap();
+        schedule.release(&due[1]);
+        assert_eq!(UndoSchedule::load(dir.path()).unwrap().len(), 2);
+        let names: Vec<String> = schedule.take_due(299).into_iter().map(|e| e.command).collect();
+        assert_eq!(names, ["b"]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let schedule = UndoSchedule::load(dir.path()).unwrap();
+        assert_eq!(schedule.len(), 0);
+        assert!(!dir.path().join("undo.msgpck").exists());
+
+        schedule.schedule(pending("open", "1.2.3.4", 100)).unwrap();
+        assert_eq!(UndoSchedule::load(dir.path()).unwrap(), schedule);
+
+        std::fs::write(dir.path().join("undo.msgpck"), b"garbage").unwrap();
+        let err = UndoSchedule::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("Could not create undo schedule"), "{err}");
+    }
+}
@@ -3,7 +3,7 @@ use base64::{engine::general_purpose, Engine};
 use zeroize::{ZeroizeOnDrop, Zeroizing};
 
 use crate::common::protocol::KEY_ID_SIZE;
-#[cfg(feature = "with-client")]
+#[cfg(any(feature = "with-client", feature = "with-server"))]
 use openssl::rand::rand_bytes;
 
 const KEY_SIZE: usize = 32;
@@ -42,7 +42,7 @@ impl CryptoHandler {
     }
 }
 
-#[cfg(feature = "with-client")]
+#[cfg(any(feature = "with-client", feature = "with-server"))]
 impl CryptoHandler {
     pub(crate) fn gen_key() -> anyhow::Result<String> {
         let mut key = [0u8; KEY_SIZE];
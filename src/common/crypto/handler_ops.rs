@@ -8,8 +8,8 @@ use openssl::cipher_ctx::CipherCtx;
 use openssl::rand::rand_bytes;
 
 use super::handler::CryptoHandler;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
-use crate::common::protocol::{CIPHERTEXT_SIZE, PLAINTEXT_SIZE};
+#[cfg(feature = "with-server")]
+use crate::common::protocol::AEAD_OVERHEAD;
 
 #[cfg(any(feature = "with-client", feature = "with-server"))]
 const IV_SIZE: usize = 12;
@@ -29,10 +29,8 @@ fn gcm_siv() -> anyhow::Result<Cipher> {
 
 #[cfg(feature = "with-client")]
 impl CryptoHandler {
-    pub(crate) fn encrypt(
-        &self,
-        plaintext: &[u8; PLAINTEXT_SIZE],
-    ) -> anyhow::Result<[u8; CIPHERTEXT_SIZE]> {
+    /// Returns IV, tag and ciphertext, in that order: `AEAD_OVERHEAD` bytes more than `plaintext`.
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
         let mut iv = [0u8; IV_SIZE];
         rand_bytes(&mut iv).with_context(|| "Could not generate IV")?;
 
@@ -41,33 +39,34 @@ impl CryptoHandler {
         ctx.encrypt_init(Some(&cipher), Some(&self.key), Some(&iv))
             .with_context(|| "Could not init encryption")?;
 
-        let mut ciphertext = Vec::with_capacity(PLAINTEXT_SIZE);
+        let mut ciphertext = Vec::with_capacity(plaintext.len());
         ctx.cipher_update_vec(plaintext, &mut ciphertext)
             .with_context(|| "Could not update encryption")?;
         ctx.cipher_final_vec(&mut ciphertext).with_context(|| "Could not finalize encryption")?;
 
-        if ciphertext.len() != PLAINTEXT_SIZE {
+        if ciphertext.len() != plaintext.len() {
             anyhow::bail!("ciphertext length mismatch");
         }
 
         let mut tag = [0u8; TAG_SIZE];
         ctx.tag(&mut tag).with_context(|| "Could not get tag")?;
 
-        let mut out = [0u8; CIPHERTEXT_SIZE];
-        out[0..IV_SIZE].copy_from_slice(&iv);
-        out[IV_SIZE..IV_SIZE + TAG_SIZE].copy_from_slice(&tag);
-        out[IV_SIZE + TAG_SIZE..].copy_from_slice(&ciphertext);
-
-        Ok(out)
+        Ok([iv.as_slice(), tag.as_slice(), ciphertext.as_slice()].concat())
     }
 }
 
 #[cfg(feature = "with-server")]
 impl CryptoHandler {
-    pub(crate) fn decrypt(
-        &self,
-        iv_tag_ciphertext: &[u8; CIPHERTEXT_SIZE],
-    ) -> anyhow::Result<[u8; PLAINTEXT_SIZE]> {
+    /// Fetch the cipher once up front, so OpenSSL has loaded everything it needs from disk before
+    /// the server sandboxes itself.
+    pub(crate) fn preload() -> anyhow::Result<()> {
+        gcm_siv().map(drop)
+    }
+
+    pub(crate) fn decrypt(&self, iv_tag_ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if iv_tag_ciphertext.len() < AEAD_OVERHEAD {
+            anyhow::bail!("Ciphertext too short");
+        }
         let iv = &iv_tag_ciphertext[..IV_SIZE];
         let tag = &iv_tag_ciphertext[IV_SIZE..IV_SIZE + TAG_SIZE];
         let ciphertext = &iv_tag_ciphertext[IV_SIZE + TAG_SIZE..];
@@ -78,16 +77,16 @@ impl CryptoHandler {
             .with_context(|| "Could not init decryption")?;
         ctx.set_tag(tag).with_context(|| "Could not set tag")?;
 
-        let mut plaintext = Vec::with_capacity(PLAINTEXT_SIZE);
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
         ctx.cipher_update_vec(ciphertext, &mut plaintext)
             .with_context(|| "Could not update decryption")?;
         ctx.cipher_final_vec(&mut plaintext)
             .with_context(|| "Could not finalize decryption (tag mismatch)")?;
 
-        if plaintext.len() != PLAINTEXT_SIZE {
+        if plaintext.len() != ciphertext.len() {
             anyhow::bail!("Plaintext length mismatch");
         }
 
-        plaintext.as_slice().try_into().with_context(|| "Could not convert plaintext")
+        Ok(plaintext)
     }
 }
@@ -0,0 +1,199 @@
+//! Structured event stream for local integrations. Both the server and the commander publish a
+//! msgpack-encoded `Event` per notable step (packet accepted or rejected, command started or
+//! finished) as a single datagram to `<socket_dir>/ruroco-events.socket`, so tools can react
+//! without scraping stdout.
+//!
+//! The *subscriber* owns the socket: it binds a `SOCK_DGRAM` Unix socket at that path, writable by
+//! both the server user and root. Publishing is fire-and-forget and non-blocking: with nobody bound
+//! (or a subscriber that falls behind) events are dropped, so a slow consumer can never stall the
+//! packet loop or a command.
+
+use crate::common::logging::debug;
+use crate::common::resolve_path;
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever a field is removed or changes meaning. Adding a field or event type does not
+/// bump it; subscribers should ignore what they don't know.
+pub const EVENT_VERSION: u8 = 1;
+
+/// Where the subscriber binds its datagram socket, next to `ruroco.socket`.
+pub fn get_event_socket_path(socket_dir: &Path) -> PathBuf {
+    resolve_path(socket_dir).join("ruroco-events.socket")
+}
+
+/// Which process published an event.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSource {
+    Server,
+    Commander,
+}
+
+/// One record on the event socket. Encoded with `rmp_serde::to_vec_named`, i.e. as a msgpack map
+/// keyed by field name, with `event` tagged by its `type` (e.g. `"packet_rejected"`).
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Event {
+    pub version: u8,
+    /// RFC 3339, UTC, millisecond precision.
+    pub time: String,
+    pub source: EventSource,
+    pub event: EventKind,
+}
+
+/// IPs are carried as strings (not `IpAddr`, which msgpack encodes as a raw byte variant) so any
+/// msgpack decoder yields something readable.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A packet passed every server check and was forwarded to the commander.
+    PacketAccepted {
+        ip: String,
+        key_id: String,
+        cmd_hash: u64,
+    },
+    /// A packet was dropped; `reason` is the same message the server logs. `ip` is the UDP source,
+    /// absent only when the receive itself failed.
+    PacketRejected { ip: Option<String>, reason: String },
+    /// The commander is about to run a command.
+    CommandStarted { name: String, ip: String },
+    /// A command finished. `exit_code` is `None` if it was killed by a signal (including the
+    /// timeout kill) or could not be spawned at all.
+    CommandFinished {
+        name: String,
+        ip: String,
+        exit_code: Option<i32>,
+        timed_out: bool,
+        duration_ms: u64,
+    },
+    /// A command was not run because of its `cooldown_sec` or `max_per_hour`.
+    CommandThrottled {
+        name: String,
+        ip: String,
+        reason: String,
+    },
+}
+
+/// Publishes events for one process. Disabled (a no-op) unless `publish_events` is set.
+#[derive(Debug)]
+pub(crate) struct EventPublisher {
+    socket: Option<UnixDatagram>,
+    target: PathBuf,
+    source: EventSource,
+}
+
+impl EventPublisher {
+    pub(crate) fn create(
+        enabled: bool,
+        socket_dir: &Path,
+        source: EventSource,
+    ) -> anyhow::Result<EventPublisher> {
+        let socket = if enabled {
+            let socket =
+                UnixDatagram::unbound().with_context(|| "Could not create event socket")?;
+            socket
+                .set_nonblocking(true)
+                .with_context(|| "Could not set event socket to non-blocking")?;
+            Some(socket)
+        } else {
+            None
+        };
+        Ok(EventPublisher {
+            socket,
+            target: get_event_socket_path(socket_dir),
+            source,
+        })
+    }
+
+    pub(crate) fn publish(&self, event: EventKind) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let event = Event {
+            version: EVENT_VERSION,
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            source: self.source,
+            event,
+        };
+        let result = rmp_serde::to_vec_named(&event)
+            .with_context(|| format!("Could not serialize {event:?}"))
+            .and_then(|bytes| {
+                socket
+                    .send_to(&bytes, &self.target)
+                    .with_context(|| format!("Could not send event to {:?}", self.target))
+            });
+        // No subscriber bound or a full receive queue is the normal case, not worth an error line.
+        if let Err(e) = result {
+            debug(format!("Dropped event: {e:#}"));
+        }
+    }
+}
+
+impl PartialEq for EventPublisher {
+    fn eq(&self, other: &Self) -> bool {
+        self.socket.is_some() == other.socket.is_some()
+            && self.target == other.target
+            && self.source == other.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventKind, EventPublisher, EventSource, EVENT_VERSION};
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_publish_to_subscriber() {
+        let dir = tempfile::tempdir().unwrap();
+        let subscriber = UnixDatagram::bind(dir.path().join("ruroco-events.socket")).unwrap();
+        let publisher = EventPublisher::create(true, dir.path(), EventSource::Server).unwrap();
+
+        publisher.publish(EventKind::PacketRejected {
+            ip: Some("1.2.3.4".to_string()),
+            reason: "nope".to_string(),
+        });
+
+        let mut buf = [0u8; 1024];
+        let len = subscriber.recv(&mut buf).unwrap();
+        let event: Event = rmp_serde::from_slice(&buf[..len]).unwrap();
+        assert_eq!(event.version, EVENT_VERSION);
+        assert_eq!(event.source, EventSource::Server);
+        assert_eq!(
+            event.event,
+            EventKind::PacketRejected {
+                ip: Some("1.2.3.4".to_string()),
+                reason: "nope".to_string(),
+            }
+        );
+        assert!(chrono::DateTime::parse_from_rfc3339(&event.time).is_ok());
+    }
+
+    #[test]
+    fn test_publish_without_subscriber_is_silent() {
+        let dir = tempfile::tempdir().unwrap();
+        let publisher = EventPublisher::create(true, dir.path(), EventSource::Commander).unwrap();
+        publisher.publish(EventKind::CommandStarted {
+            name: "default".to_string(),
+            ip: "1.2.3.4".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_disabled_publisher_sends_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let subscriber = UnixDatagram::bind(dir.path().join("ruroco-events.socket")).unwrap();
+        subscriber.set_nonblocking(true).unwrap();
+        let publisher = EventPublisher::create(false, dir.path(), EventSource::Server).unwrap();
+
+        publisher.publish(EventKind::PacketRejected {
+            ip: None,
+            reason: "nope".to_string(),
+        });
+
+        let mut buf = [0u8; 1024];
+        assert!(subscriber.recv(&mut buf).is_err());
+    }
+}
@@ -1,21 +1,21 @@
 use crate::common::logging::error;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 use crate::common::now_nanos;
 use anyhow::{anyhow, Context};
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 use std::io::Write;
 use std::os::unix::fs::chown;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
     write_atomic_with_mode(path, contents, None)
 }
 
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 pub(crate) fn write_atomic_with_mode(
     path: &Path,
     contents: &[u8],
@@ -103,14 +103,14 @@ pub(crate) fn change_file_ownership(
     Ok(())
 }
 
-fn get_uid_by_name(name: &str) -> anyhow::Result<u32> {
+pub(crate) fn get_uid_by_name(name: &str) -> anyhow::Result<u32> {
     let user = nix::unistd::User::from_name(name)
         .with_context(|| format!("Could not find user {name}"))?
         .ok_or_else(|| anyhow!("Could not find user {name}"))?;
     Ok(user.uid.as_raw())
 }
 
-fn get_gid_by_name(name: &str) -> anyhow::Result<u32> {
+pub(crate) fn get_gid_by_name(name: &str) -> anyhow::Result<u32> {
     let group = nix::unistd::Group::from_name(name)
         .with_context(|| format!("Could not find group {name}"))?
         .ok_or_else(|| anyhow!("Could not find group {name}"))?;
@@ -5,24 +5,62 @@
 //! commander can link it without OpenSSL.
 
 use crate::common::protocol::serialization::{deserialize_ip, serialize_ip};
+use crate::common::protocol::{EXTENSIONS_SIZE, KEY_ID_SIZE};
 use crate::common::resolve_path;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 
-pub(crate) const CMDR_DATA_SIZE: usize = 24;
+/// Room for the key's label in `CommanderData`; longer labels are cut (on a char boundary).
+pub(crate) const KEY_LABEL_SIZE: usize = 64;
+pub(crate) const CMDR_DATA_SIZE: usize = 72 + KEY_ID_SIZE + KEY_LABEL_SIZE + EXTENSIONS_SIZE;
 
-/// The 24-byte message the server sends the commander over the Unix socket:
-/// `cmd_hash` (`u64`, bytes 0:8) followed by the client IP (16 bytes, 8:24).
+/// The 208-byte message the server sends the commander over the Unix socket, all integers
+/// big-endian and IPs as 16 IPv6-mapped bytes:
+///
+/// | bytes   | field                                                    |
+/// |---------|----------------------------------------------------------|
+/// | 0:8     | `cmd_hash` (`u64`)                                       |
+/// | 8:24    | `ip`, the client IP the command is for                   |
+/// | 24:32   | `key_id` of the key that authenticated the packet        |
+/// | 32:48   | `dst_ip`, the server address the client sent it to       |
+/// | 48:64   | `counter` (`u128`) the packet carried                    |
+/// | 64:80   | `received_at` (`u128`, ns since the epoch, server clock) |
+/// | 80:144  | `key_label`, UTF-8, NUL-padded                           |
+/// | 144:208 | `extensions`, the packet's extension area (zeros for v1) |
+///
+/// The commander never sees key material; id and label are forwarded only so commands and
+/// canary/duress alerts can name the key that was used. The extension area is passed on as the
+/// client sent it: the server only checked its structure, the commander checks the parameters in it
+/// against the command's schema.
+#[derive(Clone)]
 pub(crate) struct CommanderData {
     pub(crate) cmd_hash: u64,
     pub(crate) ip: IpAddr,
+    pub(crate) key_id: [u8; KEY_ID_SIZE],
+    pub(crate) dst_ip: IpAddr,
+    pub(crate) counter: u128,
+    pub(crate) received_at: u128,
+    pub(crate) key_label: String,
+    pub(crate) extensions: [u8; EXTENSIONS_SIZE],
 }
 
 impl From<CommanderData> for [u8; CMDR_DATA_SIZE] {
     fn from(value: CommanderData) -> Self {
         let mut data = [0u8; CMDR_DATA_SIZE];
         data[..8].copy_from_slice(&value.cmd_hash.to_be_bytes());
-        data[8..].copy_from_slice(&serialize_ip(&value.ip));
+        data[8..24].copy_from_slice(&serialize_ip(&value.ip));
+        data[24..32].copy_from_slice(&value.key_id);
+        data[32..48].copy_from_slice(&serialize_ip(&value.dst_ip));
+        data[48..64].copy_from_slice(&value.counter.to_be_bytes());
+        data[64..80].copy_from_slice(&value.received_at.to_be_bytes());
+        // NULs would end the label early on the other side, so they are dropped here.
+        let label = value.key_label.replace('\0', "");
+        let mut end = label.len().min(KEY_LABEL_SIZE);
+        while !label.is_char_boundary(end) {
+            end -= 1;
+        }
+        data[80..80 + end].copy_from_slice(&label.as_bytes()[..end]);
+        data[144..].copy_from_slice(&value.extensions);
         data
     }
 }
@@ -32,24 +70,100 @@ impl From<[u8; CMDR_DATA_SIZE]> for CommanderData {
         let mut cmd_hash_bytes = [0u8; 8];
         cmd_hash_bytes.copy_from_slice(&data[0..8]);
         let mut ip_bytes = [0u8; 16];
-        ip_bytes.copy_from_slice(&data[8..]);
+        ip_bytes.copy_from_slice(&data[8..24]);
+        let mut key_id = [0u8; KEY_ID_SIZE];
+        key_id.copy_from_slice(&data[24..32]);
+        let mut dst_ip_bytes = [0u8; 16];
+        dst_ip_bytes.copy_from_slice(&data[32..48]);
+        let mut counter_bytes = [0u8; 16];
+        counter_bytes.copy_from_slice(&data[48..64]);
+        let mut received_at_bytes = [0u8; 16];
+        received_at_bytes.copy_from_slice(&data[64..80]);
+        let label = &data[80..144];
+        let mut extensions = [0u8; EXTENSIONS_SIZE];
+        extensions.copy_from_slice(&data[144..]);
+        let label_len = label.iter().position(|b| *b == 0).unwrap_or(label.len());
 
         Self {
             cmd_hash: u64::from_be_bytes(cmd_hash_bytes),
             ip: deserialize_ip(ip_bytes),
+            key_id,
+            dst_ip: deserialize_ip(dst_ip_bytes),
+            counter: u128::from_be_bytes(counter_bytes),
+            received_at: u128::from_be_bytes(received_at_bytes),
+            key_label: String::from_utf8_lossy(&label[..label_len]).to_string(),
+            extensions,
         }
     }
 }
 
+/// Render a key id as contiguous upper-case hex (e.g. `1A2B3C4D5E6F7081`), the form handed to
+/// commands through the environment and used in events. Logs keep using `{:X?}`.
+pub(crate) fn key_id_hex(key_id: &[u8; KEY_ID_SIZE]) -> String {
+    key_id.iter().map(|b| format!("{b:02X}")).collect()
+}
+
 pub fn get_commander_unix_socket_path(config_dir: &Path) -> PathBuf {
     resolve_path(config_dir).join("ruroco.socket")
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::common::ipc::get_commander_unix_socket_path;
+    use crate::common::ipc::{
+        get_commander_unix_socket_path, CommanderData, CMDR_DATA_SIZE, KEY_LABEL_SIZE,
+    };
+    use crate::common::protocol::EXTENSIONS_SIZE;
     use std::path::PathBuf;
 
+    #[test]
+    fn test_commander_data_roundtrip() {
+        let bytes: [u8; CMDR_DATA_SIZE] = CommanderData {
+            cmd_hash: 42,
+            ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F, 0x70, 0x81],
+            dst_ip: "2001:db8::1".parse().unwrap(),
+            counter: u128::MAX - 1,
+            received_at: 1_792_359_522_546_895_639,
+            key_label: "laptop".to_string(),
+            extensions: [7u8; EXTENSIONS_SIZE],
+        }
+        .into();
+        let parsed: CommanderData = bytes.into();
+        assert_eq!(parsed.cmd_hash, 42);
+        assert_eq!(parsed.ip, "1.2.3.4".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(parsed.key_id, [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F, 0x70, 0x81]);
+        assert_eq!(parsed.dst_ip, "2001:db8::1".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(parsed.counter, u128::MAX - 1);
+        assert_eq!(parsed.received_at, 1_792_359_522_546_895_639);
+        assert_eq!(parsed.key_label, "laptop");
+        assert_eq!(parsed.extensions, [7u8; EXTENSIONS_SIZE]);
+    }
+
+    #[test]
+    fn test_commander_data_cuts_long_label_on_char_boundary() {
+        // 63 ASCII bytes followed by a 2-byte char that does not fit into the 64 label bytes.
+        let label = format!("{}ä", "a".repeat(KEY_LABEL_SIZE - 1));
+        let bytes: [u8; CMDR_DATA_SIZE] = CommanderData {
+            cmd_hash: 42,
+            ip: "1.2.3.4".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "1.2.3.4".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: label,
+            extensions: [0u8; EXTENSIONS_SIZE],
+        }
+        .into();
+        let parsed: CommanderData = bytes.into();
+        assert_eq!(parsed.key_label, "a".repeat(KEY_LABEL_SIZE - 1));
+    }
+
+    #[test]
+    fn test_key_id_hex() {
+        let key_id = [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F, 0x70, 0x01];
+        assert_eq!(super::key_id_hex(&key_id), "1A2B3C4D5E6F7001");
+    }
+
     #[test]
     fn test_get_socket_path() {
         assert_eq!(
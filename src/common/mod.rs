@@ -1,6 +1,9 @@
 #[cfg(target_os = "android")]
 pub(crate) mod android;
 pub(crate) mod crypto;
+/// the structured event stream published to local subscribers (`Event`, socket path)
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub mod events;
 pub(crate) mod fs;
 #[cfg(any(feature = "with-client", feature = "with-commander"))]
 pub(crate) mod instance_lock;
@@ -8,7 +11,13 @@ pub(crate) mod instance_lock;
 #[cfg(any(feature = "with-server", feature = "with-commander"))]
 pub mod ipc;
 pub(crate) mod logging;
+/// systemd `sd_notify` readiness, status and watchdog messages
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) mod notify;
 pub(crate) mod protocol;
+/// SIGTERM/SIGINT handling shared by the server and commander run loops
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) mod signal;
 
 pub(crate) use crypto::blake2b_u64;
 #[cfg(any(feature = "with-client", feature = "with-server"))]
@@ -49,7 +58,7 @@ pub(crate) const DEFAULT_PORT: u16 = 80;
 #[cfg(feature = "with-server")]
 pub(crate) const FALLBACK_BIND_PORT: u16 = 34020;
 
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(feature = "with-client", feature = "with-commander"))]
 pub(crate) fn now_nanos() -> anyhow::Result<u128> {
     use anyhow::Context;
     Ok(std::time::SystemTime::now()
@@ -57,3 +66,15 @@ pub(crate) fn now_nanos() -> anyhow::Result<u128> {
         .with_context(|| "system clock before epoch")?
         .as_nanos())
 }
+
+/// Render a nanosecond counter/timestamp as RFC 3339 (UTC, milliseconds), falling back to the raw
+/// number when it is outside chrono's range.
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) fn format_nanos(nanos: u128) -> String {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    i64::try_from(nanos)
+        .map(|n| {
+            DateTime::<Utc>::from_timestamp_nanos(n).to_rfc3339_opts(SecondsFormat::Millis, true)
+        })
+        .unwrap_or_else(|_| nanos.to_string())
+}
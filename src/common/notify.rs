@@ -0,0 +1,206 @@
+//! systemd's `sd_notify` protocol, spoken directly (no libsystemd): newline-separated `KEY=value`
+//! assignments sent as one datagram to the Unix socket named by `$NOTIFY_SOCKET`. Used by both the
+//! server and the commander for `Type=notify` units: `READY=1` once they can serve, `STATUS=` for
+//! `systemctl status`, `WATCHDOG=1` keep-alives from the main loop, and `STOPPING=1` on shutdown.
+//!
+//! Without `$NOTIFY_SOCKET` (not started by systemd, or `Type=simple`) every call is a no-op.
+
+use crate::common::logging::{debug, error};
+use anyhow::{anyhow, Context};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Environment variables the notify protocol uses; spawned commands must not inherit them, or they
+/// could report readiness (or feed the watchdog) on the commander's behalf.
+pub(crate) const NOTIFY_ENV_VARS: [&str; 3] = ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"];
+
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    socket: Option<UnixDatagram>,
+    /// Half of `$WATCHDOG_USEC`, as `sd_watchdog_enabled(3)` recommends; `None` if the unit has no
+    /// `WatchdogSec=` (or it is meant for another pid).
+    watchdog_interval: Option<Duration>,
+    last_ping: Mutex<Instant>,
+}
+
+impl Notifier {
+    /// Read the notify environment. The socket is connected right away, while the process is still
+    /// unsandboxed: Landlock allows sends on an already connected socket, including to systemd's
+    /// abstract notify socket. Never fails: a broken `$NOTIFY_SOCKET` is logged and ignored.
+    pub(crate) fn from_env() -> Notifier {
+        let socket = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => match connect(&path.to_string_lossy()) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    error(format!("Ignoring NOTIFY_SOCKET: {e:#}"));
+                    None
+                }
+            },
+            None => None,
+        };
+        Notifier {
+            socket,
+            watchdog_interval: watchdog_interval(
+                env::var("WATCHDOG_USEC").ok().as_deref(),
+                env::var("WATCHDOG_PID").ok().as_deref(),
+                std::process::id(),
+            ),
+            last_ping: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// A notifier that never sends anything, whatever the environment says.
+    #[cfg(any(feature = "with-server", test))]
+    pub(crate) fn disabled() -> Notifier {
+        Notifier {
+            socket: None,
+            watchdog_interval: None,
+            last_ping: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    /// Replace the `STATUS=` line `systemctl status` shows.
+    #[cfg(feature = "with-server")]
+    pub(crate) fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    pub(crate) fn stopping(&self) {
+        self.send("STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    /// Feed the watchdog, at most once per `watchdog_interval`. Cheap enough to call on every
+    /// iteration of a loop.
+    pub(crate) fn watchdog(&self) {
+        let Some(interval) = self.watchdog_interval else {
+            return;
+        };
+        let Ok(mut last_ping) = self.last_ping.lock() else {
+            return;
+        };
+        if last_ping.elapsed() >= interval {
+            *last_ping = Instant::now();
+            self.send("WATCHDOG=1");
+        }
+    }
+
+    fn send(&self, message: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.send(message.as_bytes()) {
+                debug(format!("Could not send {message:?} to NOTIFY_SOCKET: {e}"));
+            }
+        }
+    }
+}
+
+impl PartialEq for Notifier {
+    fn eq(&self, other: &Self) -> bool {
+        self.socket.is_some() == other.socket.is_some()
+            && self.watchdog_interval == other.watchdog_interval
+    }
+}
+
+fn connect(path: &str) -> anyhow::Result<UnixDatagram> {
+    // A leading '@' names a socket in the abstract namespace.
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(path),
+    }
+    .with_context(|| format!("Invalid notify socket address {path:?}"))?;
+    let socket = UnixDatagram::unbound().with_context(|| "Could not create notify socket")?;
+    socket.connect_addr(&addr).with_context(|| format!("Could not connect to {path:?}"))?;
+    Ok(socket)
+}
+
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    let usec = usec?
+        .parse::<u64>()
+        .map_err(|e| anyhow!("{e}"))
+        .with_context(|| "Invalid WATCHDOG_USEC")
+        .inspect_err(|e| error(format!("{e:#}")))
+        .ok()
+        .filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, watchdog_interval, Notifier};
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0u8; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval(Some("30000000"), None, 1), Some(Duration::from_secs(15)));
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("1"), 1),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("2"), 1), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 1), None);
+        assert_eq!(watchdog_interval(Some("soon"), None, 1), None);
+        assert_eq!(watchdog_interval(None, None, 1), None);
+    }
+
+    #[test]
+    fn test_notify_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.socket");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier {
+            socket: Some(connect(path.to_str().unwrap()).unwrap()),
+            watchdog_interval: Some(Duration::ZERO),
+            last_ping: Mutex::new(Instant::now()),
+        };
+
+        notifier.ready("2 keys loaded");
+        assert_eq!(receive(&systemd), "READY=1\nSTATUS=2 keys loaded");
+        notifier.watchdog();
+        assert_eq!(receive(&systemd), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(receive(&systemd), "STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    #[test]
+    fn test_watchdog_is_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.socket");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let notifier = Notifier {
+            socket: Some(connect(path.to_str().unwrap()).unwrap()),
+            watchdog_interval: Some(Duration::from_secs(3600)),
+            last_ping: Mutex::new(Instant::now()),
+        };
+
+        notifier.watchdog();
+        assert!(systemd.recv(&mut [0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_connect_abstract_socket() {
+        let name = format!("ruroco-notify-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+        connect(&format!("@{name}")).unwrap().send(b"READY=1").unwrap();
+        assert_eq!(receive(&systemd), "READY=1");
+    }
+}
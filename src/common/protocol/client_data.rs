@@ -4,23 +4,31 @@ use std::net::IpAddr;
 #[cfg(feature = "with-client")]
 use crate::common::blake2b_u64;
 #[cfg(feature = "with-server")]
+use crate::common::protocol::params::decode_params;
+#[cfg(feature = "with-client")]
+use crate::common::protocol::params::encode_params;
+#[cfg(feature = "with-server")]
 use crate::common::protocol::serialization::deserialize_ip;
 #[cfg(feature = "with-client")]
 use crate::common::protocol::serialization::serialize_ip;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
-use crate::common::protocol::PLAINTEXT_SIZE;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
-use crate::common::protocol::PROTOCOL_VERSION;
 #[cfg(feature = "with-server")]
+use crate::common::protocol::EXTENSIONS_SIZE;
+use crate::common::protocol::{
+    PLAINTEXT_SIZE, PLAINTEXT_SIZE_V2, PROTOCOL_VERSION, PROTOCOL_VERSION_V2,
+};
 use anyhow::bail;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) struct ClientData {
+    /// `PROTOCOL_VERSION` or `PROTOCOL_VERSION_V2`, the first plaintext byte.
+    pub(crate) version: u8,
     pub(crate) cmd_hash: u64,
     pub(crate) counter: u128,
     pub(crate) strict: bool,
     pub(crate) src_ip: Option<IpAddr>,
     pub(crate) dst_ip: IpAddr,
+    /// `name=value` pairs from the v2 extension area; always empty for v1.
+    pub(crate) params: Vec<(String, String)>,
 }
 
 #[cfg(feature = "with-client")]
@@ -31,37 +39,84 @@ impl ClientData {
         src_ip: Option<IpAddr>,
         dst_ip: IpAddr,
         counter: u128,
+        params: Vec<(String, String)>,
     ) -> anyhow::Result<ClientData> {
         Ok(ClientData {
+            version: if params.is_empty() {
+                PROTOCOL_VERSION
+            } else {
+                PROTOCOL_VERSION_V2
+            },
             cmd_hash: blake2b_u64(command)?,
             counter,
             strict,
             src_ip,
             dst_ip,
+            params,
         })
     }
 
-    pub(crate) fn serialize(&self) -> anyhow::Result<[u8; PLAINTEXT_SIZE]> {
-        let mut out = [0u8; PLAINTEXT_SIZE];
+    /// Send as `version` instead of the lowest version that can carry the request, e.g. for a
+    /// server that only accepts v2 from this key.
+    pub(crate) fn with_version(mut self, version: u8) -> anyhow::Result<Self> {
+        match version {
+            PROTOCOL_VERSION if !self.params.is_empty() => {
+                bail!("Parameters need protocol version {PROTOCOL_VERSION_V2}")
+            }
+            PROTOCOL_VERSION | PROTOCOL_VERSION_V2 => {
+                self.version = version;
+                Ok(self)
+            }
+            _ => bail!(
+                "Unsupported protocol version {version}, expected {PROTOCOL_VERSION} or \
+                 {PROTOCOL_VERSION_V2}"
+            ),
+        }
+    }
+
+    /// A v1 plaintext (`PLAINTEXT_SIZE` bytes) or a v2 one (`PLAINTEXT_SIZE_V2`), per `version`.
+    pub(crate) fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut out = [0u8; PLAINTEXT_SIZE_V2];
 
-        out[0] = PROTOCOL_VERSION;
+        out[0] = self.version;
         out[1..9].copy_from_slice(&self.cmd_hash.to_be_bytes());
         out[9..25].copy_from_slice(&self.counter.to_be_bytes());
         out[25] = self.strict as u8;
         out[26..42].copy_from_slice(&self.src_ip.map(|i| serialize_ip(&i)).unwrap_or([0u8; 16]));
-        out[42..].copy_from_slice(&serialize_ip(&self.dst_ip));
+        out[42..PLAINTEXT_SIZE].copy_from_slice(&serialize_ip(&self.dst_ip));
 
-        Ok(out)
+        match self.version {
+            PROTOCOL_VERSION if self.params.is_empty() => Ok(out[..PLAINTEXT_SIZE].to_vec()),
+            PROTOCOL_VERSION_V2 => {
+                out[PLAINTEXT_SIZE..].copy_from_slice(&encode_params(&self.params)?);
+                Ok(out.to_vec())
+            }
+            PROTOCOL_VERSION => bail!("Parameters need protocol version {PROTOCOL_VERSION_V2}"),
+            version => bail!("Unsupported protocol version {version}"),
+        }
     }
 }
 
 #[cfg(feature = "with-server")]
 impl ClientData {
-    pub(crate) fn deserialize(data: [u8; PLAINTEXT_SIZE]) -> anyhow::Result<Self> {
-        let version = data[0];
-        if version != PROTOCOL_VERSION {
-            bail!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}");
-        }
+    /// Accepts a v1 or a v2 plaintext; the version byte has to match the length.
+    pub(crate) fn deserialize(data: &[u8]) -> anyhow::Result<Self> {
+        let version = data.first().copied().unwrap_or_default();
+        let params = match (version, data.len()) {
+            (PROTOCOL_VERSION, PLAINTEXT_SIZE) => Vec::new(),
+            (PROTOCOL_VERSION_V2, PLAINTEXT_SIZE_V2) => {
+                let mut area = [0u8; EXTENSIONS_SIZE];
+                area.copy_from_slice(&data[PLAINTEXT_SIZE..]);
+                decode_params(&area)?
+            }
+            (PROTOCOL_VERSION | PROTOCOL_VERSION_V2, len) => {
+                bail!("Invalid plaintext length {len} for protocol version {version}")
+            }
+            _ => bail!(
+                "Unsupported protocol version {version}, expected {PROTOCOL_VERSION} or \
+                 {PROTOCOL_VERSION_V2}"
+            ),
+        };
 
         let mut command_hash_bytes = [0u8; 8];
         command_hash_bytes.copy_from_slice(&data[1..9]);
@@ -73,14 +128,16 @@ impl ClientData {
         source_ip_bytes.copy_from_slice(&data[26..42]);
 
         let mut host_ip_bytes = [0u8; 16];
-        host_ip_bytes.copy_from_slice(&data[42..]);
+        host_ip_bytes.copy_from_slice(&data[42..PLAINTEXT_SIZE]);
 
         Ok(Self {
+            version,
             cmd_hash: u64::from_be_bytes(command_hash_bytes),
             counter: u128::from_be_bytes(counter_bytes),
             strict: data[25] != 0,
             src_ip: (source_ip_bytes != [0u8; 16]).then(|| deserialize_ip(source_ip_bytes)),
             dst_ip: deserialize_ip(host_ip_bytes),
+            params,
         })
     }
 
@@ -99,11 +156,13 @@ mod serialize_tests {
     #[test]
     fn test_max_size() {
         let data = ClientData {
+            version: 1,
             cmd_hash: u64::MAX,
             counter: u128::MAX,
             strict: true,
             src_ip: Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
             dst_ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            params: vec![],
         }
         .serialize()
         .unwrap();
@@ -114,11 +173,13 @@ mod serialize_tests {
     #[test]
     fn test_min_size() {
         let data = ClientData {
+            version: 1,
             cmd_hash: 0,
             counter: 0,
             strict: false,
             src_ip: Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
             dst_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            params: vec![],
         }
         .serialize()
         .unwrap();
@@ -132,7 +193,7 @@ mod serialize_tests {
 mod roundtrip_tests {
     use crate::common::blake2b_u64;
     use crate::common::protocol::client_data::ClientData;
-    use crate::common::protocol::PLAINTEXT_SIZE;
+    use crate::common::protocol::{PLAINTEXT_SIZE, PLAINTEXT_SIZE_V2};
     use std::net::{IpAddr, Ipv4Addr};
 
     #[test]
@@ -143,6 +204,7 @@ mod roundtrip_tests {
             Some("192.168.178.123".parse().unwrap()),
             "192.168.178.124".parse().unwrap(),
             1725821510 * 1_000_000_000,
+            vec![],
         )
         .unwrap()
         .serialize()
@@ -150,28 +212,68 @@ mod roundtrip_tests {
         assert_eq!(data.len(), PLAINTEXT_SIZE);
 
         assert_eq!(
-            ClientData::deserialize(data).unwrap(),
+            ClientData::deserialize(&data).unwrap(),
             ClientData {
+                version: 1,
                 cmd_hash: blake2b_u64("some_kind_of_long_but_not_really_that_long_command")
                     .unwrap(),
                 counter: 1725821510 * 1_000_000_000,
                 strict: false,
                 src_ip: Some(IpAddr::from(Ipv4Addr::new(192, 168, 178, 123))),
                 dst_ip: IpAddr::from(Ipv4Addr::new(192, 168, 178, 124)),
+                params: vec![],
             }
         );
     }
 
+    #[test]
+    fn test_roundtrip_with_params() {
+        let params = vec![("hours".to_string(), "8".to_string())];
+        let data =
+            ClientData::create("open_ssh", true, None, "10.0.0.1".parse().unwrap(), 42, params)
+                .unwrap()
+                .serialize()
+                .unwrap();
+        assert_eq!(data.len(), PLAINTEXT_SIZE_V2);
+        assert_eq!(data[0], 2);
+
+        let client_data = ClientData::deserialize(&data).unwrap();
+        assert_eq!(client_data.counter, 42);
+        assert_eq!(client_data.params, vec![("hours".to_string(), "8".to_string())]);
+
+        // The version byte has to match the length, in both directions.
+        let err = ClientData::deserialize(&data[..PLAINTEXT_SIZE]).unwrap_err().to_string();
+        assert!(err.contains("Invalid plaintext length 58 for protocol version 2"), "{err}");
+    }
+
     #[test]
     fn test_deserialize_rejects_unknown_version() {
         let mut data =
-            ClientData::create("cmd", false, None, "192.168.178.124".parse().unwrap(), 42)
+            ClientData::create("cmd", false, None, "192.168.178.124".parse().unwrap(), 42, vec![])
                 .unwrap()
                 .serialize()
                 .unwrap();
         data[0] = 0xFF; // tamper the version byte
 
-        let err = ClientData::deserialize(data).unwrap_err().to_string();
+        let err = ClientData::deserialize(&data).unwrap_err().to_string();
         assert!(err.contains("Unsupported protocol version 255"), "unexpected error: {err}");
     }
+
+    #[test]
+    fn test_with_version() {
+        let create = |params: Vec<(String, String)>| {
+            ClientData::create("cmd", true, None, "10.0.0.1".parse().unwrap(), 42, params).unwrap()
+        };
+
+        // v2 without parameters: an empty extension area.
+        let data = create(vec![]).with_version(2).unwrap().serialize().unwrap();
+        assert_eq!(data.len(), PLAINTEXT_SIZE_V2);
+        let client_data = ClientData::deserialize(&data).unwrap();
+        assert_eq!((client_data.version, client_data.params), (2, vec![]));
+
+        let params = vec![("hours".to_string(), "8".to_string())];
+        let err = create(params).with_version(1).unwrap_err().to_string();
+        assert!(err.contains("Parameters need protocol version 2"), "{err}");
+        assert!(create(vec![]).with_version(3).is_err());
+    }
 }
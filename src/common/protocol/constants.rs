@@ -1,8 +1,27 @@
 /// Wire protocol version, carried as the first byte of the authenticated plaintext.
 /// Bump this whenever the plaintext layout or packet framing changes incompatibly.
+#[cfg(any(feature = "with-client", feature = "with-server"))]
 pub(crate) const PROTOCOL_VERSION: u8 = 1;
+/// The v1 layout followed by an `EXTENSIONS_SIZE` extension area, see `extensions`. New fields go
+/// into new extension types rather than a new version. Which versions a server accepts is set per
+/// key, which version a client sends per target.
+#[cfg(any(feature = "with-client", feature = "with-server"))]
+pub(crate) const PROTOCOL_VERSION_V2: u8 = 2;
 
+#[cfg(any(feature = "with-client", feature = "with-server"))]
 pub(crate) const PLAINTEXT_SIZE: usize = 58;
-pub(crate) const CIPHERTEXT_SIZE: usize = 86;
+/// Also part of the server -> commander IPC frame (the commander validates the parameters in it),
+/// so this one is visible to commander-only builds as well.
+pub(crate) const EXTENSIONS_SIZE: usize = 64;
+#[cfg(any(feature = "with-client", feature = "with-server"))]
+pub(crate) const PLAINTEXT_SIZE_V2: usize = PLAINTEXT_SIZE + EXTENSIONS_SIZE;
+/// 12-byte IV plus 16-byte tag in front of every ciphertext.
+#[cfg(feature = "with-server")]
+pub(crate) const AEAD_OVERHEAD: usize = 28;
+/// Also part of the server -> commander IPC frame (the key id is forwarded for alerting), so this
+/// one constant is visible to commander-only builds as well.
 pub(crate) const KEY_ID_SIZE: usize = 8;
-pub(crate) const MSG_SIZE: usize = KEY_ID_SIZE + CIPHERTEXT_SIZE;
+#[cfg(feature = "with-server")]
+pub(crate) const MSG_SIZE: usize = KEY_ID_SIZE + AEAD_OVERHEAD + PLAINTEXT_SIZE;
+#[cfg(feature = "with-server")]
+pub(crate) const MSG_SIZE_V2: usize = KEY_ID_SIZE + AEAD_OVERHEAD + PLAINTEXT_SIZE_V2;
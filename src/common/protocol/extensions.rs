@@ -0,0 +1,109 @@
+//! The extension area of protocol v2: `EXTENSIONS_SIZE` authenticated bytes after the v1 fields,
+//! holding a list of entries, each `[type: u8][len: u8][value: len bytes]`. A type byte of 0 ends
+//! the list, and everything after it must be zero padding.
+//!
+//! New request fields are added as new entry types, not as a new layout. A receiver skips entries
+//! of a type it does not know, unless the type has `CRITICAL_BIT` set: such an entry changes what
+//! the request means, so a receiver that does not understand it has to reject the packet instead.
+
+use crate::common::protocol::EXTENSIONS_SIZE;
+use anyhow::bail;
+
+/// `name=value` command parameters, see `params`. Every v2 receiver knows this type, so it does not
+/// need `CRITICAL_BIT`.
+pub(crate) const EXT_PARAM: u8 = 1;
+/// Set on types that must not be ignored by a receiver that does not know them.
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) const CRITICAL_BIT: u8 = 0x80;
+
+#[cfg(feature = "with-client")]
+pub(crate) fn encode_extensions(
+    entries: &[(u8, Vec<u8>)],
+) -> anyhow::Result<[u8; EXTENSIONS_SIZE]> {
+    let mut area = [0u8; EXTENSIONS_SIZE];
+    let mut pos = 0;
+    for (kind, value) in entries {
+        if *kind == 0 {
+            bail!("Extension type 0 is reserved for the end of the list");
+        }
+        let Ok(len) = u8::try_from(value.len()) else {
+            bail!("Extension entry of type {kind} is longer than 255 bytes");
+        };
+        let end = pos + 2 + value.len();
+        if end > EXTENSIONS_SIZE {
+            bail!("Extension entries do not fit into {EXTENSIONS_SIZE} bytes");
+        }
+        area[pos] = *kind;
+        area[pos + 1] = len;
+        area[pos + 2..end].copy_from_slice(value);
+        pos = end;
+    }
+    Ok(area)
+}
+
+/// The entries whose type is in `known`, in order. Unknown types are skipped, or rejected if
+/// critical.
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) fn decode_extensions<'a>(
+    area: &'a [u8; EXTENSIONS_SIZE],
+    known: &[u8],
+) -> anyhow::Result<Vec<(u8, &'a [u8])>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < EXTENSIONS_SIZE && area[pos] != 0 {
+        let kind = area[pos];
+        let Some(&len) = area.get(pos + 1) else {
+            bail!("Truncated extension entry at byte {pos}");
+        };
+        let start = pos + 2;
+        let Some(value) = area.get(start..start + usize::from(len)) else {
+            bail!("Extension entry at byte {pos} overruns the area");
+        };
+        if known.contains(&kind) {
+            entries.push((kind, value));
+        } else if kind & CRITICAL_BIT != 0 {
+            bail!("Unknown critical extension type {kind}");
+        }
+        pos = start + usize::from(len);
+    }
+    if area[pos.min(EXTENSIONS_SIZE)..].iter().any(|b| *b != 0) {
+        bail!("Extension area has non-zero padding");
+    }
+    Ok(entries)
+}
+
+#[cfg(all(feature = "with-client", feature = "with-server"))]
+#[cfg(test)]
+mod tests {
+    use super::{decode_extensions, encode_extensions, CRITICAL_BIT};
+    use crate::common::protocol::EXTENSIONS_SIZE;
+
+    #[test]
+    fn test_unknown_types_are_skipped_unless_critical() {
+        let area = encode_extensions(&[(9, b"later".to_vec()), (1, b"a=1".to_vec())]).unwrap();
+        assert_eq!(decode_extensions(&area, &[1]).unwrap(), vec![(1, b"a=1".as_slice())]);
+
+        let area = encode_extensions(&[(CRITICAL_BIT | 9, b"later".to_vec())]).unwrap();
+        let err = decode_extensions(&area, &[1]).unwrap_err().to_string();
+        assert!(err.contains("Unknown critical extension type 137"), "{err}");
+        assert_eq!(decode_extensions(&area, &[CRITICAL_BIT | 9]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_encode_rejects_invalid_entries() {
+        assert!(encode_extensions(&[(0, vec![])]).is_err());
+        let err = encode_extensions(&[(1, vec![1; EXTENSIONS_SIZE - 1])]).unwrap_err();
+        assert!(err.to_string().contains("do not fit"), "{err}");
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_area() {
+        let mut area = [0u8; EXTENSIONS_SIZE];
+        area[..2].copy_from_slice(b"\x01\xFF");
+        assert!(decode_extensions(&area, &[1]).unwrap_err().to_string().contains("overruns"));
+
+        area[..2].copy_from_slice(b"\x01\x00");
+        area[EXTENSIONS_SIZE - 1] = 1;
+        assert!(decode_extensions(&area, &[1]).unwrap_err().to_string().contains("padding"));
+    }
+}
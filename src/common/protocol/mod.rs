@@ -1,11 +1,35 @@
 #[cfg(any(feature = "with-client", feature = "with-server"))]
 pub(crate) mod client_data;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
+#[cfg(any(
+    feature = "with-client",
+    feature = "with-server",
+    feature = "with-commander"
+))]
 pub(crate) mod constants;
+#[cfg(any(
+    feature = "with-client",
+    feature = "with-server",
+    feature = "with-commander"
+))]
+pub(crate) mod extensions;
+#[cfg(any(
+    feature = "with-client",
+    feature = "with-server",
+    feature = "with-commander"
+))]
+pub(crate) mod params;
 pub(crate) mod parser;
 pub(crate) mod serialization;
 
+#[cfg(feature = "with-server")]
+pub(crate) use constants::{AEAD_OVERHEAD, MSG_SIZE, MSG_SIZE_V2};
+#[cfg(any(
+    feature = "with-client",
+    feature = "with-server",
+    feature = "with-commander"
+))]
+pub(crate) use constants::{EXTENSIONS_SIZE, KEY_ID_SIZE};
 #[cfg(any(feature = "with-client", feature = "with-server"))]
 pub(crate) use constants::{
-    CIPHERTEXT_SIZE, KEY_ID_SIZE, MSG_SIZE, PLAINTEXT_SIZE, PROTOCOL_VERSION,
+    PLAINTEXT_SIZE, PLAINTEXT_SIZE_V2, PROTOCOL_VERSION, PROTOCOL_VERSION_V2,
 };
@@ -0,0 +1,140 @@
+//! Command parameters, carried as `EXT_PARAM` entries in the v2 extension area. The value of an
+//! entry is `name=value` in ASCII.
+//!
+//! Only the structure is checked here. Whether a command accepts a parameter, and which values,
+//! is up to its schema in `commands.toml`, which only the commander reads.
+
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+use crate::common::protocol::extensions::decode_extensions;
+#[cfg(feature = "with-client")]
+use crate::common::protocol::extensions::encode_extensions;
+use crate::common::protocol::extensions::EXT_PARAM;
+use crate::common::protocol::EXTENSIONS_SIZE;
+use anyhow::bail;
+#[cfg(feature = "with-client")]
+use anyhow::{anyhow, Context};
+
+pub(crate) const MAX_PARAM_NAME_LEN: usize = 32;
+
+/// Names become part of a `RUROCO_PARAM_<NAME>` variable: lower-case ASCII letters, digits and
+/// `_`, starting with a letter.
+pub(crate) fn check_param_name(name: &str) -> anyhow::Result<()> {
+    let valid = name.len() <= MAX_PARAM_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        bail!(
+            "Invalid parameter name {name:?}, expected up to {MAX_PARAM_NAME_LEN} lower-case \
+             letters, digits or '_', starting with a letter"
+        );
+    }
+    Ok(())
+}
+
+/// Values are printable ASCII without whitespace, so they are safe to log and to pass on.
+pub(crate) fn check_param_value(name: &str, value: &str) -> anyhow::Result<()> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_graphic()) {
+        bail!("Invalid value {value:?} for parameter {name}, expected printable ASCII");
+    }
+    Ok(())
+}
+
+/// Parse `--param` arguments (`name=value`).
+#[cfg(feature = "with-client")]
+pub(crate) fn parse_params(args: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    let params = args
+        .iter()
+        .map(|arg| {
+            let (name, value) = arg
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid parameter {arg:?}, expected name=value"))?;
+            check_param_name(name)?;
+            check_param_value(name, value)?;
+            Ok((name.to_string(), value.to_string()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Fail here, before anything is sent, rather than on every destination.
+    encode_params(&params)?;
+    Ok(params)
+}
+
+#[cfg(feature = "with-client")]
+pub(crate) fn encode_params(params: &[(String, String)]) -> anyhow::Result<[u8; EXTENSIONS_SIZE]> {
+    let mut entries = Vec::with_capacity(params.len());
+    for (index, (name, value)) in params.iter().enumerate() {
+        if params[..index].iter().any(|(other, _)| other == name) {
+            bail!("Parameter {name} is given more than once");
+        }
+        entries.push((EXT_PARAM, format!("{name}={value}").into_bytes()));
+    }
+    encode_extensions(&entries).context("Parameters do not fit, shorten names or values")
+}
+
+#[cfg(any(feature = "with-server", feature = "with-commander"))]
+pub(crate) fn decode_params(area: &[u8; EXTENSIONS_SIZE]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut params: Vec<(String, String)> = Vec::new();
+    for (_, entry) in decode_extensions(area, &[EXT_PARAM])? {
+        let Some((name, value)) = std::str::from_utf8(entry).ok().and_then(|e| e.split_once('='))
+        else {
+            bail!("Malformed parameter {:?}, expected name=value", String::from_utf8_lossy(entry));
+        };
+        check_param_name(name)?;
+        check_param_value(name, value)?;
+        if params.iter().any(|(other, _)| other == name) {
+            bail!("Parameter {name} is given more than once");
+        }
+        params.push((name.to_string(), value.to_string()));
+    }
+    Ok(params)
+}
+
+#[cfg(all(feature = "with-client", feature = "with-server"))]
+#[cfg(test)]
+mod tests {
+    use super::{decode_params, encode_params, parse_params};
+    use crate::common::protocol::EXTENSIONS_SIZE;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let params = parse_params(&args(&["hours=8", "proto=tcp"])).unwrap();
+        let area = encode_params(&params).unwrap();
+        assert_eq!(&area[..9], b"\x01\x07hours=8");
+        assert_eq!(decode_params(&area).unwrap(), params);
+        assert_eq!(decode_params(&[0u8; EXTENSIONS_SIZE]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_params() {
+        for (arg, expected) in [
+            ("hours", "expected name=value"),
+            ("Hours=8", "Invalid parameter name"),
+            ("1h=8", "Invalid parameter name"),
+            ("hours=", "Invalid value"),
+            ("hours=8 h", "Invalid value"),
+        ] {
+            let err = parse_params(&args(&[arg])).unwrap_err().to_string();
+            assert!(err.contains(expected), "{arg}: {err}");
+        }
+        let err = parse_params(&args(&["a=1", "a=2"])).unwrap_err().to_string();
+        assert!(err.contains("more than once"), "{err}");
+        let err = parse_params(&args(&["a=1".repeat(30).as_str()])).unwrap_err().to_string();
+        assert!(err.contains("do not fit"), "{err}");
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_params() {
+        let mut area = [0u8; EXTENSIONS_SIZE];
+        area[..5].copy_from_slice(b"\x01\x03a-1");
+        assert!(decode_params(&area).unwrap_err().to_string().contains("Malformed"));
+
+        area[..10].copy_from_slice(b"\x01\x03a=1\x01\x03a=2");
+        assert!(decode_params(&area).unwrap_err().to_string().contains("more than once"));
+
+        area[..10].copy_from_slice(b"\x01\x03A=1\x00\x00\x00\x00\x00");
+        assert!(decode_params(&area).unwrap_err().to_string().contains("Invalid parameter name"));
+    }
+}
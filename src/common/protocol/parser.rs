@@ -1,11 +1,7 @@
 #[cfg(feature = "with-client")]
 use crate::common::crypto::handler::CryptoHandler;
 #[cfg(feature = "with-server")]
-use crate::common::protocol::CIPHERTEXT_SIZE;
-#[cfg(feature = "with-client")]
-use crate::common::protocol::PLAINTEXT_SIZE;
-#[cfg(any(feature = "with-client", feature = "with-server"))]
-use crate::common::protocol::{KEY_ID_SIZE, MSG_SIZE};
+use crate::common::protocol::KEY_ID_SIZE;
 #[cfg(feature = "with-server")]
 use anyhow::Context;
 
@@ -24,23 +20,20 @@ impl DataParser {
         })
     }
 
-    pub(crate) fn encode(&self, data: &[u8; PLAINTEXT_SIZE]) -> anyhow::Result<[u8; MSG_SIZE]> {
+    /// Prefix the encrypted `data` with the key id: `MSG_SIZE` bytes for a v1 plaintext,
+    /// `MSG_SIZE_V2` for a v2 one.
+    pub(crate) fn encode(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
         let ciphertext = self.crypto_handler.encrypt(data)?;
-        let mut data_encoded = [0u8; MSG_SIZE];
-        data_encoded[0..KEY_ID_SIZE].copy_from_slice(&self.crypto_handler.id);
-        data_encoded[KEY_ID_SIZE..].copy_from_slice(&ciphertext);
-        Ok(data_encoded)
+        Ok([self.crypto_handler.id.as_slice(), ciphertext.as_slice()].concat())
     }
 }
 
 #[cfg(feature = "with-server")]
 impl DataParser {
-    pub(crate) fn decode(
-        data: &[u8; MSG_SIZE],
-    ) -> anyhow::Result<(&[u8; KEY_ID_SIZE], &[u8; CIPHERTEXT_SIZE])> {
-        let data_decoded = <&[u8; CIPHERTEXT_SIZE]>::try_from(&data[KEY_ID_SIZE..])
-            .with_context(|| "Could not get decoded data for ciphertext")?;
-        let key_id = <&[u8; KEY_ID_SIZE]>::try_from(&data[0..KEY_ID_SIZE])
+    /// Split a datagram into key id and IV/tag/ciphertext. Callers check the length first.
+    pub(crate) fn decode(data: &[u8]) -> anyhow::Result<(&[u8; KEY_ID_SIZE], &[u8])> {
+        let (key_id, data_decoded) = data
+            .split_first_chunk::<KEY_ID_SIZE>()
             .with_context(|| "Could not get decoded data for key id")?;
         Ok((key_id, data_decoded))
     }
@@ -1,7 +1,7 @@
 //! Public entry points for the libFuzzer targets under `fuzz/`. Only compiled with the `fuzzing`
 //! feature (which pulls in `with-server`), so these never ship in any real binary.
 //!
-//! The 94-byte UDP datagram is the only untrusted input surface on the server. This module drives
+//! The UDP datagram (94 bytes for v1, 158 for v2) is the only untrusted input surface on the server. This module drives
 //! arbitrary bytes through the server-side ingest chain to confirm no malformed input can panic,
 //! hang, or read out of bounds:
 //!   `DataParser::decode` -> `CryptoHandler::decrypt` -> `ClientData::deserialize`.
@@ -9,12 +9,12 @@
 use crate::common::client_data::ClientData;
 use crate::common::crypto_handler::CryptoHandler;
 use crate::common::data_parser::DataParser;
-use crate::common::protocol::{KEY_ID_SIZE, MSG_SIZE, PLAINTEXT_SIZE};
+use crate::common::protocol::{KEY_ID_SIZE, MSG_SIZE, MSG_SIZE_V2, PLAINTEXT_SIZE_V2};
 
 /// Feed arbitrary fuzzer bytes through the full server ingest path.
 ///
-/// Inputs shorter than `MSG_SIZE` are zero-padded and longer ones truncated, mirroring that the
-/// socket only ever hands the chain an exact `MSG_SIZE` datagram.
+/// Inputs up to `MSG_SIZE` are zero-padded to it, longer ones padded or truncated to `MSG_SIZE_V2`,
+/// mirroring that the server only ever hands the chain a datagram of one of those two sizes.
 pub fn fuzz_server_ingest(data: &[u8]) {
     // A fixed all-zero key. The id won't match anything in a real keystore, but the fuzz path calls
     // `decrypt` directly so the key only has to be structurally valid (8-byte id + 32-byte key).
@@ -23,23 +23,26 @@ pub fn fuzz_server_ingest(data: &[u8]) {
         id: [0u8; KEY_ID_SIZE],
     };
 
-    let mut packet = [0u8; MSG_SIZE];
-    let n = data.len().min(MSG_SIZE);
+    let size = if data.len() <= MSG_SIZE {
+        MSG_SIZE
+    } else {
+        MSG_SIZE_V2
+    };
+    let mut packet = vec![0u8; size];
+    let n = data.len().min(size);
     packet[..n].copy_from_slice(&data[..n]);
 
     // Path 1: decode -> decrypt. The AEAD tag check rejects essentially all random input, but the
     // slicing and OpenSSL calls must stay panic-free regardless.
     if let Ok((_key_id, ciphertext)) = DataParser::decode(&packet) {
         if let Ok(plaintext) = handler.decrypt(ciphertext) {
-            let _ = ClientData::deserialize(plaintext);
+            let _ = ClientData::deserialize(&plaintext);
         }
     }
 
     // Path 2: `deserialize` is only reachable post-authentication in production, so the fuzzer could
-    // never forge a valid tag to reach it via path 1. Exercise the byte parsing directly on
-    // arbitrary plaintext to cover that branch.
-    let mut plaintext = [0u8; PLAINTEXT_SIZE];
-    let m = data.len().min(PLAINTEXT_SIZE);
-    plaintext[..m].copy_from_slice(&data[..m]);
-    let _ = ClientData::deserialize(plaintext);
+    // never forge a valid tag to reach it via path 1. Exercise the byte parsing (including the v2
+    // extension area) directly on arbitrary plaintext to cover that branch.
+    let m = data.len().min(PLAINTEXT_SIZE_V2);
+    let _ = ClientData::deserialize(&data[..m]);
 }
@@ -27,6 +27,13 @@ impl Blocklist {
     /// Create an empty blocklist. Every entry will be saved to config_dir/blocklist.msgpck.
     /// If the blocklist.msgpck file already exists, its content will be loaded if possible.
     pub fn create(config_dir: &Path) -> anyhow::Result<Blocklist> {
+        let blocklist = Self::load(config_dir)?;
+        blocklist.save()?;
+        Ok(blocklist)
+    }
+
+    /// Like `create`, but never writes: for read-only users of the persisted state (`replay`, `inspect`).
+    pub(crate) fn load(config_dir: &Path) -> anyhow::Result<Blocklist> {
         let blocklist_path = Self::get_blocklist_path(config_dir);
         let blocklist = if blocklist_path.exists() {
             let blocklist_str = fs::read(&blocklist_path).with_context(|| {
@@ -43,11 +50,17 @@ impl Blocklist {
                 path: blocklist_path,
             }
         };
-
-        blocklist.save()?;
         Ok(blocklist)
     }
 
+    /// An empty blocklist with no backing file, for `replay`; it must never be `save`d.
+    pub(crate) fn in_memory() -> Blocklist {
+        Blocklist {
+            map: HashMap::new(),
+            path: PathBuf::new(),
+        }
+    }
+
     pub fn get_blocklist_path(config_dir: &Path) -> PathBuf {
         resolve_path(config_dir).join("blocklist.msgpck")
     }
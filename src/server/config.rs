@@ -7,9 +7,11 @@
 //! The inherent methods that act on `config_dir` (keys, UDP socket, blocklist) are separate
 //! `impl ConfigServer` blocks in `keys.rs` and `socket.rs`.
 
-use anyhow::{anyhow, Context};
-use clap::Parser;
+use crate::common::protocol::{PROTOCOL_VERSION, PROTOCOL_VERSION_V2};
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
@@ -19,6 +21,100 @@ use std::path::{Path, PathBuf};
 pub struct CliServer {
     #[arg(short, long, default_value = PathBuf::from("/etc/ruroco/config.toml").into_os_string())]
     pub(crate) config: PathBuf,
+    /// Append every received datagram, with receive time and source address, to this capture
+    /// file (created 0600 if missing). Feed it to `replay` to reproduce the server's verdicts.
+    #[arg(long)]
+    pub(crate) record: Option<PathBuf>,
+    #[command(subcommand)]
+    pub(crate) command: Option<ServerCommand>,
+}
+
+// Offline tools; without a subcommand the server runs. Not a doc comment: clap would show it as
+// the about text of `--help` in place of the crate description.
+#[derive(Debug, Subcommand)]
+pub(crate) enum ServerCommand {
+    /// Run the packets of a `--record` capture through the server's checks against `--config`,
+    /// printing the verdict for each. Nothing is forwarded to the commander and no state is saved.
+    Replay(ReplayCommand),
+    /// Decrypt one packet with a key and print its fields, plus whether the protocol version,
+    /// replay, clock skew and destination IP checks against `--config` would pass right now.
+    Inspect(InspectCommand),
+    /// Query or control the running server through its `control_socket`.
+    #[command(subcommand)]
+    Ctl(CtlCommand),
+    /// Manage the `.key` files in `config_dir`. Changes take effect after `ctl reload` or a restart.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct ReplayCommand {
+    /// Capture file written by `--record`.
+    pub(crate) file: PathBuf,
+    /// Check replays against the persisted blocklist instead of a fresh, empty one. Without it
+    /// each packet is judged as on first receipt (counters still advance within the capture).
+    #[arg(long, default_value_t = false)]
+    pub(crate) with_blocklist: bool,
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct InspectCommand {
+    /// Key file the packet was encrypted with.
+    #[arg(short, long)]
+    pub(crate) key_file: PathBuf,
+    /// The 94-byte (v1) or 158-byte (v2) packet as hex or base64. Read from stdin if omitted.
+    pub(crate) packet: Option<String>,
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub(crate) enum CtlCommand {
+    /// Show whether maintenance mode is on and how many keys are loaded.
+    Status,
+    /// List the loaded keys: id, label (the key file name) and the last packet accepted with each.
+    Keys,
+    /// Show the current rate-limit window per source IP and globally, with how long a source over
+    /// its limit stays blocked.
+    RateLimits,
+    /// Forget the rate-limit window of one source IP, lifting its throttle right away.
+    ResetRateLimit { ip: IpAddr },
+    /// Re-read the `.key` files in `config_dir`. On any error the loaded keys stay as they were.
+    Reload,
+    /// Turn maintenance mode on or off. While on, every packet is rejected before decryption.
+    Maintenance {
+        #[arg(value_enum)]
+        mode: Switch,
+    },
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub(crate) enum KeysCommand {
+    /// List the key files: id, label (the file name without `.key`) and path.
+    List,
+    /// Generate a new key, or import one with `--import`, and save it as `<label>.key`.
+    Add {
+        label: String,
+        /// Key file to import, e.g. one made by `ruroco-client gen`; `-` reads it from stdin.
+        #[arg(long)]
+        import: Option<PathBuf>,
+    },
+    /// Delete `<label>.key`. Its blocklist entry stays, so old packets cannot be replayed if the
+    /// same key is ever added again.
+    Remove { label: String },
+    /// Replace the key in `<label>.key` with a newly generated one, which has a new id.
+    Rotate { label: String },
+    /// Print the id of `<label>.key`, or of any key file with `--key-file`.
+    ShowId {
+        #[arg(required_unless_present = "key_file")]
+        label: Option<String>,
+        #[arg(short, long, conflicts_with = "label")]
+        key_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum Switch {
+    On,
+    Off,
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -68,6 +164,41 @@ pub struct ConfigServer {
     /// Defaults to 3600.
     #[serde(default = "default_max_clock_skew_seconds")]
     pub max_clock_skew_seconds: u64,
+    /// Publish structured events (packet accepted/rejected) to `ruroco-events.socket` in the
+    /// socket dir, for a local subscriber to consume. Off by default. Shared with the commander,
+    /// which publishes its command events under the same switch. See `common::events`.
+    #[serde(default)]
+    pub publish_events: bool,
+    /// User to switch to once the socket is bound and the blocklist is loaded, for starts as root
+    /// outside systemd (container entrypoints, init scripts). Unset under `ruroco.service`, which
+    /// already runs the server as `User=ruroco`. `blocklist_dir` must be writable by this user.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group to switch to along with `user`. Defaults to the user's primary group.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Let the server keep running as root (no `user` set, or `user = "root"`). Off by default:
+    /// the server parses untrusted packets and should never need root for that.
+    #[serde(default)]
+    pub allow_root: bool,
+    /// Restrict the running server with Landlock (filesystem) and a seccomp syscall allowlist once
+    /// privileges are dropped. On by default; kernels without support only log it. See
+    /// `server::sandbox`.
+    #[serde(default = "default_sandbox")]
+    pub sandbox: bool,
+    /// Unix socket for `ruroco-server ctl`: key and rate-limit introspection, reload and
+    /// maintenance mode. Created mode 0660 and owned by the server's user and group, so only root
+    /// and members of that group can use it. Unset disables it.
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Protocol versions accepted from keys without an entry in `key_protocol_versions`. Defaults
+    /// to both, `[1, 2]`; drop 1 once every client sends 2.
+    #[serde(default = "default_protocol_versions")]
+    pub protocol_versions: Vec<u8>,
+    /// Per-key override of `protocol_versions`, by key label (the key file name without `.key`),
+    /// e.g. `laptop = [2]`, to move clients to a new version one at a time.
+    #[serde(default)]
+    pub key_protocol_versions: HashMap<String, Vec<u8>>,
 }
 
 fn deserialize_ips<'de, D>(d: D) -> Result<Vec<IpAddr>, D::Error>
@@ -92,8 +223,35 @@ impl ConfigServer {
     }
 
     pub(crate) fn deserialize(data: &str) -> anyhow::Result<ConfigServer> {
-        toml::from_str::<ConfigServer>(data).with_context(|| "Could not parse server config")
+        let config = toml::from_str::<ConfigServer>(data)
+            .with_context(|| "Could not parse server config")?;
+        check_protocol_versions(&config.protocol_versions)
+            .with_context(|| "Invalid protocol_versions")?;
+        for (label, versions) in &config.key_protocol_versions {
+            check_protocol_versions(versions)
+                .with_context(|| format!("Invalid key_protocol_versions for key {label}"))?;
+        }
+        Ok(config)
+    }
+
+    /// The protocol versions accepted from the key with this label.
+    pub(crate) fn protocol_versions_for(&self, label: Option<&String>) -> &[u8] {
+        label
+            .and_then(|label| self.key_protocol_versions.get(label))
+            .unwrap_or(&self.protocol_versions)
+    }
+}
+
+fn check_protocol_versions(versions: &[u8]) -> anyhow::Result<()> {
+    if versions.is_empty() {
+        bail!("No protocol version accepted, expected {PROTOCOL_VERSION} and/or {PROTOCOL_VERSION_V2}");
+    }
+    if let Some(version) =
+        versions.iter().find(|v| ![PROTOCOL_VERSION, PROTOCOL_VERSION_V2].contains(v))
+    {
+        bail!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION} and/or {PROTOCOL_VERSION_V2}");
     }
+    Ok(())
 }
 
 #[cfg(any(test, feature = "testing"))]
@@ -108,6 +266,14 @@ impl Default for ConfigServer {
             max_requests_per_second: default_max_requests_per_second(),
             max_requests_per_second_global: default_max_requests_per_second_global(),
             max_clock_skew_seconds: default_max_clock_skew_seconds(),
+            publish_events: false,
+            user: None,
+            group: None,
+            allow_root: false,
+            sandbox: default_sandbox(),
+            control_socket: None,
+            protocol_versions: default_protocol_versions(),
+            key_protocol_versions: HashMap::new(),
         }
     }
 }
@@ -129,6 +295,14 @@ fn default_max_clock_skew_seconds() -> u64 {
     3600
 }
 
+fn default_sandbox() -> bool {
+    true
+}
+
+fn default_protocol_versions() -> Vec<u8> {
+    vec![PROTOCOL_VERSION, PROTOCOL_VERSION_V2]
+}
+
 fn default_config_path() -> PathBuf {
     PathBuf::from("/etc/ruroco")
 }
@@ -137,8 +311,10 @@ fn default_config_path() -> PathBuf {
 mod tests {
     use super::{
         default_config_path, default_max_clock_skew_seconds, default_max_requests_per_second,
-        default_max_requests_per_second_global, ConfigServer,
+        default_max_requests_per_second_global, default_protocol_versions, default_sandbox,
+        ConfigServer,
     };
+    use std::collections::HashMap;
 
     #[test]
     fn test_create_deserialize() {
@@ -153,10 +329,42 @@ mod tests {
                 max_requests_per_second: default_max_requests_per_second(),
                 max_requests_per_second_global: default_max_requests_per_second_global(),
                 max_clock_skew_seconds: default_max_clock_skew_seconds(),
+                publish_events: false,
+                user: None,
+                group: None,
+                allow_root: false,
+                sandbox: default_sandbox(),
+                control_socket: None,
+                protocol_versions: default_protocol_versions(),
+                key_protocol_versions: HashMap::new(),
             }
         );
     }
 
+    #[test]
+    fn test_deserialize_protocol_versions() {
+        let config = ConfigServer::deserialize(
+            "ips = [\"127.0.0.1\"]\nprotocol_versions = [1]\n[key_protocol_versions]\nlaptop = [2]",
+        )
+        .unwrap();
+        assert_eq!(config.protocol_versions_for(Some(&"laptop".to_string())), [2]);
+        assert_eq!(config.protocol_versions_for(Some(&"phone".to_string())), [1]);
+        assert_eq!(config.protocol_versions_for(None), [1]);
+
+        for (toml, expected) in [
+            ("protocol_versions = []", "Invalid protocol_versions"),
+            ("protocol_versions = [3]", "Invalid protocol_versions"),
+            (
+                "[key_protocol_versions]\nlaptop = [0]",
+                "Invalid key_protocol_versions for key laptop",
+            ),
+        ] {
+            let err =
+                ConfigServer::deserialize(&format!("ips = [\"127.0.0.1\"]\n{toml}")).unwrap_err();
+            assert!(err.to_string().contains(expected), "{toml}: {err:#}");
+        }
+    }
+
     #[test]
     fn test_deserialize_state_and_socket_dirs() {
         use std::path::PathBuf;
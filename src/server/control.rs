@@ -0,0 +1,467 @@
+//! The admin control socket (`control_socket` in `config.toml`) and its client,
+//! `ruroco-server ctl`. The server polls the socket next to its UDP socket and answers one request
+//! per connection, between packets, so a request never races with packet handling.
+//!
+//! The protocol is one request line (`status`, `keys`, `rate-limits`, `reset-rate-limit <ip>`,
+//! `reload`, `maintenance on|off`) answered by `ok` plus the output lines, or by `error: <reason>`,
+//! after which the server closes the connection. Access control is the socket file's mode (0660,
+//! owned by the server's user and group).
+
+use crate::common::logging::{error, info};
+use crate::common::{format_nanos, normalize_ip, now_nanos};
+use crate::server::config::{ConfigServer, CtlCommand, Switch};
+use crate::server::Server;
+use anyhow::{anyhow, bail, Context};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::stat::{umask, Mode};
+use std::fs;
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::os::fd::AsFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+const CONTROL_SOCKET_MODE: u32 = 0o660;
+/// Same bound as the plain `recv_from` read timeout: shutdown and the watchdog are checked at
+/// least once a second.
+const POLL_TIMEOUT_MS: u16 = 1000;
+/// A client that connects and then stalls must not hold up packet processing for long.
+const SERVER_IO_TIMEOUT: Duration = Duration::from_secs(1);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: u64 = 256;
+
+/// Bind the control socket, owner and group only. Refuses to take over a path another server is
+/// still answering on; a stale socket file from an earlier run is replaced.
+pub(super) fn bind_control_socket(path: &Path) -> anyhow::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Could not create parents for {dir:?}"))?;
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("Another server is already answering on control socket {path:?}");
+    }
+    let _ = fs::remove_file(path);
+
+    // As for the commander socket: bind() under a tight umask so the socket never exists with
+    // looser permissions than CONTROL_SOCKET_MODE, then restore the umask.
+    let previous_umask = umask(Mode::from_bits_truncate(0o077));
+    let bind_result = UnixListener::bind(path);
+    umask(previous_umask);
+    let listener =
+        bind_result.with_context(|| format!("Could not bind control socket {path:?}"))?;
+    fs::set_permissions(path, Permissions::from_mode(CONTROL_SOCKET_MODE)).with_context(|| {
+        format!("Could not set permissions {CONTROL_SOCKET_MODE:o} for {path:?}")
+    })?;
+    info(format!("Control socket listening on {path:?}"));
+    Ok(listener)
+}
+
+impl Server {
+    /// Without a control socket, returns `true` right away and `recv_from`'s read timeout does the
+    /// waiting. With one, waits on both sockets, answers a pending control request, and returns
+    /// whether a datagram is ready.
+    pub(super) fn wait_for_packet(&mut self) -> anyhow::Result<bool> {
+        let Some(control) = &self.control else {
+            return Ok(true);
+        };
+        let mut fds = [
+            PollFd::new(self.socket.as_fd(), PollFlags::POLLIN),
+            PollFd::new(control.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, PollTimeout::from(POLL_TIMEOUT_MS)) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(false),
+            Ok(_) => {}
+            Err(e) => bail!("Could not poll the server sockets: {e}"),
+        }
+        let [packet_ready, control_ready] =
+            fds.map(|fd| fd.revents().is_some_and(|events| !events.is_empty()));
+        if control_ready {
+            self.serve_control();
+        }
+        Ok(packet_ready)
+    }
+
+    /// Accept one control connection and answer its request. Errors are logged, never returned:
+    /// a broken admin client must not stop the server.
+    pub(super) fn serve_control(&mut self) {
+        let Some(control) = &self.control else {
+            return;
+        };
+        match control.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = self.answer_control(stream) {
+                    error(format!("Control request failed: {e:#}"));
+                }
+            }
+            Err(e) => error(format!("Could not accept control connection: {e}")),
+        }
+    }
+
+    fn answer_control(&mut self, mut stream: UnixStream) -> anyhow::Result<()> {
+        stream
+            .set_read_timeout(Some(SERVER_IO_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(SERVER_IO_TIMEOUT)))
+            .with_context(|| "Could not set control socket timeouts")?;
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .take(MAX_REQUEST_LEN)
+            .read_line(&mut line)
+            .with_context(|| "Could not read control request")?;
+        let request = line.trim();
+        info(format!("Control request: {request}"));
+
+        let response = match parse_request(request).and_then(|cmd| self.handle_control(&cmd)) {
+            Ok(lines) => {
+                format!("ok\n{}", lines.iter().map(|l| format!("{l}\n")).collect::<String>())
+            }
+            Err(e) => format!("error: {e:#}\n"),
+        };
+        stream.write_all(response.as_bytes()).with_context(|| "Could not write control response")
+    }
+
+    pub(super) fn handle_control(&mut self, command: &CtlCommand) -> anyhow::Result<Vec<String>> {
+        Ok(match command {
+            CtlCommand::Status => vec![
+                format!("maintenance: {}", on_off(self.maintenance)),
+                format!("keys:        {} loaded", self.crypto_handlers.len()),
+            ],
+            CtlCommand::Keys => self.describe_keys(),
+            CtlCommand::RateLimits => self.describe_rate_limits(),
+            CtlCommand::ResetRateLimit { ip } => {
+                let ip = normalize_ip(*ip);
+                if self.rate_limiter.reset(ip) {
+                    vec![format!("reset the rate-limit window of {ip}")]
+                } else {
+                    vec![format!("{ip} has no rate-limit window, nothing to reset")]
+                }
+            }
+            CtlCommand::Reload => vec![self.reload_keys()?],
+            CtlCommand::Maintenance { mode } => {
+                self.maintenance = *mode == Switch::On;
+                self.notifier.status(&self.systemd_status());
+                info(format!("Maintenance mode turned {}", on_off(self.maintenance)));
+                vec![format!("maintenance mode is {}", on_off(self.maintenance))]
+            }
+        })
+    }
+
+    /// The `STATUS=` line systemd shows for the running server.
+    pub(super) fn systemd_status(&self) -> String {
+        let keys = self.crypto_handlers.len();
+        if self.maintenance {
+            format!("Maintenance mode, rejecting all packets, {keys} key(s) loaded")
+        } else {
+            format!("Listening, {keys} key(s) loaded")
+        }
+    }
+
+    fn describe_keys(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self.crypto_handlers.keys().collect();
+        keys.sort_by_key(|id| (self.key_labels.get(*id), **id));
+        keys.into_iter()
+            .map(|id| {
+                let label = self.key_labels.get(id).map(String::as_str).unwrap_or("-");
+                let last = match self.last_accepted.get(id) {
+                    Some((at, ip)) => format!("last accepted {} from {ip}", format_nanos(*at)),
+                    None => "nothing accepted since start".to_string(),
+                };
+                let versions = self.config.protocol_versions_for(self.key_labels.get(id));
+                let versions: Vec<String> = versions.iter().map(u8::to_string).collect();
+                format!("{id:X?}  {label}  versions {}  {last}", versions.join(","))
+            })
+            .collect()
+    }
+
+    fn describe_rate_limits(&mut self) -> Vec<String> {
+        let max_per_ip = self.config.max_requests_per_second;
+        let max_global = self.config.max_requests_per_second_global;
+        let (per_ip, (global, global_left)) = self.rate_limiter.current_window();
+        let mut lines = vec![
+            format!(
+                "limits: {max_per_ip}/s per source IP, {max_global}/s global; a source over its \
+                 limit is blocked until its window ends, there are no longer bans"
+            ),
+            format!(
+                "global: {global} request(s) this second{}",
+                blocked(global, max_global, global_left)
+            ),
+        ];
+        lines.extend(per_ip.into_iter().map(|(ip, (count, left))| {
+            format!("{ip}: {count} request(s) this second{}", blocked(count, max_per_ip, left))
+        }));
+        lines
+    }
+
+    /// Re-read the key files and swap them in, seeding new keys in the blocklist like
+    /// `Server::create` does. Any error leaves the loaded keys untouched.
+    fn reload_keys(&mut self) -> anyhow::Result<String> {
+        let (handlers, labels) = self.config.create_crypto_handlers()?;
+        let floor = now_nanos()?;
+        let unseen: Vec<_> = handlers
+            .keys()
+            .filter(|id| self.blocklist.get_counter(**id).is_none())
+            .copied()
+            .collect();
+        for key_id in &unseen {
+            self.blocklist.seed_if_absent(*key_id, floor);
+        }
+        if !self.replaying {
+            if let Err(e) = self.blocklist.save() {
+                for key_id in &unseen {
+                    self.blocklist.remove(*key_id);
+                }
+                return Err(e).with_context(|| "Could not save blocklist for the reloaded keys");
+            }
+        }
+
+        let added = handlers.keys().filter(|id| !self.crypto_handlers.contains_key(*id)).count();
+        let removed = self.crypto_handlers.keys().filter(|id| !handlers.contains_key(*id)).count();
+        self.last_accepted.retain(|id, _| handlers.contains_key(id));
+        self.crypto_handlers = handlers;
+        self.key_labels = labels;
+        self.notifier.status(&self.systemd_status());
+        let summary = format!(
+            "reloaded {} key(s): {added} added, {removed} removed",
+            self.crypto_handlers.len()
+        );
+        info(format!("Control socket: {summary}"));
+        Ok(summary)
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn blocked(count: u32, max: u32, left: Duration) -> String {
+    if count >= max {
+        format!(", blocked for another {}ms", left.as_millis())
+    } else {
+        String::new()
+    }
+}
+
+fn request_line(command: &CtlCommand) -> String {
+    match command {
+        CtlCommand::Status => "status".to_string(),
+        CtlCommand::Keys => "keys".to_string(),
+        CtlCommand::RateLimits => "rate-limits".to_string(),
+        CtlCommand::ResetRateLimit { ip } => format!("reset-rate-limit {ip}"),
+        CtlCommand::Reload => "reload".to_string(),
+        CtlCommand::Maintenance { mode: Switch::On } => "maintenance on".to_string(),
+        CtlCommand::Maintenance { mode: Switch::Off } => "maintenance off".to_string(),
+    }
+}
+
+fn parse_request(line: &str) -> anyhow::Result<CtlCommand> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    Ok(match words.as_slice() {
+        ["status"] => CtlCommand::Status,
+        ["keys"] => CtlCommand::Keys,
+        ["rate-limits"] => CtlCommand::RateLimits,
+        ["reset-rate-limit", ip] => CtlCommand::ResetRateLimit {
+            ip: ip.parse::<IpAddr>().with_context(|| format!("Invalid IP address {ip:?}"))?,
+        },
+        ["reload"] => CtlCommand::Reload,
+        ["maintenance", "on"] => CtlCommand::Maintenance { mode: Switch::On },
+        ["maintenance", "off"] => CtlCommand::Maintenance { mode: Switch::Off },
+        _ => bail!("Unknown control request {line:?}"),
+    })
+}
+
+pub(super) fn run_ctl(config_path: &Path, command: &CtlCommand) -> anyhow::Result<()> {
+    let config = ConfigServer::create_from_path(config_path)?;
+    let path = config
+        .control_socket
+        .ok_or_else(|| anyhow!("control_socket is not set in {config_path:?}"))?;
+    print!("{}", send_request(&path, command)?);
+    Ok(())
+}
+
+fn send_request(path: &Path, command: &CtlCommand) -> anyhow::Result<String> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Could not connect to control socket {path:?}"))?;
+    stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .with_context(|| "Could not set control socket read timeout")?;
+    stream
+        .write_all(format!("{}\n", request_line(command)).as_bytes())
+        .with_context(|| format!("Could not send request to {path:?}"))?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .with_context(|| format!("Could not read response from {path:?}"))?;
+
+    match response.strip_prefix("ok\n") {
+        Some(body) => Ok(body.to_string()),
+        None if response.is_empty() => bail!("The server closed {path:?} without a response"),
+        None => bail!("{}", response.trim_end().strip_prefix("error: ").unwrap_or(&response)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_control_socket, parse_request, request_line, send_request};
+    use crate::client::gen::Generator;
+    use crate::common::client_data::ClientData;
+    use crate::common::data_parser::DataParser;
+    use crate::server::config::{ConfigServer, CtlCommand, Switch};
+    use crate::server::Server;
+    use std::fs;
+    use std::net::{IpAddr, SocketAddr};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::thread;
+
+    fn create_server(dir: &Path, key: &str) -> Server {
+        fs::write(dir.join("alice.key"), key).unwrap();
+        Server::create(
+            ConfigServer {
+                config_dir: dir.to_path_buf(),
+                socket_dir: Some(dir.to_path_buf()),
+                control_socket: Some(dir.join("ctl").join("control.socket")),
+                ..Default::default()
+            },
+            Some("127.0.0.1:0".to_string()),
+        )
+        .unwrap()
+    }
+
+    fn receive(server: &mut Server, key: &str, counter: u128) -> anyhow::Result<()> {
+        let dst_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let plaintext = ClientData::create("default", false, None, dst_ip, counter, vec![])
+            .unwrap()
+            .serialize()
+            .unwrap();
+        let encoded = DataParser::create(key).unwrap().encode(&plaintext).unwrap();
+        server.client_recv_data[..encoded.len()].copy_from_slice(&encoded);
+        let src: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        server.run_loop_iteration(Ok((encoded.len(), src)))
+    }
+
+    #[test]
+    fn test_request_line_round_trip() {
+        for command in [
+            CtlCommand::Status,
+            CtlCommand::Keys,
+            CtlCommand::RateLimits,
+            CtlCommand::ResetRateLimit {
+                ip: "2001:db8::1".parse().unwrap(),
+            },
+            CtlCommand::Reload,
+            CtlCommand::Maintenance { mode: Switch::On },
+            CtlCommand::Maintenance { mode: Switch::Off },
+        ] {
+            assert_eq!(parse_request(&request_line(&command)).unwrap(), command);
+        }
+        for invalid in [
+            "",
+            "shutdown",
+            "keys now",
+            "maintenance maybe",
+            "reset-rate-limit nope",
+        ] {
+            assert!(parse_request(invalid).is_err(), "{invalid:?} must be rejected");
+        }
+    }
+
+    #[test]
+    fn test_control_socket_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let mut server = create_server(dir.path(), &key);
+        let path = dir.path().join("ctl").join("control.socket");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            send_request(&client_path, &CtlCommand::Maintenance { mode: Switch::On })
+        });
+        server.serve_control();
+        assert_eq!(client.join().unwrap().unwrap(), "maintenance mode is on\n");
+
+        let client = thread::spawn(move || send_request(&path, &CtlCommand::Reload));
+        fs::write(dir.path().join("broken.key"), "not a key").unwrap();
+        server.serve_control();
+        let err = client.join().unwrap().unwrap_err().to_string();
+        assert!(err.contains("load key"), "{err}");
+        assert_eq!(server.crypto_handlers.len(), 1);
+    }
+
+    #[test]
+    fn test_bind_control_socket_refuses_live_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.socket");
+        let _listener = bind_control_socket(&path).unwrap();
+        let err = bind_control_socket(&path).unwrap_err().to_string();
+        assert!(err.contains("Another server is already answering"), "{err}");
+    }
+
+    #[test]
+    fn test_maintenance_mode_rejects_packets() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let mut server = create_server(dir.path(), &key);
+
+        server.handle_control(&CtlCommand::Maintenance { mode: Switch::On }).unwrap();
+        let err = receive(&mut server, &key, crate::common::now_nanos().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Maintenance mode is on"), "{err}");
+        assert!(server.last_accepted.is_empty());
+
+        server.handle_control(&CtlCommand::Maintenance { mode: Switch::Off }).unwrap();
+        receive(&mut server, &key, crate::common::now_nanos().unwrap()).unwrap();
+        let keys = server.handle_control(&CtlCommand::Keys).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("  alice  versions 1,2  last accepted "), "{keys:?}");
+        assert!(keys[0].ends_with(" from 127.0.0.1"), "{keys:?}");
+    }
+
+    #[test]
+    fn test_rate_limits_and_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let mut server = create_server(dir.path(), &key);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        server.rate_limiter.check(ip, 2, 100).unwrap();
+        server.rate_limiter.check(ip, 2, 100).unwrap();
+
+        let lines = server.handle_control(&CtlCommand::RateLimits).unwrap();
+        assert!(lines[0].starts_with("limits: 2/s per source IP, 100/s global; "), "{lines:?}");
+        assert!(lines[0].ends_with("there are no longer bans"), "{lines:?}");
+        assert_eq!(lines[1], "global: 2 request(s) this second");
+        let (ip_line, ms) = lines[2].rsplit_once(' ').unwrap();
+        assert_eq!(ip_line, "192.0.2.1: 2 request(s) this second, blocked for another");
+        let ms: u64 = ms.strip_suffix("ms").unwrap().parse().unwrap();
+        assert!(ms <= 1000, "{ms}");
+
+        let reset = server.handle_control(&CtlCommand::ResetRateLimit { ip }).unwrap();
+        assert_eq!(reset, vec!["reset the rate-limit window of 192.0.2.1".to_string()]);
+        assert_eq!(server.handle_control(&CtlCommand::RateLimits).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reload_adds_and_removes_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let mut server = create_server(dir.path(), &key);
+
+        let bob = Generator::create().unwrap().gen().unwrap();
+        fs::write(dir.path().join("bob.key"), &bob).unwrap();
+        fs::remove_file(dir.path().join("alice.key")).unwrap();
+        let summary = server.handle_control(&CtlCommand::Reload).unwrap();
+        assert_eq!(summary, vec!["reloaded 1 key(s): 1 added, 1 removed".to_string()]);
+
+        let keys = server.handle_control(&CtlCommand::Keys).unwrap();
+        assert!(keys[0].contains("  bob  versions 1,2  nothing accepted since start"), "{keys:?}");
+        // The new key is seeded like at startup, so an old counter cannot be replayed against it.
+        assert!(receive(&mut server, &bob, 1).is_err());
+        receive(&mut server, &bob, crate::common::now_nanos().unwrap()).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
 use crate::common::client_data::ClientData;
-use crate::common::ipc::CommanderData;
+use crate::common::events::EventKind;
+use crate::common::ipc::{key_id_hex, CommanderData};
 use crate::common::logging::{error, info};
 use crate::common::now_nanos;
+use crate::common::protocol::{EXTENSIONS_SIZE, PLAINTEXT_SIZE};
 use crate::server::Server;
 use anyhow::{anyhow, Context};
 use std::io::Write;
@@ -13,35 +15,41 @@ impl Server {
     pub(super) fn validate_and_send_command(
         &mut self,
         key_id: [u8; crate::common::protocol::KEY_ID_SIZE],
-        plaintext_data: [u8; crate::common::protocol::PLAINTEXT_SIZE],
+        plaintext_data: &[u8],
         src_ip: IpAddr,
     ) -> anyhow::Result<()> {
-        let max_future_counter = now_nanos()?
+        let received_at = now_nanos()?;
+        let max_future_counter = received_at
             .saturating_add(u128::from(self.config.max_clock_skew_seconds) * 1_000_000_000);
 
+        let key = self.describe_key(&key_id);
+        let versions = self.config.protocol_versions_for(self.key_labels.get(&key_id));
         match ClientData::deserialize(plaintext_data)? {
+            client_data if !versions.contains(&client_data.version) => Err(anyhow!(
+                "Protocol version {} not accepted for key {key} - expected one of {versions:?}",
+                client_data.version
+            )),
             client_data if self.blocklist.is_counter_replayed(key_id, client_data.counter) => {
                 let server_counter = self.blocklist.get_counter(key_id);
                 Err(anyhow!(
-                    "Invalid counter for key {key_id:X?} - {} is on blocklist, expected > {server_counter:?}",
+                    "Invalid counter for key {key} - {} is on blocklist, expected > {server_counter:?}",
                     client_data.counter,
                 ))
             }
             client_data if client_data.counter > max_future_counter => Err(anyhow!(
-                "Future counter for key {key_id:X?} - {} exceeds now + skew ({max_future_counter}); not updating blocklist",
+                "Future counter for key {key} - {} exceeds now + skew ({max_future_counter}); not updating blocklist",
                 client_data.counter
             )),
             client_data if !self.config.ips.contains(&client_data.dst_ip) => {
                 let destination_ip = &client_data.dst_ip;
                 let ips = &self.config.ips;
-                Err(anyhow!("Invalid host IP for key {key_id:X?} - expected {ips:?} to contain {destination_ip}"))
+                Err(anyhow!("Invalid host IP for key {key} - expected {ips:?} to contain {destination_ip}"))
             }
             client_data if client_data.is_source_ip_invalid(src_ip) => {
                 let client_src_ip_str =
                     client_data.src_ip.map(|i| i.to_string()).unwrap_or("none".to_string());
                 Err(anyhow!(
-                    "Invalid source IP for key {:X?} - expected {client_src_ip_str}, actual {src_ip}",
-                    key_id
+                    "Invalid source IP for key {key} - expected {client_src_ip_str}, actual {src_ip}"
                 ))
             }
             client_data => {
@@ -49,16 +57,41 @@ impl Server {
                 let server_counter = self.blocklist.get_counter(key_id);
                 let client_counter = client_data.counter;
                 let ip = client_data.src_ip.unwrap_or(src_ip);
-                info(format!("Valid data for key {key_id:X?} - trying cmd {cmd} and counter {client_counter}|{server_counter:?} with {ip}"));
+                let params: Vec<String> =
+                    client_data.params.iter().map(|(name, value)| format!("{name}={value}")).collect();
+                info(format!("Valid data for key {key} - trying cmd {cmd} and counter {client_counter}|{server_counter:?} with {ip} and params {params:?}"));
                 // Persist the advanced counter before executing: if the blocklist can't be saved we
                 // must not run the command, otherwise a replay could re-trigger it after a restart.
                 self.update_block_list(key_id, client_data.counter)?;
-                self.send_command(CommanderData { cmd_hash: cmd, ip });
+                self.last_accepted.insert(key_id, (received_at, ip));
+                self.events.publish(EventKind::PacketAccepted {
+                    ip: ip.to_string(),
+                    key_id: key_id_hex(&key_id),
+                    cmd_hash: cmd,
+                });
+                self.send_command(CommanderData {
+                    cmd_hash: cmd,
+                    ip,
+                    key_id,
+                    dst_ip: client_data.dst_ip,
+                    counter: client_counter,
+                    received_at,
+                    key_label: self.key_labels.get(&key_id).cloned().unwrap_or_default(),
+                    extensions: extensions_area(plaintext_data),
+                });
                 Ok(())
             }
         }
     }
 
+    /// How log lines name a key: its label (the key file name without `.key`), then its id.
+    fn describe_key(&self, key_id: &[u8; crate::common::protocol::KEY_ID_SIZE]) -> String {
+        match self.key_labels.get(key_id) {
+            Some(label) => format!("{label} {key_id:X?}"),
+            None => format!("{key_id:X?}"),
+        }
+    }
+
     pub(super) fn update_block_list(
         &mut self,
         key_id: [u8; crate::common::protocol::KEY_ID_SIZE],
@@ -66,6 +99,9 @@ impl Server {
     ) -> anyhow::Result<()> {
         let previous = self.blocklist.get_counter(key_id).copied();
         self.blocklist.upsert(key_id, counter);
+        if self.replaying {
+            return Ok(());
+        }
         if let Err(e) = self.blocklist.save() {
             // Persist failed: roll the in-memory advance back so this counter is not silently
             // consumed. The caller aborts before executing, so the client can retry the same
@@ -80,6 +116,9 @@ impl Server {
     }
 
     pub(super) fn send_command(&self, data: CommanderData) {
+        if self.replaying {
+            return;
+        }
         match self.write_to_socket(data) {
             Ok(_) => info("Successfully sent data to commander"),
             Err(e) => error(format!(
@@ -93,7 +132,7 @@ impl Server {
         let mut stream = UnixStream::connect(&self.socket_path)
             .with_context(|| format!("Could not connect to socket {:?}", self.socket_path))?;
         // Bound the write so a hung commander can't stall the server's single-threaded loop. The
-        // payload is tiny (24 bytes), so a second is generous for a healthy commander.
+        // payload is tiny (208 bytes), so a second is generous for a healthy commander.
         stream
             .set_write_timeout(Some(Duration::from_secs(1)))
             .with_context(|| format!("Could not set write timeout for {:?}", self.socket_path))?;
@@ -109,3 +148,12 @@ impl Server {
         Ok(())
     }
 }
+
+/// The v2 extension area as the client sent it, or all zeros (no entries) for a v1 plaintext.
+/// Forwarded raw: only the commander knows which parameters a command accepts.
+fn extensions_area(plaintext: &[u8]) -> [u8; EXTENSIONS_SIZE] {
+    let mut area = [0u8; EXTENSIONS_SIZE];
+    let sent = plaintext.get(PLAINTEXT_SIZE..).unwrap_or_default();
+    area.iter_mut().zip(sent).for_each(|(to, from)| *to = *from);
+    area
+}
@@ -0,0 +1,307 @@
+//! `ruroco-server inspect --key-file <key> [packet]`: decrypts a single packet (hex or base64, or
+//! read from stdin) with one key, prints what the client put in it and says which of the server's
+//! checks it would pass right now. Read-only: the blocklist is loaded, never saved.
+
+use crate::common::client_data::ClientData;
+use crate::common::crypto_handler::CryptoHandler;
+use crate::common::data_parser::DataParser;
+use crate::common::ipc::key_id_hex;
+use crate::common::protocol::{MSG_SIZE, MSG_SIZE_V2};
+use crate::common::{format_nanos, now_nanos};
+use crate::server::config::{ConfigServer, InspectCommand};
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose, Engine};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub(super) fn run_inspect(config_path: &Path, inspect: InspectCommand) -> anyhow::Result<()> {
+    let config = ConfigServer::create_from_path(config_path)?;
+    let key: Zeroizing<String> = fs::read_to_string(&inspect.key_file)
+        .with_context(|| format!("Could not read key file {:?}", inspect.key_file))?
+        .into();
+    let packet = match inspect.packet {
+        Some(packet) => packet,
+        None => {
+            let mut packet = String::new();
+            std::io::stdin()
+                .read_to_string(&mut packet)
+                .with_context(|| "Could not read packet from stdin")?;
+            packet
+        }
+    };
+
+    // The server labels keys by their file name, so this label finds the same key_protocol_versions
+    // entry as long as the key file is the installed one.
+    let label = inspect.key_file.file_stem().map(|stem| stem.to_string_lossy().to_string());
+    for line in inspect_packet(&config, label.as_ref(), &key, &packet)? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Parse a packet given as hex (188 digits for v1, 316 for v2) or standard base64 (128 or 212
+/// characters).
+fn parse_packet(input: &str) -> anyhow::Result<Vec<u8>> {
+    let input = input.trim();
+    let is_hex = [MSG_SIZE * 2, MSG_SIZE_V2 * 2].contains(&input.len())
+        && input.bytes().all(|b| b.is_ascii_hexdigit());
+    let bytes = if is_hex {
+        (0..input.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&input[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .with_context(|| "Could not decode hex packet")?
+    } else {
+        general_purpose::STANDARD
+            .decode(input)
+            .with_context(|| "Packet is neither hex nor base64")?
+    };
+    let len = bytes.len();
+    if len != MSG_SIZE && len != MSG_SIZE_V2 {
+        bail!("Packet is {len} bytes, expected {MSG_SIZE} or {MSG_SIZE_V2}");
+    }
+    Ok(bytes)
+}
+
+/// Decode, decrypt and deserialize `packet` with `key`, then evaluate the server's protocol
+/// version, replay, clock skew and destination IP checks against `config`, in the order
+/// `validate_and_send_command` runs them. `label` picks the key's `key_protocol_versions` entry.
+/// The strict source IP check is reported but not evaluated: it needs the UDP source address.
+pub(super) fn inspect_packet(
+    config: &ConfigServer,
+    label: Option<&String>,
+    key: &str,
+    packet: &str,
+) -> anyhow::Result<Vec<String>> {
+    let handler = CryptoHandler::create(key)?;
+    let data = parse_packet(packet)?;
+    let (key_id, ciphertext) = DataParser::decode(&data)?;
+    if *key_id != handler.id {
+        bail!(
+            "Packet is for key id {}, but the key file has id {}",
+            key_id_hex(key_id),
+            key_id_hex(&handler.id)
+        );
+    }
+    let plaintext = handler.decrypt(ciphertext)?;
+    let client_data = ClientData::deserialize(&plaintext)?;
+    let params: Vec<String> =
+        client_data.params.iter().map(|(name, value)| format!("{name}={value}")).collect();
+
+    let mut lines = vec![
+        format!("key id:           {}", key_id_hex(key_id)),
+        format!("protocol version: {}", client_data.version),
+        format!("cmd_hash:         {}", client_data.cmd_hash),
+        format!(
+            "counter:          {} ({})",
+            client_data.counter,
+            format_nanos(client_data.counter)
+        ),
+        format!("strict:           {}", client_data.strict),
+        format!(
+            "src ip:           {}",
+            client_data.src_ip.map(|i| i.to_string()).unwrap_or("none".to_string())
+        ),
+        format!("dst ip:           {}", client_data.dst_ip),
+        format!(
+            "params:           {}",
+            if params.is_empty() {
+                "none".to_string()
+            } else {
+                params.join(" ")
+            }
+        ),
+        "checks:".to_string(),
+    ];
+
+    let versions = config.protocol_versions_for(label);
+    lines.push(if versions.contains(&client_data.version) {
+        format!("  version:  pass - {} is in {versions:?}", client_data.version)
+    } else {
+        format!("  version:  fail - {} is not in {versions:?}", client_data.version)
+    });
+
+    let blocklist = config.load_blocklist()?;
+    lines.push(match blocklist.get_counter(*key_id) {
+        // The live server seeds unknown keys with its start time, which is not knowable here.
+        None => "  replay:   unknown - no blocklist entry for this key yet; the server accepts \
+                 counters newer than its start time"
+            .to_string(),
+        Some(stored) if blocklist.is_counter_replayed(*key_id, client_data.counter) => {
+            format!("  replay:   fail - counter is not above the blocklist's {stored}")
+        }
+        Some(stored) => format!("  replay:   pass - counter is above the blocklist's {stored}"),
+    });
+
+    let max_future_counter =
+        now_nanos()?.saturating_add(u128::from(config.max_clock_skew_seconds) * 1_000_000_000);
+    lines.push(if client_data.counter > max_future_counter {
+        format!(
+            "  skew:     fail - counter is more than {}s in the future",
+            config.max_clock_skew_seconds
+        )
+    } else {
+        format!("  skew:     pass - counter is within now + {}s", config.max_clock_skew_seconds)
+    });
+
+    let ips = &config.ips;
+    lines.push(if ips.contains(&client_data.dst_ip) {
+        format!("  dst ip:   pass - {} is in {ips:?}", client_data.dst_ip)
+    } else {
+        format!("  dst ip:   fail - {} is not in {ips:?}", client_data.dst_ip)
+    });
+
+    lines.push(match (client_data.strict, client_data.src_ip) {
+        (true, Some(ip)) => format!("  src ip:   checked on receipt - the sender must be {ip}"),
+        _ => "  src ip:   not checked - packet is not strict or carries no source IP".to_string(),
+    });
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect_packet, parse_packet};
+    use crate::client::gen::Generator;
+    use crate::common::client_data::ClientData;
+    use crate::common::data_parser::DataParser;
+    use crate::common::now_nanos;
+    use crate::common::protocol::{MSG_SIZE, MSG_SIZE_V2};
+    use crate::server::blocklist::Blocklist;
+    use crate::server::config::ConfigServer;
+    use base64::{engine::general_purpose, Engine};
+    use std::net::IpAddr;
+
+    fn config(dir: &std::path::Path) -> ConfigServer {
+        ConfigServer {
+            config_dir: dir.to_path_buf(),
+            ips: vec!["127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    fn packet(key: &str, counter: u128, dst_ip: &str) -> Vec<u8> {
+        packet_with_params(key, counter, dst_ip, vec![])
+    }
+
+    fn packet_with_params(
+        key: &str,
+        counter: u128,
+        dst_ip: &str,
+        params: Vec<(String, String)>,
+    ) -> Vec<u8> {
+        let dst_ip: IpAddr = dst_ip.parse().unwrap();
+        let plaintext = ClientData::create("default", true, Some(dst_ip), dst_ip, counter, params)
+            .unwrap()
+            .serialize()
+            .unwrap();
+        DataParser::create(key).unwrap().encode(&plaintext).unwrap()
+    }
+
+    fn to_hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_parse_packet_hex_and_base64() {
+        for size in [MSG_SIZE, MSG_SIZE_V2] {
+            let data = vec![7u8; size];
+            assert_eq!(parse_packet(&format!(" {}\n", to_hex(&data))).unwrap(), data);
+            assert_eq!(parse_packet(&general_purpose::STANDARD.encode(&data)).unwrap(), data);
+        }
+        assert_eq!(
+            parse_packet("AAAA").unwrap_err().to_string(),
+            format!("Packet is 3 bytes, expected {MSG_SIZE} or {MSG_SIZE_V2}")
+        );
+        assert!(parse_packet("not a packet").is_err());
+    }
+
+    #[test]
+    fn test_inspect_passing_packet() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let counter = now_nanos().unwrap();
+        let data = packet(&key, counter, "127.0.0.1");
+
+        let mut blocklist = Blocklist::create(dir.path()).unwrap();
+        blocklist.upsert(data[..8].try_into().unwrap(), counter - 1);
+        blocklist.save().unwrap();
+
+        let lines = inspect_packet(&config(dir.path()), None, &key, &to_hex(&data)).unwrap();
+        let report = lines.join("\n");
+        assert!(report.contains("protocol version: 1"), "{report}");
+        assert!(report.contains(&format!("counter:          {counter} (")), "{report}");
+        assert!(report.contains("strict:           true"), "{report}");
+        assert!(report.contains("params:           none"), "{report}");
+        assert!(report.contains("version:  pass - 1 is in [1, 2]"), "{report}");
+        assert!(report.contains("replay:   pass"), "{report}");
+        assert!(report.contains("skew:     pass"), "{report}");
+        assert!(report.contains("dst ip:   pass"), "{report}");
+        assert!(report.contains("the sender must be 127.0.0.1"), "{report}");
+    }
+
+    #[test]
+    fn test_inspect_failing_packet() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let counter = now_nanos().unwrap() + 7_200 * 1_000_000_000;
+        let data = packet(&key, counter, "10.0.0.1");
+
+        let mut blocklist = Blocklist::create(dir.path()).unwrap();
+        blocklist.upsert(data[..8].try_into().unwrap(), counter);
+        blocklist.save().unwrap();
+
+        let encoded = general_purpose::STANDARD.encode(&data);
+        let report = inspect_packet(&config(dir.path()), None, &key, &encoded).unwrap().join("\n");
+        assert!(report.contains("replay:   fail"), "{report}");
+        assert!(report.contains("skew:     fail"), "{report}");
+        assert!(report.contains("dst ip:   fail"), "{report}");
+    }
+
+    #[test]
+    fn test_inspect_unknown_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let other = Generator::create().unwrap().gen().unwrap();
+        let data = packet(&other, 1, "127.0.0.1");
+
+        let err = inspect_packet(&config(dir.path()), None, &key, &to_hex(&data)).unwrap_err();
+        assert!(err.to_string().contains("but the key file has id"), "{err}");
+    }
+
+    #[test]
+    fn test_inspect_without_blocklist_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let data = packet(&key, 1, "127.0.0.1");
+
+        let report =
+            inspect_packet(&config(dir.path()), None, &key, &to_hex(&data)).unwrap().join("\n");
+        assert!(report.contains("replay:   unknown"), "{report}");
+        // Inspection must never create or modify the blocklist.
+        assert!(!Blocklist::get_blocklist_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn test_inspect_v2_packet_with_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        let params = vec![("hours".to_string(), "8".to_string())];
+        let data = packet_with_params(&key, 1, "127.0.0.1", params);
+        assert_eq!(data.len(), MSG_SIZE_V2);
+
+        let report =
+            inspect_packet(&config(dir.path()), None, &key, &to_hex(&data)).unwrap().join("\n");
+        assert!(report.contains("protocol version: 2"), "{report}");
+        assert!(report.contains("params:           hours=8"), "{report}");
+
+        let mut config = config(dir.path());
+        config.key_protocol_versions.insert("laptop".to_string(), vec![1]);
+        let report = inspect_packet(&config, Some(&"laptop".to_string()), &key, &to_hex(&data))
+            .unwrap()
+            .join("\n");
+        assert!(report.contains("version:  fail - 2 is not in [1]"), "{report}");
+    }
+}
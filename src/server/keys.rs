@@ -12,6 +12,9 @@ use std::fs::ReadDir;
 use std::path::PathBuf;
 use zeroize::Zeroizing;
 
+/// Key id to label (the key file name without `.key`).
+pub(crate) type KeyLabels = HashMap<[u8; KEY_ID_SIZE], String>;
+
 impl ConfigServer {
     pub(crate) fn create_blocklist(&self) -> anyhow::Result<Blocklist> {
         // Blocklist lives in `blocklist_dir` when set (a writable StateDirectory), otherwise in
@@ -19,27 +22,36 @@ impl ConfigServer {
         Blocklist::create(self.blocklist_dir.as_ref().unwrap_or(&self.config_dir))
     }
 
+    pub(crate) fn load_blocklist(&self) -> anyhow::Result<Blocklist> {
+        Blocklist::load(self.blocklist_dir.as_ref().unwrap_or(&self.config_dir))
+    }
+
+    /// Load every `.key` file in `config_dir`. Also returns each key's label: its file name without
+    /// the `.key` extension.
     pub(crate) fn create_crypto_handlers(
         &self,
-    ) -> anyhow::Result<HashMap<[u8; KEY_ID_SIZE], CryptoHandler>> {
+    ) -> anyhow::Result<(HashMap<[u8; KEY_ID_SIZE], CryptoHandler>, KeyLabels)> {
         let key_paths = self.get_key_paths()?;
         info(format!("Creating server, loading keys from {key_paths:?}, using {} ...", version()));
 
         let mut handlers = HashMap::with_capacity(key_paths.len());
+        let mut labels = HashMap::with_capacity(key_paths.len());
         for path in &key_paths {
             let content: Zeroizing<String> = fs::read_to_string(path)
                 .with_context(|| format!("Could not read key file {}", path.display()))?
                 .into();
             let handler = CryptoHandler::create(&content)
                 .with_context(|| format!("load key {}", path.display()))?;
-            info(format!("loading key with id {:X?}", &handler.id));
+            let label = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            info(format!("loading key {label} with id {:X?}", &handler.id));
 
+            labels.insert(handler.id, label);
             if handlers.insert(handler.id, handler).is_some() {
                 bail!("Duplicate key files detected; refusing to start");
             }
         }
 
-        Ok(handlers)
+        Ok((handlers, labels))
     }
 
     pub(crate) fn get_commander_unix_socket_path(&self) -> PathBuf {
@@ -54,6 +66,15 @@ impl ConfigServer {
     }
 
     pub(crate) fn get_key_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let key_files = self.find_key_paths()?;
+        match key_files.len() {
+            0 => Err(anyhow!("Could not find any .key files in {:?}", self.resolve_config_dir())),
+            _ => Ok(key_files),
+        }
+    }
+
+    /// Every `.key` file in `config_dir`, sorted by path; empty if there are none.
+    pub(crate) fn find_key_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
         let config_dir = self.resolve_config_dir();
 
         let entries: ReadDir = match fs::read_dir(&config_dir) {
@@ -61,16 +82,13 @@ impl ConfigServer {
             Err(e) => bail!("Error reading directory {config_dir:?}: {e}"),
         };
 
-        let key_files: Vec<PathBuf> = entries
+        let mut key_files: Vec<PathBuf> = entries
             .flatten()
             .map(|entry| entry.path())
             .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == "key"))
             .collect();
-
-        match key_files.len() {
-            0 => Err(anyhow!("Could not find any .key files in {config_dir:?}")),
-            _ => Ok(key_files),
-        }
+        key_files.sort();
+        Ok(key_files)
     }
 }
 
@@ -137,6 +155,24 @@ mod tests {
         assert!(err.contains("Duplicate key files detected"), "unexpected: {err}");
     }
 
+    #[cfg(feature = "with-client")]
+    #[test]
+    fn test_create_crypto_handlers_labels_keys_by_file_stem() {
+        use crate::common::crypto_handler::CryptoHandler;
+
+        let dir = tempfile::tempdir().unwrap();
+        let alice = CryptoHandler::gen_key().unwrap();
+        std::fs::write(dir.path().join("alice.key"), &alice).unwrap();
+        let config = ConfigServer {
+            config_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let (handlers, labels) = config.create_crypto_handlers().unwrap();
+        let id = CryptoHandler::create(&alice).unwrap().id;
+        assert!(handlers.contains_key(&id));
+        assert_eq!(labels.get(&id).map(String::as_str), Some("alice"));
+    }
+
     #[test]
     fn test_create_blocklist() {
         let dir = tempfile::tempdir().unwrap();
@@ -4,32 +4,58 @@
 
 use crate::common::crypto_handler::CryptoHandler;
 use crate::common::data_parser::DataParser;
+use crate::common::events::{EventKind, EventPublisher, EventSource};
 use crate::common::logging::{debug, info};
-use crate::common::protocol::{KEY_ID_SIZE, MSG_SIZE, PLAINTEXT_SIZE};
+use crate::common::notify::Notifier;
+use crate::common::protocol::{KEY_ID_SIZE, MSG_SIZE, MSG_SIZE_V2};
+use crate::common::signal::{install_signal_handlers, shutdown_requested};
 use crate::common::{normalize_ip, now_nanos};
 use crate::server::blocklist::Blocklist;
-use crate::server::config::{CliServer, ConfigServer};
+use crate::server::config::{CliServer, ConfigServer, ServerCommand};
+use crate::server::control::{bind_control_socket, run_ctl};
 use crate::server::error_throttle::ErrorThrottle;
+use crate::server::inspect::run_inspect;
+use crate::server::keys::KeyLabels;
+use crate::server::manage_keys::run_keys;
 use crate::server::rate_limiter::RateLimiter;
-use crate::server::signal::{install_signal_handlers, shutdown_requested};
+use crate::server::recorder::Recorder;
+use crate::server::replay::run_replay;
 use anyhow::{anyhow, bail, Context};
 use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+pub(super) const RECV_BUFFER_SIZE: usize = MSG_SIZE_V2 + 1;
+
 #[derive(Debug)]
 pub struct Server {
     // `pub(super)` so the `impl Server` in the sibling `handler` module can reach these.
     pub(super) config: ConfigServer,
-    crypto_handlers: HashMap<[u8; KEY_ID_SIZE], CryptoHandler>,
-    socket: UdpSocket,
-    client_recv_data: [u8; MSG_SIZE],
+    pub(super) crypto_handlers: HashMap<[u8; KEY_ID_SIZE], CryptoHandler>,
+    pub(super) key_labels: KeyLabels,
+    pub(super) socket: UdpSocket,
+    /// One byte larger than the largest valid datagram, so an oversized one shows up as a wrong
+    /// read count instead of being silently cut to a valid size.
+    pub(super) client_recv_data: [u8; RECV_BUFFER_SIZE],
     pub(super) socket_path: PathBuf,
     pub(super) blocklist: Blocklist,
-    rate_limiter: RateLimiter,
-    error_throttle: ErrorThrottle,
+    pub(super) rate_limiter: RateLimiter,
+    pub(super) error_throttle: ErrorThrottle,
+    pub(super) events: EventPublisher,
+    pub(super) recorder: Option<Recorder>,
+    pub(super) notifier: Notifier,
+    pub(super) control: Option<UnixListener>,
+    /// Receive time (nanoseconds) and client IP of the last packet accepted per key, since start.
+    pub(super) last_accepted: HashMap<[u8; KEY_ID_SIZE], (u128, IpAddr)>,
+    /// Toggled over the control socket: while set, every packet is rejected before decryption.
+    pub(super) maintenance: bool,
+    /// Set by `replay`: packets are judged exactly as live, but rate limits (which depend on
+    /// wall-clock arrival) are skipped, the blocklist is never saved and nothing reaches the
+    /// commander.
+    pub(super) replaying: bool,
 }
 
 impl Server {
@@ -38,7 +64,7 @@ impl Server {
     }
 
     pub fn create(config: ConfigServer, address: Option<String>) -> anyhow::Result<Server> {
-        let crypto_handlers = config.create_crypto_handlers()?;
+        let (crypto_handlers, key_labels) = config.create_crypto_handlers()?;
         let mut blocklist = config.create_blocklist()?;
         let floor = now_nanos()?;
         for key_id in crypto_handlers.keys() {
@@ -47,13 +73,25 @@ impl Server {
         blocklist.save()?;
         Ok(Server {
             crypto_handlers,
+            key_labels,
             socket: config.create_server_udp_socket(address)?,
-            client_recv_data: [0u8; MSG_SIZE],
+            control: config.control_socket.as_deref().map(bind_control_socket).transpose()?,
+            client_recv_data: [0u8; RECV_BUFFER_SIZE],
             socket_path: config.get_commander_unix_socket_path(),
+            events: EventPublisher::create(
+                config.publish_events,
+                config.socket_dir.as_ref().unwrap_or(&config.config_dir),
+                EventSource::Server,
+            )?,
             blocklist,
             rate_limiter: RateLimiter::new(),
             config,
             error_throttle: ErrorThrottle::default(),
+            recorder: None,
+            notifier: Notifier::from_env(),
+            last_accepted: HashMap::new(),
+            maintenance: false,
+            replaying: false,
         })
     }
 
@@ -63,12 +101,25 @@ impl Server {
             .set_read_timeout(Some(Duration::from_secs(1)))
             .with_context(|| "Could not set socket read timeout")?;
         install_signal_handlers();
+        self.notifier.ready(&self.systemd_status());
         loop {
+            // The read timeout bounds each iteration to about a second, so this keeps pinging
+            // while no packets arrive.
+            self.notifier.watchdog();
             if shutdown_requested() {
                 info("Shutdown requested, stopping server loop");
+                self.notifier.stopping();
                 break;
             }
+            if !self.wait_for_packet()? {
+                continue;
+            }
             let data = self.socket.recv_from(&mut self.client_recv_data);
+            if let (Some(recorder), Ok((count, src))) = (&mut self.recorder, &data) {
+                if let Err(e) = recorder.record(*src, &self.client_recv_data[..*count]) {
+                    self.error_throttle.log(&e);
+                }
+            }
             if let Err(e) = &data {
                 match e.kind() {
                     // No packet within the read timeout, or a signal interrupted the syscall
@@ -82,34 +133,44 @@ impl Server {
                     _ => bail!("Could not receive bytes from socket, giving up: {e}"),
                 }
             }
+            let src_ip = data.as_ref().ok().map(|(_, src)| normalize_ip(src.ip()).to_string());
             if let Err(e) = self.run_loop_iteration(data) {
                 self.error_throttle.log(&e);
+                self.events.publish(EventKind::PacketRejected {
+                    ip: src_ip,
+                    reason: e.to_string(),
+                });
             }
         }
         Ok(())
     }
 
-    fn run_loop_iteration(
+    pub(super) fn run_loop_iteration(
         &mut self,
         data: std::io::Result<(usize, SocketAddr)>,
     ) -> anyhow::Result<()> {
         match data {
-            Ok((count, src)) if count != MSG_SIZE => {
-                Err(anyhow!("Invalid read count {count}, expected {MSG_SIZE} from {src}"))
-            }
+            Ok((count, src)) if count != MSG_SIZE && count != MSG_SIZE_V2 => Err(anyhow!(
+                "Invalid read count {count}, expected {MSG_SIZE} or {MSG_SIZE_V2} from {src}"
+            )),
             Ok((count, src)) => {
                 debug(format!("Successfully received {count} bytes from {src}"));
                 let src_ip = normalize_ip(src.ip());
                 self.check_rate_limit(src_ip)?;
-                let received_data = self.client_recv_data;
-                let (key_id, plaintext) = self.decrypt(&received_data)?;
-                self.validate_and_send_command(key_id, plaintext, src_ip)
+                if self.maintenance {
+                    bail!("Maintenance mode is on, rejecting packet from {src}");
+                }
+                let (key_id, plaintext) = self.decrypt(&self.client_recv_data[..count])?;
+                self.validate_and_send_command(key_id, &plaintext, src_ip)
             }
             Err(e) => bail!("Could not receive bytes from socket: {e}"),
         }
     }
 
     fn check_rate_limit(&mut self, src_ip: IpAddr) -> anyhow::Result<()> {
+        if self.replaying {
+            return Ok(());
+        }
         self.rate_limiter.check(
             src_ip,
             self.config.max_requests_per_second,
@@ -117,10 +178,7 @@ impl Server {
         )
     }
 
-    fn decrypt(
-        &self,
-        data: &[u8; MSG_SIZE],
-    ) -> anyhow::Result<([u8; KEY_ID_SIZE], [u8; PLAINTEXT_SIZE])> {
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<([u8; KEY_ID_SIZE], Vec<u8>)> {
         let (key_id, encrypted_data) = DataParser::decode(data)?;
         let plaintext = self
             .crypto_handlers
@@ -132,16 +190,32 @@ impl Server {
 }
 
 pub fn run_server(server: CliServer) -> anyhow::Result<()> {
-    Server::create_from_path(&server.config)?.run()
+    match server.command {
+        Some(ServerCommand::Replay(replay)) => run_replay(&server.config, replay),
+        Some(ServerCommand::Inspect(inspect)) => run_inspect(&server.config, inspect),
+        Some(ServerCommand::Ctl(ctl)) => run_ctl(&server.config, &ctl),
+        Some(ServerCommand::Keys(keys)) => run_keys(&server.config, keys),
+        None => {
+            let mut instance = Server::create_from_path(&server.config)?;
+            if let Some(path) = &server.record {
+                info(format!("Recording received datagrams to {path:?}"));
+                instance.recorder = Some(Recorder::open(path)?);
+            }
+            instance.drop_privileges()?;
+            instance.apply_sandbox();
+            instance.run()
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::client::gen::Generator;
     use crate::common::data_parser::DataParser;
-    use crate::common::protocol::MSG_SIZE;
+    use crate::common::protocol::{MSG_SIZE, MSG_SIZE_V2};
     use crate::server::config::{CliServer, ConfigServer};
     use crate::server::get_random_range;
+    use crate::server::listener::RECV_BUFFER_SIZE;
     use crate::server::Server;
     use clap::error::ErrorKind::DisplayHelp;
     use clap::Parser;
@@ -257,7 +331,9 @@ mod tests {
 
         assert_eq!(
             server.run_loop_iteration(success_data).unwrap_err().to_string(),
-            format!("Invalid read count 0, expected {MSG_SIZE} from 127.0.0.1:8080")
+            format!(
+                "Invalid read count 0, expected {MSG_SIZE} or {MSG_SIZE_V2} from 127.0.0.1:8080"
+            )
         );
     }
 
@@ -324,6 +400,8 @@ mod tests {
     fn test_run_server_invalid_path() {
         let server = CliServer {
             config: PathBuf::from("/nonexistent/ruroco_test_path.toml"),
+            record: None,
+            command: None,
         };
         assert!(super::run_server(server).is_err());
     }
@@ -349,15 +427,79 @@ mod tests {
         src_ip: Option<IpAddr>,
         dst_ip: IpAddr,
         counter: u128,
-    ) -> [u8; MSG_SIZE] {
+    ) -> [u8; RECV_BUFFER_SIZE] {
         use crate::common::client_data::ClientData;
 
         let parser = DataParser::create(key).unwrap();
-        let plaintext =
-            ClientData::create(cmd, strict, src_ip, dst_ip, counter).unwrap().serialize().unwrap();
+        let plaintext = ClientData::create(cmd, strict, src_ip, dst_ip, counter, vec![])
+            .unwrap()
+            .serialize()
+            .unwrap();
         let encoded = parser.encode(&plaintext).unwrap();
-        server.client_recv_data = encoded;
-        encoded
+        server.client_recv_data[..encoded.len()].copy_from_slice(&encoded);
+        server.client_recv_data
+    }
+
+    #[test]
+    fn test_v2_packet_forwards_params() {
+        use crate::common::client_data::ClientData;
+        use crate::common::ipc::{CommanderData, CMDR_DATA_SIZE};
+        use crate::common::protocol::params::decode_params;
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let (temp_dir, mut server, key) = create_server_with_key().unwrap();
+        server.socket_path = temp_dir.path().join("ruroco.socket");
+        let commander = UnixListener::bind(&server.socket_path).unwrap();
+
+        let localhost = "127.0.0.1".parse().unwrap();
+        let params = vec![("hours".to_string(), "8".to_string())];
+        let counter = crate::common::now_nanos().unwrap();
+        let plaintext = ClientData::create("open", false, None, localhost, counter, params.clone())
+            .unwrap()
+            .serialize()
+            .unwrap();
+        let encoded = DataParser::create(&key).unwrap().encode(&plaintext).unwrap();
+        assert_eq!(encoded.len(), MSG_SIZE_V2);
+        server.client_recv_data[..MSG_SIZE_V2].copy_from_slice(&encoded);
+
+        let src = SocketAddr::new(localhost, 8080);
+        server.run_loop_iteration(Ok((MSG_SIZE_V2, src))).unwrap();
+        let mut frame = [0u8; CMDR_DATA_SIZE];
+        commander.accept().unwrap().0.read_exact(&mut frame).unwrap();
+        let data: CommanderData = frame.into();
+        assert_eq!(data.counter, counter);
+        assert_eq!(decode_params(&data.extensions).unwrap(), params);
+
+        // A datagram longer than the largest valid one is not cut down to it.
+        let err = server.run_loop_iteration(Ok((RECV_BUFFER_SIZE, src))).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid read count 159"), "{err}");
+    }
+
+    #[test]
+    fn test_protocol_version_not_accepted_for_key() {
+        use crate::common::client_data::ClientData;
+
+        let (_temp_dir, mut server, key) = create_server_with_key().unwrap();
+        server.config.protocol_versions = vec![2];
+        let localhost = "127.0.0.1".parse().unwrap();
+        let counter = crate::common::now_nanos().unwrap();
+
+        load_encrypted_packet(&mut server, &key, "default", false, None, localhost, counter);
+        let err = server.run_loop_iteration(localhost_src(8080)).unwrap_err().to_string();
+        assert!(err.contains("Protocol version 1 not accepted for key"), "{err}");
+
+        // The rejected packet did not use up its counter.
+        let plaintext = ClientData::create("default", false, None, localhost, counter, vec![])
+            .unwrap()
+            .with_version(2)
+            .unwrap()
+            .serialize()
+            .unwrap();
+        let encoded = DataParser::create(&key).unwrap().encode(&plaintext).unwrap();
+        server.client_recv_data[..MSG_SIZE_V2].copy_from_slice(&encoded);
+        let src = SocketAddr::new(localhost, 8080);
+        assert!(server.run_loop_iteration(Ok((MSG_SIZE_V2, src))).is_ok());
     }
 
     #[test]
@@ -441,11 +583,9 @@ mod tests {
             "10.0.0.1".parse().unwrap(), // not in server's ips list
             std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos(),
         );
-        assert!(server
-            .run_loop_iteration(localhost_src(8080))
-            .unwrap_err()
-            .to_string()
-            .contains("Invalid host IP"));
+        let err = server.run_loop_iteration(localhost_src(8080)).unwrap_err().to_string();
+        // Named by the key file's label as well as by id.
+        assert!(err.contains("Invalid host IP for key test ["), "unexpected: {err}");
     }
 
     #[test]
@@ -486,7 +626,7 @@ mod tests {
             Commander::create(
                 ConfigCommander {
                     config_dir: commander_dir,
-                    allow_non_routable_ips: true,
+                    allow_cidrs: vec!["127.0.0.0/8".parse().unwrap()],
                     ..Default::default()
                 },
                 ConfigCommands::from_map(cmds),
@@ -530,6 +670,7 @@ mod tests {
     #[test]
     fn test_write_to_socket_no_listener() {
         use crate::common::ipc::CommanderData;
+        use crate::common::protocol::EXTENSIONS_SIZE;
 
         let dir = tempfile::tempdir().unwrap();
         let (_temp_dir, mut server, _key) = create_server_with_key().unwrap();
@@ -538,7 +679,13 @@ mod tests {
         assert!(server
             .write_to_socket(CommanderData {
                 cmd_hash: 42,
-                ip: "127.0.0.1".parse().unwrap()
+                ip: "127.0.0.1".parse().unwrap(),
+                key_id: [0u8; 8],
+                dst_ip: "127.0.0.1".parse().unwrap(),
+                counter: 0,
+                received_at: 0,
+                key_label: String::new(),
+                extensions: [0u8; EXTENSIONS_SIZE],
             })
             .unwrap_err()
             .to_string()
@@ -548,6 +695,7 @@ mod tests {
     #[test]
     fn test_send_command_logs_error_on_missing_socket() {
         use crate::common::ipc::CommanderData;
+        use crate::common::protocol::EXTENSIONS_SIZE;
 
         let dir = tempfile::tempdir().unwrap();
         let (_temp_dir, mut server, _key) = create_server_with_key().unwrap();
@@ -556,6 +704,12 @@ mod tests {
         server.send_command(CommanderData {
             cmd_hash: 42,
             ip: "127.0.0.1".parse().unwrap(),
+            key_id: [0u8; 8],
+            dst_ip: "127.0.0.1".parse().unwrap(),
+            counter: 0,
+            received_at: 0,
+            key_label: String::new(),
+            extensions: [0u8; EXTENSIONS_SIZE],
         });
     }
 
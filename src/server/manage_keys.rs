@@ -0,0 +1,325 @@
+//! `ruroco-server keys add|list|remove|rotate|show-id`: manages the `.key` files in `config_dir`.
+//! Keys are written `root`-owned, readable by the server's group (mode `0640`), and a key whose id
+//! is already used by another key file is refused up front, as `create_crypto_handlers` would
+//! refuse to start with it. A running server only sees the change after `ctl reload` or a restart.
+
+use crate::common::crypto_handler::CryptoHandler;
+use crate::common::{change_file_ownership, now_nanos};
+use crate::server::config::{ConfigServer, KeysCommand};
+use anyhow::{anyhow, bail, Context};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const KEY_FILE_MODE: u32 = 0o640;
+const KEY_FILE_USER: &str = "root";
+/// Group of new key files when `group` is not set in `config.toml`; `ruroco.service` runs the
+/// server with it.
+const DEFAULT_KEY_FILE_GROUP: &str = "ruroco";
+const RELOAD_HINT: &str = "run `ruroco-server ctl reload` or restart the server to apply this";
+
+/// Owner of written key files, as names; an empty name leaves that part unchanged.
+struct Ownership<'a> {
+    user: &'a str,
+    group: &'a str,
+}
+
+pub(super) fn run_keys(config_path: &Path, command: KeysCommand) -> anyhow::Result<()> {
+    let config = ConfigServer::create_from_path(config_path)?;
+    let ownership = Ownership {
+        user: KEY_FILE_USER,
+        group: config.group.as_deref().unwrap_or(DEFAULT_KEY_FILE_GROUP),
+    };
+    for line in manage_keys(&config, command, &ownership)? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+fn manage_keys(
+    config: &ConfigServer,
+    command: KeysCommand,
+    ownership: &Ownership,
+) -> anyhow::Result<Vec<String>> {
+    match command {
+        KeysCommand::List => list_keys(config),
+        KeysCommand::Add { label, import } => {
+            let key = match import {
+                Some(path) => read_key(&path)?,
+                None => CryptoHandler::gen_key()?.into(),
+            };
+            add_key(config, &label, &key, ownership)
+        }
+        KeysCommand::Remove { label } => remove_key(config, &label),
+        KeysCommand::Rotate { label } => rotate_key(config, &label, ownership),
+        KeysCommand::ShowId { label, key_file } => {
+            let path = match key_file {
+                Some(path) => path,
+                None => key_path(config, label.as_deref().unwrap_or_default())?,
+            };
+            Ok(vec![format!("{:X?}", load_key(&path)?.id)])
+        }
+    }
+}
+
+fn list_keys(config: &ConfigServer) -> anyhow::Result<Vec<String>> {
+    Ok(config
+        .find_key_paths()?
+        .iter()
+        .map(|path| {
+            let label = path.file_stem().unwrap_or_default().to_string_lossy();
+            match load_key(path) {
+                Ok(handler) => format!("{:X?}  {label}  {}", handler.id, path.display()),
+                Err(e) => format!("invalid  {label}  {}: {e:#}", path.display()),
+            }
+        })
+        .collect())
+}
+
+fn add_key(
+    config: &ConfigServer,
+    label: &str,
+    key: &str,
+    ownership: &Ownership,
+) -> anyhow::Result<Vec<String>> {
+    let path = key_path(config, label)?;
+    if path.exists() {
+        bail!("{path:?} already exists; use `keys rotate {label}` to replace its key");
+    }
+    let handler = CryptoHandler::create(key).with_context(|| "Invalid key")?;
+    ensure_unique_id(config, &handler.id, &path)?;
+    install_key(&path, key, ownership)?;
+
+    Ok(vec![
+        format!("added key {:X?} as {}", handler.id, path.display()),
+        format!("copy {} to the client and use it with `ruroco-client send -k`", path.display()),
+        RELOAD_HINT.to_string(),
+    ])
+}
+
+fn remove_key(config: &ConfigServer, label: &str) -> anyhow::Result<Vec<String>> {
+    let path = key_path(config, label)?;
+    // An unparsable key file can still be removed, there is just no id to report.
+    let id = load_key(&path).map(|handler| format!("{:X?}", handler.id)).ok();
+    fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+
+    Ok(vec![
+        match id {
+            Some(id) => format!("removed key {id} ({})", path.display()),
+            None => format!("removed {}", path.display()),
+        },
+        RELOAD_HINT.to_string(),
+    ])
+}
+
+fn rotate_key(
+    config: &ConfigServer,
+    label: &str,
+    ownership: &Ownership,
+) -> anyhow::Result<Vec<String>> {
+    let path = key_path(config, label)?;
+    let old_id = load_key(&path)?.id;
+    let key: Zeroizing<String> = CryptoHandler::gen_key()?.into();
+    let handler = CryptoHandler::create(&key)?;
+    ensure_unique_id(config, &handler.id, &path)?;
+    install_key(&path, &key, ownership)?;
+
+    Ok(vec![
+        format!("rotated {}: {old_id:X?} -> {:X?}", path.display(), handler.id),
+        format!("clients still using the old key need the new {}", path.display()),
+        RELOAD_HINT.to_string(),
+    ])
+}
+
+/// `config_dir/<label>.key`. Labels end up in file names and log lines, so they are restricted
+/// to ASCII letters, digits, `.`, `_` and `-`, and may not start with a `.`.
+fn key_path(config: &ConfigServer, label: &str) -> anyhow::Result<PathBuf> {
+    let valid = !label.is_empty()
+        && !label.starts_with('.')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        bail!("Invalid key label {label:?}: use letters, digits, '.', '_' and '-'");
+    }
+    Ok(config.resolve_config_dir().join(format!("{label}.key")))
+}
+
+/// Read a key from `path`, or from stdin if `path` is `-`.
+fn read_key(path: &Path) -> anyhow::Result<Zeroizing<String>> {
+    let mut key = Zeroizing::new(String::new());
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_string(&mut key)
+            .with_context(|| "Could not read key from stdin")?;
+    } else {
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut key))
+            .with_context(|| format!("Could not read key file {path:?}"))?;
+    }
+    Ok(key.trim().to_string().into())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<CryptoHandler> {
+    let content: Zeroizing<String> = fs::read_to_string(path)
+        .with_context(|| format!("Could not read key file {path:?}"))?
+        .into();
+    CryptoHandler::create(&content).with_context(|| format!("load key {}", path.display()))
+}
+
+/// Refuse `id` if a key file other than `path` already has it. Every other key file must load,
+/// since the server would not start with a broken one either.
+fn ensure_unique_id(config: &ConfigServer, id: &[u8], path: &Path) -> anyhow::Result<()> {
+    for other in config.find_key_paths()?.iter().filter(|other| *other != path) {
+        if load_key(other)?.id == id {
+            bail!("Key id {id:X?} is already used by {other:?}; refusing to add a duplicate");
+        }
+    }
+    Ok(())
+}
+
+/// Write `key` to a staging file next to `path`, set its mode and owner, then rename it over
+/// `path`, so the server never sees a partial key or one it cannot read.
+fn install_key(path: &Path, key: &str, ownership: &Ownership) -> anyhow::Result<()> {
+    let staging = path.with_extension(format!("key.{}.tmp", now_nanos()?));
+    let result = write_staged_key(&staging, key, ownership)
+        .and_then(|()| fs::rename(&staging, path).map_err(|e| anyhow!("{e}")))
+        .with_context(|| format!("Could not write {path:?}"));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
+
+fn write_staged_key(staging: &Path, key: &str, ownership: &Ownership) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(staging)
+        .with_context(|| format!("Could not create {staging:?}"))?;
+    file.write_all(key.as_bytes()).with_context(|| format!("Could not write {staging:?}"))?;
+    file.sync_all().with_context(|| format!("Could not sync {staging:?}"))?;
+    change_file_ownership(staging, ownership.user, ownership.group)?;
+    // Only now that the group is right may the group read it; explicit, so the umask has no say.
+    fs::set_permissions(staging, fs::Permissions::from_mode(KEY_FILE_MODE))
+        .with_context(|| format!("Could not set mode of {staging:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{manage_keys, Ownership};
+    use crate::common::crypto_handler::CryptoHandler;
+    use crate::server::config::{ConfigServer, KeysCommand};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    // Tests do not run as root; empty names leave the owner alone.
+    const OWNERSHIP: Ownership = Ownership {
+        user: "",
+        group: "",
+    };
+
+    fn config(dir: &Path) -> ConfigServer {
+        ConfigServer {
+            config_dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    fn add(config: &ConfigServer, label: &str) -> anyhow::Result<Vec<String>> {
+        let command = KeysCommand::Add {
+            label: label.to_string(),
+            import: None,
+        };
+        manage_keys(config, command, &OWNERSHIP)
+    }
+
+    fn key_id(path: &Path) -> [u8; 8] {
+        CryptoHandler::create(&fs::read_to_string(path).unwrap()).unwrap().id
+    }
+
+    #[test]
+    fn test_add_and_list_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        add(&config, "laptop").unwrap();
+
+        let path = dir.path().join("laptop.key");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        fs::write(dir.path().join("broken.key"), "nope").unwrap();
+
+        let listed = manage_keys(&config, KeysCommand::List, &OWNERSHIP).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].starts_with("invalid  broken  "), "{listed:?}");
+        assert_eq!(listed[1], format!("{:X?}  laptop  {}", key_id(&path), path.display()));
+        assert!(add(&config, "laptop").unwrap_err().to_string().contains("already exists"));
+    }
+
+    #[test]
+    fn test_add_refuses_duplicate_id_and_bad_labels() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        add(&config, "laptop").unwrap();
+
+        let command = KeysCommand::Add {
+            label: "phone".to_string(),
+            import: Some(dir.path().join("laptop.key")),
+        };
+        let err = manage_keys(&config, command, &OWNERSHIP).unwrap_err().to_string();
+        assert!(err.contains("is already used by"), "unexpected: {err}");
+        assert!(!dir.path().join("phone.key").exists());
+
+        for label in ["", ".hidden", "../etc/passwd", "a b"] {
+            let err = add(&config, label).unwrap_err().to_string();
+            assert!(err.starts_with("Invalid key label"), "unexpected: {err}");
+        }
+    }
+
+    #[test]
+    fn test_rotate_show_id_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        add(&config, "laptop").unwrap();
+        let path = dir.path().join("laptop.key");
+        let old_id = key_id(&path);
+
+        manage_keys(
+            &config,
+            KeysCommand::Rotate {
+                label: "laptop".to_string(),
+            },
+            &OWNERSHIP,
+        )
+        .unwrap();
+        let new_id = key_id(&path);
+        assert_ne!(old_id, new_id);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        let show = KeysCommand::ShowId {
+            label: Some("laptop".to_string()),
+            key_file: None,
+        };
+        assert_eq!(manage_keys(&config, show, &OWNERSHIP).unwrap(), [format!("{new_id:X?}")]);
+
+        manage_keys(
+            &config,
+            KeysCommand::Remove {
+                label: "laptop".to_string(),
+            },
+            &OWNERSHIP,
+        )
+        .unwrap();
+        assert!(!path.exists());
+        assert!(manage_keys(
+            &config,
+            KeysCommand::Remove {
+                label: "laptop".to_string()
+            },
+            &OWNERSHIP
+        )
+        .is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
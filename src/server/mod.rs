@@ -9,12 +9,18 @@
 pub mod blocklist;
 /// the server's view of `config.toml` (`ConfigServer`) and its CLI (`CliServer`)
 pub mod config;
+mod control;
 mod error_throttle;
 mod handler;
+mod inspect;
 mod keys;
 mod listener;
+mod manage_keys;
+mod privileges;
 mod rate_limiter;
-mod signal;
+mod recorder;
+mod replay;
+mod sandbox;
 mod socket;
 
 pub use listener::{run_server, Server};
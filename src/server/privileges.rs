@@ -0,0 +1,108 @@
+//! In-process privilege dropping for deployments without `ruroco.service` (container entrypoints,
+//! init scripts). `run_server` calls `Server::drop_privileges` once everything that may need root
+//! is done (UDP and control sockets bound, blocklist loaded, capture file opened) and before the
+//! first packet is read. Under systemd `User=ruroco` the server is unprivileged from the start and
+//! this only verifies that it is not root.
+
+use crate::common::info;
+use crate::server::Server;
+use anyhow::{anyhow, bail, Context};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Group, Uid, User};
+use std::os::unix::fs::chown;
+
+impl Server {
+    /// Switch to the configured `user`/`group` (setgroups, setgid, setuid, in that order), then
+    /// refuse to continue as root unless `allow_root` is set.
+    pub(super) fn drop_privileges(&mut self) -> anyhow::Result<()> {
+        if let Some(user) = &self.config.user {
+            let (uid, gid) = lookup_ids(user, self.config.group.as_deref())?;
+            if Uid::effective() != uid {
+                // Bound as root: hand the control socket to the server's user and group, which
+                // are who the 0660 mode is meant for.
+                if let Some(path) = &self.config.control_socket {
+                    chown(path, Some(uid.as_raw()), Some(gid.as_raw())).with_context(|| {
+                        format!("Could not change ownership of control socket {path:?}")
+                    })?;
+                }
+                switch_to(uid, gid)?;
+                info(format!("Dropped privileges to {user} ({uid}:{gid})"));
+            }
+            // The blocklist was created while still privileged; make sure the unprivileged user can
+            // persist it now, not on the first accepted packet.
+            self.blocklist
+                .save()
+                .with_context(|| format!("blocklist_dir must be writable by user {user}"))?;
+        }
+        refuse_root(Uid::effective(), self.config.allow_root)
+    }
+}
+
+/// Resolve `user` and the group to run as: `group` if given, otherwise the user's primary group.
+fn lookup_ids(user: &str, group: Option<&str>) -> anyhow::Result<(Uid, Gid)> {
+    let user = User::from_name(user)
+        .with_context(|| format!("Could not look up user {user}"))?
+        .ok_or_else(|| anyhow!("Could not find user {user}"))?;
+    let gid = match group {
+        Some(group) => {
+            Group::from_name(group)
+                .with_context(|| format!("Could not look up group {group}"))?
+                .ok_or_else(|| anyhow!("Could not find group {group}"))?
+                .gid
+        }
+        None => user.gid,
+    };
+    Ok((user.uid, gid))
+}
+
+fn switch_to(uid: Uid, gid: Gid) -> anyhow::Result<()> {
+    // Supplementary groups first: once the uid is gone, so is the permission to change them.
+    setgroups(&[gid]).with_context(|| {
+        "Could not set supplementary groups (dropping privileges requires starting as root)"
+    })?;
+    setgid(gid).with_context(|| format!("Could not set group id to {gid}"))?;
+    setuid(uid).with_context(|| format!("Could not set user id to {uid}"))?;
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        bail!("Privileges were not dropped: could switch back to root after setuid({uid})");
+    }
+    Ok(())
+}
+
+fn refuse_root(euid: Uid, allow_root: bool) -> anyhow::Result<()> {
+    if euid.is_root() && !allow_root {
+        bail!(
+            "Refusing to run the server as root: set `user` (and optionally `group`) in the config \
+             to drop privileges after startup, or set `allow_root = true` to override"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_ids, refuse_root};
+    use nix::unistd::{Gid, Uid};
+
+    #[test]
+    fn test_lookup_ids() {
+        assert_eq!(lookup_ids("root", None).unwrap(), (Uid::from_raw(0), Gid::from_raw(0)));
+        assert_eq!(lookup_ids("root", Some("root")).unwrap(), (Uid::from_raw(0), Gid::from_raw(0)));
+        assert_eq!(
+            lookup_ids("nonexistent_ruroco_user_xyz", None).unwrap_err().to_string(),
+            "Could not find user nonexistent_ruroco_user_xyz"
+        );
+        assert_eq!(
+            lookup_ids("root", Some("nonexistent_ruroco_group_xyz")).unwrap_err().to_string(),
+            "Could not find group nonexistent_ruroco_group_xyz"
+        );
+    }
+
+    #[test]
+    fn test_refuse_root() {
+        assert!(refuse_root(Uid::from_raw(0), false)
+            .unwrap_err()
+            .to_string()
+            .contains("Refusing to run the server as root"));
+        assert!(refuse_root(Uid::from_raw(0), true).is_ok());
+        assert!(refuse_root(Uid::from_raw(1000), false).is_ok());
+    }
+}
@@ -3,6 +3,9 @@ use std::collections::HashMap;
 use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
+/// Requests counted in a window, and the time left of it.
+pub(crate) type Window = (u32, Duration);
+
 #[derive(Debug)]
 pub(crate) struct RateLimiter {
     per_ip: HashMap<IpAddr, (Instant, u32)>,
@@ -57,6 +60,34 @@ impl RateLimiter {
         }
     }
 
+    /// Requests counted in the current window and the time left of it: per source IP (sorted by
+    /// IP) and globally. A source at its limit stays blocked for that time left; there are no
+    /// longer bans.
+    pub(crate) fn current_window(&mut self) -> (Vec<(IpAddr, Window)>, Window) {
+        self.drop_old_entries();
+        let mut per_ip: Vec<(IpAddr, Window)> = self
+            .per_ip
+            .iter()
+            .map(|(ip, (since, count))| (*ip, (*count, Self::time_left(*since))))
+            .collect();
+        per_ip.sort_by_key(|(ip, _)| *ip);
+        let global = if self.global.0.elapsed() < Duration::from_secs(1) {
+            (self.global.1, Self::time_left(self.global.0))
+        } else {
+            (0, Duration::ZERO)
+        };
+        (per_ip, global)
+    }
+
+    fn time_left(window_start: Instant) -> Duration {
+        Duration::from_secs(1).saturating_sub(window_start.elapsed())
+    }
+
+    /// Forget `ip`'s window. Returns whether it had one.
+    pub(crate) fn reset(&mut self, ip: IpAddr) -> bool {
+        self.per_ip.remove(&ip).is_some()
+    }
+
     /// Lazy sweep: drop entries whose window has elapsed so the map can never grow unbounded under
     /// a flood of (spoofable) unique source IPs.
     fn drop_old_entries(&mut self) {
@@ -89,6 +120,26 @@ mod tests {
         assert_eq!(limiter.per_ip.len(), 1);
     }
 
+    #[test]
+    fn test_current_window_and_reset() {
+        let mut limiter = RateLimiter::new();
+        limiter.check(ip(2), 2, u32::MAX).unwrap();
+        limiter.check(ip(1), 2, u32::MAX).unwrap();
+        limiter.check(ip(1), 2, u32::MAX).unwrap();
+        assert!(limiter.check(ip(1), 2, u32::MAX).is_err());
+        let (per_ip, (global, global_left)) = limiter.current_window();
+        let counts: Vec<(IpAddr, u32)> =
+            per_ip.iter().map(|(ip, (count, _))| (*ip, *count)).collect();
+        assert_eq!((counts, global), (vec![(ip(1), 2), (ip(2), 1)], 3));
+        let second = Duration::from_secs(1);
+        assert!(per_ip.iter().all(|(_, (_, left))| *left > Duration::ZERO && *left <= second));
+        assert!(global_left > Duration::ZERO && global_left <= second);
+
+        assert!(limiter.reset(ip(1)));
+        assert!(!limiter.reset(ip(1)));
+        assert!(limiter.check(ip(1), 2, u32::MAX).is_ok());
+    }
+
     #[test]
     fn test_rate_limit_enforced_within_window() {
         let mut limiter = RateLimiter::new();
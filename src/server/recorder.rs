@@ -0,0 +1,180 @@
+//! Packet capture for `--record` and `replay`: every datagram the server receives is appended to a
+//! length-prefixed file, so a report like "my knock doesn't work" can be reproduced offline.
+//!
+//! File layout: the 8-byte `MAGIC`, then one record per datagram:
+//!
+//! | Bytes | Field | Encoding |
+//! | --- | --- | --- |
+//! | 16 | receive time | `u128` big-endian, nanoseconds since the Unix epoch |
+//! | 16 | source IP | IPv6-mapped (`serialize_ip`) |
+//! | 2 | source port | `u16` big-endian |
+//! | 2 | payload length | `u16` big-endian |
+//! | n | payload | the datagram as received |
+
+use crate::common::now_nanos;
+use crate::common::protocol::serialization::{deserialize_ip, serialize_ip};
+use anyhow::{bail, Context};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RUROCAP1";
+const HEADER_SIZE: usize = 16 + 16 + 2 + 2;
+
+/// One recorded datagram.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Record {
+    pub(crate) time_nanos: u128,
+    pub(crate) src: SocketAddr,
+    pub(crate) data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    file: File,
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Open `path` for appending, writing `MAGIC` if the file is new. An existing non-empty file
+    /// must already be a capture, so `--record` can never scribble over an unrelated file. Created
+    /// `0600`: the packets are encrypted, but the source addresses are still worth keeping private.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Recorder> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Could not open capture file {path:?}"))?;
+
+        let len = file.metadata().with_context(|| format!("Could not stat {path:?}"))?.len();
+        if len == 0 {
+            file.write_all(MAGIC).with_context(|| format!("Could not write to {path:?}"))?;
+        } else {
+            let mut magic = [0u8; MAGIC.len()];
+            file.read_exact(&mut magic).with_context(|| format!("Could not read {path:?}"))?;
+            if &magic != MAGIC {
+                bail!("{path:?} exists and is not a ruroco capture file");
+            }
+        }
+
+        Ok(Recorder {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Append one datagram. Each record goes out in a single `write_all`, so a crash can at worst
+    /// truncate the last record, which `read_records` then reports.
+    pub(crate) fn record(&mut self, src: SocketAddr, data: &[u8]) -> anyhow::Result<()> {
+        let data_len = u16::try_from(data.len())
+            .with_context(|| format!("Datagram of {} bytes is too large to record", data.len()))?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+        buf.extend_from_slice(&now_nanos()?.to_be_bytes());
+        buf.extend_from_slice(&serialize_ip(&src.ip()));
+        buf.extend_from_slice(&src.port().to_be_bytes());
+        buf.extend_from_slice(&data_len.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.file
+            .write_all(&buf)
+            .with_context(|| format!("Could not append to capture file {:?}", self.path))
+    }
+}
+
+/// Read every record of a capture written by `Recorder`.
+pub(crate) fn read_records(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let content =
+        std::fs::read(path).with_context(|| format!("Could not read capture file {path:?}"))?;
+    let Some(mut rest) = content.strip_prefix(MAGIC) else {
+        bail!("{path:?} is not a ruroco capture file");
+    };
+
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        let Some((header, tail)) = rest.split_first_chunk::<HEADER_SIZE>() else {
+            bail!("Truncated record header after {} records in {path:?}", records.len());
+        };
+        let (time, header) = split_array::<16>(header);
+        let (ip, header) = split_array::<16>(header);
+        let (port, header) = split_array::<2>(header);
+        let (len, _) = split_array::<2>(header);
+
+        let len = usize::from(u16::from_be_bytes(len));
+        let Some((data, tail)) = tail.split_at_checked(len) else {
+            bail!("Truncated record payload after {} records in {path:?}", records.len());
+        };
+        records.push(Record {
+            time_nanos: u128::from_be_bytes(time),
+            src: SocketAddr::new(deserialize_ip(ip), u16::from_be_bytes(port)),
+            data: data.to_vec(),
+        });
+        rest = tail;
+    }
+    Ok(records)
+}
+
+fn split_array<const N: usize>(data: &[u8]) -> ([u8; N], &[u8]) {
+    let mut head = [0u8; N];
+    head.copy_from_slice(&data[..N]);
+    (head, &data[N..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_records, Recorder, MAGIC};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_record_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.bin");
+        let v4: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+
+        let mut recorder = Recorder::open(&path).unwrap();
+        recorder.record(v4, b"hello").unwrap();
+        drop(recorder);
+        // Re-opening appends rather than truncating.
+        Recorder::open(&path).unwrap().record(v6, b"").unwrap();
+
+        let records = read_records(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].src, v4);
+        assert_eq!(records[0].data, b"hello");
+        assert_eq!(records[1].src, v6);
+        assert!(records[1].data.is_empty());
+        assert!(records[0].time_nanos <= records[1].time_nanos);
+    }
+
+    #[test]
+    fn test_open_refuses_foreign_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "ips = [\"127.0.0.1\"]").unwrap();
+        let err = Recorder::open(&path).unwrap_err().to_string();
+        assert!(err.contains("not a ruroco capture file"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_read_truncated_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.bin");
+        Recorder::open(&path).unwrap().record("1.2.3.4:1".parse().unwrap(), b"hello").unwrap();
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &content[..content.len() - 1]).unwrap();
+
+        let err = read_records(&path).unwrap_err().to_string();
+        assert!(err.contains("Truncated record payload"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_read_empty_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.bin");
+        std::fs::write(&path, MAGIC).unwrap();
+        assert!(read_records(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! `ruroco-server replay <file>`: runs a `--record` capture through `run_loop_iteration` against a
+//! config and prints one verdict per packet. The server is built in `replaying` mode, so nothing is
+//! forwarded to the commander and the persisted blocklist is never written.
+
+use crate::common::events::{EventPublisher, EventSource};
+use crate::common::format_nanos;
+use crate::common::notify::Notifier;
+use crate::server::blocklist::Blocklist;
+use crate::server::config::{ConfigServer, ReplayCommand};
+use crate::server::error_throttle::ErrorThrottle;
+use crate::server::listener::RECV_BUFFER_SIZE;
+use crate::server::rate_limiter::RateLimiter;
+use crate::server::recorder::read_records;
+use crate::server::Server;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::path::Path;
+
+impl Server {
+    /// A server for offline replay. Never touches the configured address (a live server may hold
+    /// it): the UDP socket is an ephemeral loopback bind that is never read from.
+    pub(super) fn create_for_replay(
+        config: ConfigServer,
+        with_blocklist: bool,
+    ) -> anyhow::Result<Server> {
+        let (crypto_handlers, key_labels) = config.create_crypto_handlers()?;
+        let mut blocklist = if with_blocklist {
+            config.load_blocklist()?
+        } else {
+            Blocklist::in_memory()
+        };
+        // As in `Server::create`, a key without an entry counts as replayed. A fresh replay seeds
+        // the lowest floor so every first counter passes; with the real blocklist, keys it has
+        // never seen get `create`'s floor of "now".
+        let floor = if with_blocklist {
+            crate::common::now_nanos()?
+        } else {
+            0
+        };
+        for key_id in crypto_handlers.keys() {
+            blocklist.seed_if_absent(*key_id, floor);
+        }
+
+        Ok(Server {
+            crypto_handlers,
+            key_labels,
+            socket: UdpSocket::bind("127.0.0.1:0")
+                .with_context(|| "Could not bind replay socket")?,
+            client_recv_data: [0u8; RECV_BUFFER_SIZE],
+            socket_path: config.get_commander_unix_socket_path(),
+            events: EventPublisher::create(false, &config.config_dir, EventSource::Server)?,
+            blocklist,
+            rate_limiter: RateLimiter::new(),
+            config,
+            error_throttle: ErrorThrottle::default(),
+            recorder: None,
+            // A replay running inside a service unit must not report readiness for it.
+            notifier: Notifier::disabled(),
+            control: None,
+            last_accepted: HashMap::new(),
+            maintenance: false,
+            replaying: true,
+        })
+    }
+
+    /// Replay every record of `file`, returning one verdict line per packet.
+    pub(super) fn replay(&mut self, file: &Path) -> anyhow::Result<Vec<String>> {
+        let records = read_records(file)?;
+        let mut verdicts = Vec::with_capacity(records.len());
+        for (index, record) in records.iter().enumerate() {
+            // Mirror `recv_from` into the fixed buffer: longer datagrams are cut at its size.
+            let count = record.data.len().min(RECV_BUFFER_SIZE);
+            self.client_recv_data = [0u8; RECV_BUFFER_SIZE];
+            self.client_recv_data[..count].copy_from_slice(&record.data[..count]);
+
+            let verdict = match self.run_loop_iteration(Ok((count, record.src))) {
+                Ok(()) => "accepted".to_string(),
+                Err(e) => format!("rejected: {e}"),
+            };
+            verdicts.push(format!(
+                "#{} {} {} ({} bytes): {verdict}",
+                index + 1,
+                format_nanos(record.time_nanos),
+                record.src,
+                record.data.len()
+            ));
+        }
+        Ok(verdicts)
+    }
+}
+
+pub(super) fn run_replay(config_path: &Path, replay: ReplayCommand) -> anyhow::Result<()> {
+    let config = ConfigServer::create_from_path(config_path)?;
+    let mut server = Server::create_for_replay(config, replay.with_blocklist)?;
+    for verdict in server.replay(&replay.file)? {
+        println!("{verdict}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::gen::Generator;
+    use crate::common::client_data::ClientData;
+    use crate::common::data_parser::DataParser;
+    use crate::common::protocol::MSG_SIZE;
+    use crate::server::blocklist::Blocklist;
+    use crate::server::config::ConfigServer;
+    use crate::server::recorder::Recorder;
+    use crate::server::Server;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    fn encrypt(key: &str, counter: u128) -> Vec<u8> {
+        let dst_ip = "127.0.0.1".parse().unwrap();
+        let plaintext = ClientData::create("default", false, None, dst_ip, counter, vec![])
+            .unwrap()
+            .serialize()
+            .unwrap();
+        DataParser::create(key).unwrap().encode(&plaintext).unwrap()
+    }
+
+    fn write_capture(path: &Path, packets: &[Vec<u8>]) {
+        let src: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let mut recorder = Recorder::open(path).unwrap();
+        for packet in packets {
+            recorder.record(src, packet).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay_verdicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        fs::write(dir.path().join("test.key"), &key).unwrap();
+        let capture = dir.path().join("capture.bin");
+        let packet = encrypt(&key, 1_000);
+        write_capture(&capture, &[packet.clone(), packet, vec![1, 2, 3]]);
+
+        let mut server = Server::create_for_replay(
+            ConfigServer {
+                config_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            false,
+        )
+        .unwrap();
+        let verdicts = server.replay(&capture).unwrap();
+
+        assert_eq!(verdicts.len(), 3);
+        assert!(verdicts[0].ends_with(&format!("({MSG_SIZE} bytes): accepted")), "{verdicts:?}");
+        assert!(verdicts[1].contains("rejected: Invalid counter"), "{verdicts:?}");
+        assert!(verdicts[2].contains("rejected: Invalid read count 3"), "{verdicts:?}");
+        // Replay must not persist anything.
+        assert!(!Blocklist::get_blocklist_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn test_replay_with_blocklist_rejects_old_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Generator::create().unwrap().gen().unwrap();
+        fs::write(dir.path().join("test.key"), &key).unwrap();
+        let capture = dir.path().join("capture.bin");
+        write_capture(&capture, &[encrypt(&key, 1_000)]);
+
+        let mut server = Server::create_for_replay(
+            ConfigServer {
+                config_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            true,
+        )
+        .unwrap();
+        let verdicts = server.replay(&capture).unwrap();
+        assert!(verdicts[0].contains("rejected: Invalid counter"), "{verdicts:?}");
+    }
+}
@@ -0,0 +1,194 @@
+//! Self-sandboxing for deployments without `ruroco.service`'s systemd hardening. After privileges
+//! are dropped, `run_server` calls `Server::apply_sandbox`, which restricts the process to what
+//! `Server::run` needs:
+//!
+//! - **Landlock**: `config_dir` read-only, `blocklist_dir` read-write, everything else denied,
+//!   including TCP bind/connect, abstract Unix sockets and signals to other processes. Landlock
+//!   does not mediate `connect(2)` to pathname Unix sockets, so the commander socket (and the
+//!   event socket) stay reachable while nothing else under `socket_dir` is.
+//! - **seccomp**: an allowlist of the syscalls the receive loop makes; anything else fails with
+//!   `EPERM` (an error the loop logs) rather than killing the process.
+//!
+//! Both degrade gracefully: a kernel without Landlock or seccomp logs the sandbox as not enforced
+//! and the server keeps running.
+
+use crate::common::crypto_handler::CryptoHandler;
+use crate::common::info;
+use crate::common::logging::error;
+use crate::server::Server;
+use anyhow::{anyhow, Context};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, Scope, ABI,
+};
+use nix::libc;
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule, TargetArch,
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Highest Landlock ABI the ruleset was written and tested against. Newer kernels are not asked for
+/// newer rights (which could deny something the server needs); older ones get a best-effort subset.
+const LANDLOCK_ABI: ABI = ABI::V6;
+
+impl Server {
+    pub(super) fn apply_sandbox(&self) {
+        if !self.config.sandbox {
+            info("Sandbox disabled by config (sandbox = false)");
+            return;
+        }
+        // Fetch the cipher once while the filesystem is still open: OpenSSL loads its config and
+        // providers lazily on first use.
+        if let Err(e) = CryptoHandler::preload() {
+            error(format!("Could not preload cipher before sandboxing: {e:#}"));
+        }
+
+        let config_dir = self.config.resolve_config_dir();
+        let blocklist_dir = self.config.blocklist_dir.as_ref().unwrap_or(&config_dir);
+        match restrict_filesystem(&config_dir, blocklist_dir) {
+            Ok(RulesetStatus::FullyEnforced) => info("Landlock sandbox fully enforced"),
+            Ok(RulesetStatus::PartiallyEnforced) => {
+                info("Landlock sandbox partially enforced (older kernel ABI)")
+            }
+            Ok(RulesetStatus::NotEnforced) => {
+                info("Landlock sandbox not enforced: not supported by this kernel")
+            }
+            Err(e) => error(format!("Landlock sandbox not enforced: {e:#}")),
+        }
+
+        match seccomp_filter().and_then(|filter| {
+            seccompiler::apply_filter(&filter).with_context(|| "Could not apply seccomp filter")
+        }) {
+            Ok(()) => info("seccomp syscall allowlist enforced"),
+            Err(e) => error(format!("seccomp sandbox not enforced: {e:#}")),
+        }
+    }
+}
+
+fn restrict_filesystem(config_dir: &Path, blocklist_dir: &Path) -> anyhow::Result<RulesetStatus> {
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+        .handle_access(AccessNet::from_all(LANDLOCK_ABI))?
+        .scope(Scope::from_all(LANDLOCK_ABI))?
+        .create()?
+        .add_rules(path_beneath_rules([config_dir], AccessFs::from_read(LANDLOCK_ABI)))?
+        .add_rules(path_beneath_rules([blocklist_dir], AccessFs::from_all(LANDLOCK_ABI)))?
+        .restrict_self()?;
+    Ok(status.ruleset)
+}
+
+/// Syscalls `Server::run` makes after startup: receiving datagrams, connecting and writing to the
+/// commander socket, publishing events, the atomic blocklist rewrite, answering the control socket
+/// (including a key reload), logging, and what the allocator, OpenSSL and the Rust runtime need
+/// underneath.
+fn allowed_syscalls() -> Vec<libc::c_long> {
+    let mut syscalls = vec![
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_recvfrom,
+        libc::SYS_sendto,
+        libc::SYS_connect,
+        libc::SYS_accept4,
+        libc::SYS_ppoll,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_openat,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_getdents64,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_futex,
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_sched_yield,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([
+        libc::SYS_rename,
+        libc::SYS_chmod,
+        libc::SYS_stat,
+        libc::SYS_poll,
+    ]);
+    syscalls
+}
+
+fn seccomp_filter() -> anyhow::Result<BpfProgram> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH)
+        .map_err(|e| anyhow!("No seccomp support for this architecture: {e}"))?;
+
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> =
+        allowed_syscalls().into_iter().map(|syscall| (syscall, vec![])).collect();
+    // Only Unix sockets (commander and event socket) are created after startup.
+    rules.insert(libc::SYS_socket, vec![arg_equals(0, libc::AF_UNIX as u64)?]);
+    // `is_terminal` checks on stdout/stderr when the first log line of each is written. The cast is
+    // a no-op on glibc but not on targets where the ioctl request type is `c_int`.
+    #[allow(clippy::unnecessary_cast)]
+    rules.insert(libc::SYS_ioctl, vec![arg_equals(1, libc::TCGETS as u64)?]);
+
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )
+    .with_context(|| "Could not build seccomp filter")?;
+    filter.try_into().with_context(|| "Could not compile seccomp filter")
+}
+
+fn arg_equals(index: u8, value: u64) -> anyhow::Result<SeccompRule> {
+    let condition = SeccompCondition::new(index, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, value)
+        .with_context(|| format!("Could not build seccomp condition on argument {index}"))?;
+    SeccompRule::new(vec![condition]).with_context(|| "Could not build seccomp rule")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowed_syscalls, seccomp_filter};
+    use nix::libc;
+
+    #[test]
+    fn test_seccomp_filter_compiles() {
+        assert!(!seccomp_filter().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_allowed_syscalls_exclude_process_creation() {
+        let syscalls = allowed_syscalls();
+        for forbidden in [
+            libc::SYS_execve,
+            libc::SYS_clone,
+            libc::SYS_ptrace,
+            libc::SYS_bind,
+        ] {
+            assert!(!syscalls.contains(&forbidden), "{forbidden} must not be allowed");
+        }
+    }
+}
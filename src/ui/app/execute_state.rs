@@ -64,6 +64,8 @@ mod tests {
             permissive: false,
             ipv4: false,
             ipv6: false,
+            params: vec![],
+            protocol_version: None,
         }
     }
 
@@ -22,6 +22,10 @@ pub(crate) struct CreateForm {
     pub(crate) permissive: bool,
     pub(crate) ipv4: bool,
     pub(crate) ipv6: bool,
+    /// Parameter rows being edited, as (name, value).
+    pub(crate) params: Vec<(String, String)>,
+    /// `None` lets the client pick the protocol version.
+    pub(crate) protocol_version: Option<u8>,
 }
 
 pub(crate) struct RurocoApp {
@@ -59,6 +63,8 @@ impl RurocoApp {
                 permissive: false,
                 ipv4: false,
                 ipv6: false,
+                params: Vec::new(),
+                protocol_version: None,
             },
             execute: ExecuteState {
                 status: HashMap::new(),
@@ -8,6 +8,12 @@ pub(crate) struct CommandData {
     pub(crate) ip: String,
     pub(crate) ipv4: bool,
     pub(crate) ipv6: bool,
+    /// `name=value` pairs, sent as `--param`.
+    #[serde(default)]
+    pub(crate) params: Vec<String>,
+    /// Sent as `--protocol-version`; `None` lets the client pick.
+    #[serde(default)]
+    pub(crate) protocol_version: Option<u8>,
     #[serde(skip)]
     pub(crate) name: String,
 }
@@ -42,6 +48,14 @@ pub(crate) fn data_to_command(data: &CommandData) -> String {
     if data.permissive {
         command.push_str("--permissive ");
     }
+    for param in &data.params {
+        command.push_str("--param ");
+        command.push_str(param);
+        command.push(' ');
+    }
+    if let Some(version) = data.protocol_version {
+        command.push_str(&format!("--protocol-version {version} "));
+    }
 
     command.trim_end().to_string()
 }
@@ -53,6 +67,8 @@ pub(crate) fn command_to_data(input: &str) -> CommandData {
     let mut ipv4 = false;
     let mut ipv6 = false;
     let mut permissive = false;
+    let mut params = Vec::new();
+    let mut protocol_version = None;
 
     let mut it = input.split_whitespace();
     while let Some(tok) = it.next() {
@@ -72,6 +88,14 @@ pub(crate) fn command_to_data(input: &str) -> CommandData {
                     ip = v.to_string();
                 }
             }
+            "--param" => {
+                if let Some(v) = it.next() {
+                    params.push(v.to_string());
+                }
+            }
+            "--protocol-version" => {
+                protocol_version = it.next().and_then(|v| v.parse().ok());
+            }
             "--ipv4" => ipv4 = true,
             "--ipv6" => ipv6 = true,
             "--permissive" => permissive = true,
@@ -86,18 +110,22 @@ pub(crate) fn command_to_data(input: &str) -> CommandData {
         ip,
         ipv4,
         ipv6,
+        params,
+        protocol_version,
         name: String::new(),
     })
 }
 
 pub(crate) fn add_command_name(mut data: CommandData) -> CommandData {
     let name = format!(
-        "{}@{}{}{}{}",
+        "{}@{}{}{}{}{}{}",
         data.command,
         data.address,
         if data.permissive { " permissive" } else { "" },
         if data.ipv4 { " ipv4" } else { "" },
-        if data.ipv6 { " ipv6" } else { "" }
+        if data.ipv6 { " ipv6" } else { "" },
+        data.params.iter().map(|p| format!(" {p}")).collect::<String>(),
+        data.protocol_version.map(|v| format!(" v{v}")).unwrap_or_default()
     );
     data.name = name;
     data
@@ -122,6 +150,8 @@ mod tests {
             ipv4,
             ipv6,
             permissive,
+            params: vec![],
+            protocol_version: None,
             name: String::new(),
         }
     }
@@ -238,4 +268,29 @@ mod tests {
         let result = add_command_name(data);
         assert_eq!(result.name, "c@h:80 permissive");
     }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let mut data = make_cmd("host:80", "open_ssh", "", false, false, false);
+        data.params = vec!["hours=8".to_string(), "proto=tcp".to_string()];
+        let cmd_str = data_to_command(&data);
+        assert_eq!(
+            cmd_str,
+            "send --address host:80 --command open_ssh --param hours=8 --param proto=tcp"
+        );
+        let parsed = command_to_data(&cmd_str);
+        assert_eq!(parsed.params, data.params);
+        assert_eq!(parsed.name, "open_ssh@host:80 hours=8 proto=tcp");
+    }
+
+    #[test]
+    fn test_protocol_version_roundtrip() {
+        let mut data = make_cmd("host:80", "default", "", false, false, false);
+        data.protocol_version = Some(2);
+        let cmd_str = data_to_command(&data);
+        assert_eq!(cmd_str, "send --address host:80 --command default --protocol-version 2");
+        let parsed = command_to_data(&cmd_str);
+        assert_eq!(parsed.protocol_version, Some(2));
+        assert_eq!(parsed.name, "default@host:80 v2");
+    }
 }
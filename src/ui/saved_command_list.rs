@@ -113,6 +113,8 @@ mod tests {
             ip: String::new(),
             ipv4: false,
             ipv6: false,
+            params: vec![],
+            protocol_version: None,
             permissive: false,
             name: String::new(),
         }
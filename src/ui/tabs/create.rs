@@ -24,6 +24,14 @@ pub(crate) fn render(
         });
         arg_row(ui, "use ipv4 only", |ui| ui.checkbox(&mut form.ipv4, ""));
         arg_row(ui, "use ipv6 only", |ui| ui.checkbox(&mut form.ipv6, ""));
+        param_rows(ui, &mut form.params);
+        arg_row(ui, "protocol version (auto: 2 with parameters, else 1)", |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut form.protocol_version, None, "auto");
+                ui.selectable_value(&mut form.protocol_version, Some(1), "1");
+                ui.selectable_value(&mut form.protocol_version, Some(2), "2");
+            })
+        });
 
         ui.add_space(10.0);
 
@@ -35,6 +43,13 @@ pub(crate) fn render(
                 ip: form.ip.clone(),
                 ipv4: form.ipv4,
                 ipv6: form.ipv6,
+                params: form
+                    .params
+                    .iter()
+                    .filter(|(name, _)| !name.is_empty())
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect(),
+                protocol_version: form.protocol_version,
                 name: String::new(),
             });
             commands_list.add(cmd);
@@ -44,10 +59,38 @@ pub(crate) fn render(
             form.permissive = false;
             form.ipv4 = false;
             form.ipv6 = false;
+            form.params.clear();
+            form.protocol_version = None;
         }
     });
 }
 
+/// One row per parameter of the command, sent as `--param name=value`; whether the server accepts
+/// it is up to the command's schema in its commands.toml. Whitespace is stripped for the same
+/// reason as in the command name.
+fn param_rows(ui: &mut egui::Ui, params: &mut Vec<(String, String)>) {
+    let mut to_remove = None;
+    for (index, (name, value)) in params.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            let w = (ui.available_width() - 90.0) / 2.0;
+            ui.add_sized([w, 40.0], egui::TextEdit::singleline(name).hint_text("name"));
+            ui.add_sized([w, 40.0], egui::TextEdit::singleline(value).hint_text("value"));
+            if ui.add_sized([80.0, 40.0], egui::Button::new("Remove")).clicked() {
+                to_remove = Some(index);
+            }
+        });
+        name.retain(|c| !c.is_whitespace());
+        value.retain(|c| !c.is_whitespace());
+        ui.add_space(6.0);
+    }
+    if let Some(index) = to_remove {
+        params.remove(index);
+    }
+    if ui.add_sized([ui.available_width(), 40.0], egui::Button::new("Add Parameter")).clicked() {
+        params.push((String::new(), String::new()));
+    }
+}
+
 fn arg_row<R>(ui: &mut egui::Ui, label: &str, widget: impl FnOnce(&mut egui::Ui) -> R) -> R {
     let r = ui
         .horizontal(|ui| {
@@ -85,6 +128,8 @@ mod tests {
             permissive: false,
             ipv4: false,
             ipv6: false,
+            params: vec![],
+            protocol_version: None,
         }
     }
 
@@ -130,4 +175,27 @@ mod tests {
         harness.run();
         assert_eq!(harness.state().1.get().len(), 1);
     }
+
+    #[test]
+    fn test_add_command_with_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = (make_form(), CommandsList::create(dir.path()), String::new());
+        let mut harness = Harness::new_ui_state(
+            |ui, (form, cl, config_text): &mut (CreateForm, CommandsList, String)| {
+                render(form, cl, config_text, ui);
+            },
+            state,
+        );
+        harness.get_by_label("Add Parameter").click();
+        harness.run();
+        assert_eq!(harness.state().0.params.len(), 1);
+
+        harness.state_mut().0.params[0] = ("hours".to_string(), "8".to_string());
+        harness.get_by_label("Add Command").click();
+        harness.run();
+        let (form, commands_list, config_text) = harness.state();
+        assert!(form.params.is_empty());
+        assert_eq!(commands_list.get()[0].params, vec!["hours=8".to_string()]);
+        assert!(config_text.ends_with("--param hours=8"), "{config_text}");
+    }
 }
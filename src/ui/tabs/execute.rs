@@ -82,6 +82,8 @@ fn exec_command(state: &mut ExecuteState, key: &str, cmd: CommandData) {
             },
             ipv4: cmd.ipv4,
             ipv6: cmd.ipv6,
+            params: cmd.params.clone(),
+            protocol_version: cmd.protocol_version,
             key_file: key_file.path().to_path_buf(),
             ..Default::default()
         };
@@ -135,6 +137,8 @@ mod tests {
             ip: String::new(),
             ipv4: false,
             ipv6: false,
+            params: vec![],
+            protocol_version: None,
             name: "default@127.0.0.1:1234".into(),
         }
     }
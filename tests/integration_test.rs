@@ -124,6 +124,8 @@ mod tests {
                 ipv4: false,
                 ipv6: false,
                 send_delay_ms: 50,
+                params: vec![],
+                protocol_version: None,
             })
             .expect("could not create sender");
 
@@ -154,7 +156,7 @@ mod tests {
                 Commander::create(
                     ConfigCommander {
                         config_dir,
-                        allow_non_routable_ips: true,
+                        allow_cidrs: vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()],
                         ..Default::default()
                     },
                     ConfigCommands::from_map(commands),